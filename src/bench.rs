@@ -0,0 +1,215 @@
+//! Load for comparing the two protocols, and what came of it. `bin/bench.rs` points this at a cluster.
+//!
+//! Each client either keeps exactly one op going at a time (closed loop), or sends at random times whether or not
+//! the last one's back (open loop, Poisson arrivals at some rate). Latency is from an op going out to the client
+//! getting its answer, retries and all, so it's what a user would see. Ops that timed out aren't in the percentiles.
+
+use std::{
+    fmt,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{
+    client::{Client, ClientError, Protocol},
+    history::{Call, History},
+    kv::{KvOp, KvResult},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Load {
+    /// Next op as soon as the last one's back.
+    Closed,
+    /// Ops per second, per client, at exponentially distributed gaps.
+    Open(f64),
+}
+
+impl fmt::Display for Load {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Load::Closed => write!(f, "closed"),
+            Load::Open(_) => write!(f, "open"),
+        }
+    }
+}
+
+/// `ops` random ops through each of `clients` at once, under `load`. Waits for every answer (or timeout).
+/// Returns every call, and how long it all took.
+pub fn drive<P: Protocol<Op = KvOp, Output = KvResult>>(clients: &[Client<P>], load: Load, ops: usize) -> (Vec<Call>, Duration) {
+    let history = History::new();
+    for c in clients {
+        c.record(&history);
+    }
+    let start = Instant::now();
+    thread::scope(|s| {
+        for c in clients {
+            s.spawn(move || match load {
+                Load::Closed => {
+                    for _ in 0..ops {
+                        let _ = c.submit(KvOp::random(&mut rand::thread_rng()));
+                    }
+                }
+                Load::Open(rate) => {
+                    let (tx, rx) = mpsc::channel();
+                    for _ in 0..ops {
+                        let tx = tx.clone();
+                        c.submit_with(KvOp::random(&mut rand::thread_rng()), move |res| {
+                            let _ = tx.send(res);
+                        });
+                        // 1 - u, so never ln(0).
+                        let u: f64 = rand::thread_rng().gen();
+                        thread::sleep(Duration::from_secs_f64(-(1.0 - u).ln() / rate));
+                    }
+                    for _ in 0..ops {
+                        let _ = rx.recv();
+                    }
+                }
+            });
+        }
+    });
+    (history.calls(), start.elapsed())
+}
+
+/// The `q`th quantile of `sorted`, by nearest rank. Zero if there's nothing.
+pub fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// How long each answered op in `calls` took, shortest first.
+pub fn latencies(calls: &[Call]) -> Vec<Duration> {
+    let mut out = calls
+        .iter()
+        .filter_map(|c| match &c.returned {
+            Some((t, Ok(_))) => Some(t.saturating_sub(c.invoked)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    out.sort();
+    out
+}
+
+/// One run, summed up. A row of the CSV, or a line of the JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub protocol: String,
+    pub load: String,
+    pub clients: usize,
+    /// Per client, for open loop.
+    pub rate: Option<f64>,
+    pub ops: usize,
+    pub answered: usize,
+    pub timeouts: usize,
+    pub elapsed_s: f64,
+    /// Answered ops per second.
+    pub throughput: f64,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+    pub p999_ms: f64,
+    /// Sent by the cluster during the run, to each other and to the clients.
+    pub messages: usize,
+    pub messages_per_op: f64,
+}
+
+impl Report {
+    pub const CSV_HEADER: &'static str = "protocol,load,clients,rate,ops,answered,timeouts,elapsed_s,throughput,\
+                                          p50_ms,p99_ms,p999_ms,messages,messages_per_op";
+
+    pub fn new(protocol: &str, load: Load, clients: usize, calls: &[Call], elapsed: Duration, messages: usize) -> Self {
+        let lat = latencies(calls);
+        let ms = |q| percentile(&lat, q).as_secs_f64() * 1000.0;
+        let timeouts = calls
+            .iter()
+            .filter(|c| matches!(c.returned, Some((_, Err(ClientError::Timeout)))))
+            .count();
+        Self {
+            protocol: protocol.to_string(),
+            load: load.to_string(),
+            clients,
+            rate: match load {
+                Load::Closed => None,
+                Load::Open(r) => Some(r),
+            },
+            ops: calls.len(),
+            answered: lat.len(),
+            timeouts,
+            elapsed_s: elapsed.as_secs_f64(),
+            throughput: lat.len() as f64 / elapsed.as_secs_f64(),
+            p50_ms: ms(0.5),
+            p99_ms: ms(0.99),
+            p999_ms: ms(0.999),
+            messages,
+            messages_per_op: messages as f64 / lat.len().max(1) as f64,
+        }
+    }
+
+    /// Goes under `CSV_HEADER`.
+    pub fn csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{:.3},{:.1},{:.3},{:.3},{:.3},{},{:.2}",
+            self.protocol,
+            self.load,
+            self.clients,
+            self.rate.map_or(String::new(), |r| r.to_string()),
+            self.ops,
+            self.answered,
+            self.timeouts,
+            self.elapsed_s,
+            self.throughput,
+            self.p50_ms,
+            self.p99_ms,
+            self.p999_ms,
+            self.messages,
+            self.messages_per_op
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} {}-loop, {} clients: {} of {} ops answered ({} timed out) in {:.2}s, {:.1} ops/s",
+            self.protocol, self.load, self.clients, self.answered, self.ops, self.timeouts, self.elapsed_s, self.throughput
+        )?;
+        write!(
+            f,
+            "    latency p50 {:.2}ms, p99 {:.2}ms, p99.9 {:.2}ms. {} messages, {:.1} per op",
+            self.p50_ms, self.p99_ms, self.p999_ms, self.messages, self.messages_per_op
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(from: u64, to: u64, res: Result<KvResult, ClientError>) -> Call {
+        Call {
+            client: 0,
+            op: KvOp::Get("k".into()),
+            invoked: Duration::from_millis(from),
+            returned: Some((Duration::from_millis(to), res)),
+        }
+    }
+
+    #[test]
+    fn report_leaves_timeouts_out_of_latency() {
+        // 1ms to 100ms, one each, and a timeout that would be the worst of the lot.
+        let mut calls = (1..=100).map(|ms| call(1000, 1000 + ms, Ok(Ok(None)))).collect::<Vec<_>>();
+        calls.push(call(0, 3000, Err(ClientError::Timeout)));
+        let r = Report::new("raft", Load::Closed, 2, &calls, Duration::from_secs(2), 404);
+        assert_eq!((r.ops, r.answered, r.timeouts), (101, 100, 1));
+        assert_eq!((r.p50_ms, r.p99_ms, r.p999_ms), (50.0, 99.0, 100.0));
+        assert_eq!((r.throughput, r.messages_per_op), (50.0, 4.04));
+        assert_eq!(r.csv().split(',').count(), Report::CSV_HEADER.split(',').count());
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+}
@@ -1,14 +1,19 @@
 //! Code for acceptor.
 //!
-//!
+//! Promises and accepts live in `acceptor-(id).wal`, so an acceptor restarted with the same id keeps its word.
 
-use dc_project::paxos::{acceptor, dir::acceptor_init};
+use dc_project::{
+    config::ClusterConfig,
+    paxos::{acceptor, dir::acceptor_init, storage::FileStorage},
+};
 use std::env;
 
 fn main() {
     let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
     println!("Acceptor {}", id);
 
-    let sock = acceptor_init(id);
-    acceptor::listen(id, sock.1, sock.0);
+    let cfg = ClusterConfig::from_env();
+    let sock = acceptor_init(&cfg, id);
+    let storage = FileStorage::open(format!("acceptor-{id}.wal"));
+    acceptor::listen(id, &cfg, sock.1, sock.0, Box::new(storage), None);
 }
@@ -0,0 +1,99 @@
+//! Paxos against Raft, under the same load. See `dc_project::bench`.
+//!
+//! Starts the whole cluster in this process, warms each client up with a read (so Raft has a leader and every
+//! connection is up), then drives it and writes out what happened:
+//!
+//! - `bench.csv`: one row per run, added to the end, so several runs make a table.
+//! - `bench.json`: the same, one JSON object per line.
+//! - `bench-<protocol>-<load>-<clients>.csv`: every op's latency, for plotting.
+//!
+//! Set `BENCH_OUT` to write to another prefix than `bench`. `FAULTS` works as in the threaded harnesses.
+//! Messages are everything the cluster sent during the run, heartbeats and all.
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    process,
+    time::Duration,
+};
+
+use dc_project::{
+    bench::{self, Load, Report},
+    client::{self, Options, Protocol},
+    config::ClusterConfig,
+    faults::Faults,
+    history::Call,
+    kv::{KvOp, KvResult, KvStore},
+    paxos::{self, dir::paxos_init},
+    raft::{self, dir::raft_init},
+};
+
+/// ```sh
+/// cargo run --release --bin bench -- (raft|paxos) [clients] [ops per client] [ops/s per client, for open loop]
+/// ```
+fn main() {
+    let proto = env::args().nth(1).unwrap();
+    let n = env::args().nth(2).map_or(4, |s| s.parse().unwrap());
+    let ops = env::args().nth(3).map_or(200, |s| s.parse().unwrap());
+    let load = env::args().nth(4).map_or(Load::Closed, |s| Load::Open(s.parse().unwrap()));
+    let out = env::var("BENCH_OUT").unwrap_or("bench".into());
+    let cfg = ClusterConfig::from_env();
+    // No faults unless asked for, but they count what goes by either way.
+    let faults = Faults::from_env(&cfg).unwrap_or_else(|| Faults::new(rand::random()));
+
+    let (calls, elapsed, messages) = match proto.as_str() {
+        "raft" => {
+            raft_init::<KvStore>(&cfg, Some(faults.clone()), None);
+            let clients = (0..n).map(|i| raft::client::Client::new(&cfg, i, Options::default())).collect::<Vec<_>>();
+            run(&clients, load, ops, &faults)
+        }
+        "paxos" => {
+            paxos_init::<KvStore>(&cfg, Some(faults.clone()), None);
+            let clients = (0..n).map(|i| paxos::client::Client::new(&cfg, i, Options::default())).collect::<Vec<_>>();
+            run(&clients, load, ops, &faults)
+        }
+        _ => panic!("Expected raft or paxos, got {proto}."),
+    };
+
+    let report = Report::new(&proto, load, n, &calls, elapsed, messages);
+    println!("{report}");
+
+    let summary = format!("{out}.csv");
+    let header = fs::metadata(&summary).is_err();
+    let mut f = OpenOptions::new().create(true).append(true).open(&summary).unwrap();
+    if header {
+        writeln!(f, "{}", Report::CSV_HEADER).unwrap();
+    }
+    writeln!(f, "{}", report.csv()).unwrap();
+    let mut f = OpenOptions::new().create(true).append(true).open(format!("{out}.json")).unwrap();
+    writeln!(f, "{}", serde_json::to_string(&report).unwrap()).unwrap();
+
+    // From the first op, in ms. No latency for ones that timed out.
+    let start = calls.iter().map(|c| c.invoked).min().unwrap_or_default();
+    let mut per_op = "client,invoked_ms,latency_ms\n".to_string();
+    for c in &calls {
+        let latency = match &c.returned {
+            Some((t, Ok(_))) => format!("{:.3}", (*t - c.invoked).as_secs_f64() * 1000.0),
+            _ => String::new(),
+        };
+        per_op += &format!("{},{:.3},{latency}\n", c.client, (c.invoked - start).as_secs_f64() * 1000.0);
+    }
+    let path = format!("{out}-{proto}-{load}-{n}.csv");
+    fs::write(&path, per_op).unwrap();
+    println!("Wrote {summary}, {out}.json and {path}.");
+
+    // The cluster's threads never finish.
+    process::exit(0);
+}
+
+/// Warm up, then `bench::drive`. Also returns how many messages the cluster sent meanwhile.
+fn run<P: Protocol<Op = KvOp, Output = KvResult>>(clients: &[client::Client<P>], load: Load, ops: usize, faults: &Faults) -> (Vec<Call>, Duration, usize) {
+    for c in clients {
+        // Raft may still be electing someone. Each try waits out all the client's own retries.
+        while c.submit(KvOp::Get("k0".into())).is_err() {}
+    }
+    let before = faults.counts().sent;
+    let (calls, elapsed) = bench::drive(clients, load, ops);
+    (calls, elapsed, faults.counts().sent - before)
+}
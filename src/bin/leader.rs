@@ -3,12 +3,16 @@
 
 use std::env;
 
-use dc_project::paxos::{dir::leader_init, leader};
+use dc_project::{
+    config::ClusterConfig,
+    paxos::{dir::leader_init, leader},
+};
 
 fn main() {
     let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
     println!("Leader {}", id);
 
-    let sock = leader_init(id);
-    leader::listen(id, sock.0, sock.1);
+    let cfg = ClusterConfig::from_env();
+    let sock = leader_init(&cfg, id);
+    leader::listen(id, &cfg, sock.0, sock.1, None);
 }
@@ -0,0 +1,33 @@
+//! Checks client histories are linearizable. Exits with 1 if they aren't, so it can go in CI.
+//!
+//! Histories from several clients are checked together, as one run.
+
+use std::{env, process};
+
+use dc_project::{
+    history,
+    linearizability::{self, Kv, Register},
+};
+
+/// ```sh
+/// HISTORY=h0.json cargo run --bin raft_client -- 0 &
+/// HISTORY=h1.json cargo run --bin raft_client -- 1
+/// cargo run --bin lincheck -- [--register] h0.json h1.json
+/// ```
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    // One key or many. Keys can be checked one at a time, so the default is a lot quicker.
+    let register = args.next_if(|a| a == "--register").is_some();
+    let calls = args.flat_map(history::load).collect::<Vec<_>>();
+    let res = match register {
+        true => linearizability::check::<Register>(&calls),
+        false => linearizability::check::<Kv>(&calls),
+    };
+    match res {
+        Ok(()) => println!("{} calls, linearizable.", calls.len()),
+        Err(v) => {
+            println!("{v}");
+            process::exit(1);
+        }
+    }
+}
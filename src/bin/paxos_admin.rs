@@ -0,0 +1,51 @@
+//! Reconfiguration for a running Paxos cluster.
+//!
+//! ```sh
+//! cargo run --bin paxos_admin -- --leaders 2 --acceptors 3,4,5
+//! ```
+//!
+//! Ids are looked up in `cluster.json`. The new leaders and acceptors must already be running (usually as standbys).
+//! The replicas get the new config decided like any other command, and switch over `WINDOW` slots later.
+
+use std::{env, thread, time::Duration};
+
+use dc_project::{
+    config::{ClusterConfig, Role},
+    paxos::{Config, Message},
+};
+use message_io::{network::Transport, node};
+use serde_json::to_vec;
+
+fn ids(s: &str) -> Vec<usize> {
+    s.split(',').map(|i| i.trim().parse().unwrap()).collect()
+}
+
+fn main() {
+    let cfg = ClusterConfig::from_env();
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (mut leaders, mut acceptors) = (vec![], vec![]);
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, val] if flag == "--leaders" => leaders = ids(val),
+            [flag, val] if flag == "--acceptors" => acceptors = ids(val),
+            _ => panic!("Usage: paxos_admin --leaders (ids) --acceptors (ids)"),
+        }
+    }
+    assert!(!leaders.is_empty() && !acceptors.is_empty(), "Need at least one leader and one acceptor.");
+
+    let config = Config {
+        leaders: leaders.iter().map(|&i| cfg.addr(Role::Leader, i)).collect(),
+        acceptors: acceptors.iter().map(|&i| cfg.addr(Role::Acceptor, i)).collect(),
+    };
+    let msg = <Message>::Reconfigure(config);
+
+    // Every replica proposes it. Whichever slot it lands in first wins, the rest are harmless repeats.
+    let (handler, _listener) = node::split::<()>();
+    for n in cfg.nodes(Role::Replica) {
+        let ep = handler.network().connect_sync(Transport::Udp, n.addr).unwrap().0;
+        handler.network().send(ep, &to_vec(&msg).unwrap());
+    }
+    // Give the sends a moment before the sockets go away.
+    thread::sleep(Duration::from_millis(100));
+    println!("Sent {:?}.", msg);
+}
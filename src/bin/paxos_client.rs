@@ -1,44 +1,42 @@
 //! Code for client.
 //!
-//! Thinking each client produces a random value at random intervals and sends it to the replica.
+//! Thinking each client produces a random value at random intervals and sends it to the replicas.
 //!
-//! Which replica? Designated replica, random replica, or all replicas? RANDOM REPLICA.
+//! Which replica? Designated replica, random replica, or all replicas? ALL OF THEM. First answer wins.
 
 use std::env;
 
 use dc_project::{
-    paxos::{
-        dir::{client_init, get_all_replicas},
-        Command, Message,
-    },
+    client::Options,
+    config::ClusterConfig,
+    history::History,
+    kv::KvOp,
+    paxos::client::Client,
     Params,
 };
-use rand::seq::SliceRandom;
-use serde_json::to_vec;
 
 /// ```sh
 /// cargo run --bin paxos_client -- (client_id)
 /// ```
 ///
-/// Right now only written for Paxos.
+/// With `HISTORY` set, every op goes in that file too, to check with `lincheck`.
 fn main() {
     let params = Params::new();
-    let (handler, _listener) = client_init();
-    let reps = get_all_replicas(handler.clone());
-    let rep = reps.choose(&mut rand::thread_rng()).unwrap();
-    println!("Sending to {:?}", rep);
+    let cfg = ClusterConfig::from_env();
     let client_id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
+    let client = Client::new(&cfg, client_id, Options::default());
+    println!("Client {client_id} at {}", client.addr());
+    let history = History::new();
+    client.record(&history);
     let u = rand::distributions::Uniform::from(0.0..1.0);
-    for i in 0..params.k {
-        let val = rand::random::<u64>();
-        let msg = Message::Request(Command {
-            client_id,
-            op_id: i,
-            op: val.to_string(),
-        });
-        dbg!(&msg);
-        handler.network().send(*rep, &to_vec(&msg).unwrap());
+    for _ in 0..params.k {
+        let op = KvOp::random(&mut rand::thread_rng());
+        let res = client.submit(op.clone());
+        println!("{:?} -> {:?}", op, res);
         params.sleep(u, &mut rand::thread_rng());
     }
+    if let Ok(path) = env::var("HISTORY") {
+        history.save(path);
+    }
     println!("Done.");
 }
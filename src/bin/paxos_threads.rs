@@ -3,83 +3,53 @@
 //! cargo r --bin paxos_threads
 //! ```
 //! 
-//! in the root directory of the project.
+//! in the root directory of the project. Set `FAULTS` to a script (see `dc_project::faults`) to mess with the network, e.g.
+//! `FAULTS="duplicate 0.05; reorder 0.1; 3s isolate leader 0; 6s heal"`.
+//!
+//! Exits once every request has an answer (or ran out of retries), with 1 if what the client saw isn't linearizable.
+//! Set `INVARIANTS` to check the replicas' decisions against each other as they go (see `dc_project::invariants`),
+//! and exit with 1 if they ever disagree.
 
-use std::{thread, time::Duration};
+use std::process;
 
 use dc_project::{
-    paxos::{
-        acceptor,
-        dir::{
-            acceptor_init, client_init, get_all_replicas, leader_init, replica_init,
-            ACCEPTOR_COUNT, LEADER_COUNT, REPLICA_COUNT,
-        },
-        leader, replica, Command, Message,
-    },
+    client::Options,
+    config::ClusterConfig,
+    faults::Faults,
+    invariants::Invariants,
+    linearizability::{self, Kv},
+    paxos::{client::Client, dir::paxos_init},
+    kv::KvStore,
     Params,
 };
-use rand::seq::SliceRandom;
-use serde_json::to_vec;
 // use serde_json::to_vec;
 
 fn main() {
     let params = Params::new();
-    let sock = client_init();
-    let reps = get_all_replicas(sock.0.clone());
-
-    let mut acc_handles = vec![];
-    for i in 0..ACCEPTOR_COUNT {
-        let sock = acceptor_init(i.into());
-        acc_handles.push(thread::spawn(move || {
-            acceptor::listen(i.into(), sock.1, sock.0);
-        }));
-    }
-    thread::sleep(Duration::from_secs(1));
-
-    let mut lea_handles = vec![];
-    for i in 0..LEADER_COUNT {
-        let sock = leader_init(i.into());
-        lea_handles.push(thread::spawn(move || {
-            leader::listen(i.into(), sock.0, sock.1);
-        }));
-    }
-    thread::sleep(Duration::from_secs(1));
-
-    let mut rep_handles = vec![];
-    for i in 0..REPLICA_COUNT {
-        let sock = replica_init(i.into());
-        rep_handles.push(thread::spawn(move || {
-            replica::listen(i.into(), sock.1, sock.0);
-        }));
-    }
-
-    thread::sleep(Duration::from_secs(1));
-
-    let u = rand::distributions::Uniform::from(0.0..1.0);
-
-    let rep = reps.choose(&mut rand::thread_rng()).unwrap();
-    for i in 0..params.k {
-        let val = rand::random::<u64>();
-        let msg = Message::Request(Command {
-            client_id: 0,
-            op_id: i,
-            op: val.to_string(),
-        });
-        sock.0.network().send(*rep, &to_vec(&msg).unwrap());
-        params.sleep(u, &mut rand::thread_rng());
-    }
-
-    for handle in acc_handles {
-        handle.join().unwrap();
-    }
-
-    for handle in lea_handles {
-        handle.join().unwrap();
-    }
-
-    for handle in rep_handles {
-        handle.join().unwrap();
+    let cfg = ClusterConfig::from_env();
+    let faults = Faults::from_env(&cfg);
+    let invariants = Invariants::from_env();
+
+    paxos_init::<KvStore>(&cfg, faults, invariants.clone()); // Acceptors, leaders and replicas spawn
+
+    let client = Client::new(&cfg, 0, Options::default());
+    let calls = params.drive(&client);
+    let answered = calls.iter().filter(|c| matches!(c.returned, Some((_, Ok(_))))).count();
+    println!("{answered} of {} requests answered.", calls.len());
+    let mut ok = match linearizability::check::<Kv>(&calls) {
+        Ok(()) => {
+            println!("Linearizable.");
+            true
+        }
+        Err(v) => {
+            println!("{v}");
+            false
+        }
+    };
+    if let Some(inv) = &invariants {
+        ok &= inv.report();
     }
+    process::exit(if ok { 0 } else { 1 });
 }
 
 // fn main() {
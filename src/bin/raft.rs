@@ -1,14 +1,43 @@
 //! Code for server.
 //!
-//!
+//! State lives in `raft-(id).log` and `raft-(id).snap`, so a server restarted with the same id picks up where it left off.
 
-use dc_project::{raft::{dir::RAFT_PORT, server}, LOOPBACK};
-use std::{env, net::SocketAddr};
+use dc_project::{
+    config::ClusterConfig,
+    kv::KvStore,
+    raft::{server, storage::FileStorage, Options, ReadMode},
+};
+use std::env;
 
+/// ```sh
+/// cargo run --bin raft -- (id) [snapshot threshold] [log|index|lease] [max entries per append] [max in flight]
+/// ```
 fn main() {
     let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
     println!("Server {}", id);
 
-    server::run(id, SocketAddr::from((LOOPBACK, RAFT_PORT + id as u16)));
-}
+    let mut opts = Options::default();
+    if let Some(t) = env::args().nth(2) {
+        opts.snapshot_threshold = t.parse().unwrap();
+    }
+    // How reads are served. ReadIndex unless told otherwise.
+    if let Some(m) = env::args().nth(3) {
+        opts.read_mode = match m.as_str() {
+            "log" => ReadMode::Log,
+            "index" => ReadMode::ReadIndex,
+            "lease" => ReadMode::Lease,
+            _ => panic!("Expected log, index or lease, got {m}."),
+        };
+    }
+    // Flow control to each follower.
+    if let Some(n) = env::args().nth(4) {
+        opts.max_entries = n.parse().unwrap();
+    }
+    if let Some(n) = env::args().nth(5) {
+        opts.max_inflight = n.parse().unwrap();
+    }
 
+    let cfg = ClusterConfig::from_env();
+    let storage = FileStorage::open(format!("raft-{id}.log"));
+    server::run::<KvStore>(id, &cfg, Box::new(storage), opts, None, None);
+}
@@ -0,0 +1,43 @@
+//! Membership changes for a running Raft cluster.
+//!
+//! ```sh
+//! cargo run --bin raft_admin -- add (id)
+//! cargo run --bin raft_admin -- remove (id)
+//! cargo run --bin raft_admin -- transfer (id)
+//! ```
+//!
+//! The server being added must be in `cluster.json` (usually with `"standby": true`) and already running.
+//! It catches up as a learner, and only gets a vote once it has.
+//!
+//! `transfer` moves leadership to a voter, e.g. before taking the leader down. Requests wait meanwhile.
+
+use std::{env, thread, time::Duration};
+
+use dc_project::{
+    config::{ClusterConfig, Role},
+    raft::Message,
+};
+use message_io::{network::Transport, node};
+use serde_json::to_vec;
+
+fn main() {
+    let cfg = ClusterConfig::from_env();
+    let action = env::args().nth(1).unwrap();
+    let id = env::args().nth(2).unwrap().parse::<usize>().unwrap();
+    let msg: Message = match action.as_str() {
+        "add" => Message::AddServer(id, cfg.addr(Role::Raft, id)),
+        "remove" => Message::RemoveServer(id),
+        "transfer" => Message::TransferLeadership(id),
+        _ => panic!("Expected add, remove or transfer, got {action}."),
+    };
+
+    // Whoever gets it passes it on to the leader.
+    let (handler, _listener) = node::split::<()>();
+    for n in cfg.nodes(Role::Raft).filter(|n| !n.standby && n.id != id) {
+        let ep = handler.network().connect_sync(Transport::Udp, n.addr).unwrap().0;
+        handler.network().send(ep, &to_vec(&msg).unwrap());
+    }
+    // Give the sends a moment before the sockets go away.
+    thread::sleep(Duration::from_millis(100));
+    println!("Sent {:?}.", msg);
+}
@@ -3,52 +3,48 @@
 //! cargo r --bin raft_threads
 //! ```
 //! 
-//! in the root directory of the project.
+//! in the root directory of the project. Set `FAULTS` to a script (see `dc_project::faults`) to mess with the network, e.g.
+//! `FAULTS="drop 0.05; 3s isolate raft 2; 6s heal"`.
+//!
+//! Exits once every request has an answer (or ran out of retries), with 1 if what the client saw isn't linearizable.
+//! Set `INVARIANTS` to check the servers' logs against each other as they go (see `dc_project::invariants`), and
+//! exit with 1 if they ever disagree.
 
-use std::net::SocketAddr;
-use std::thread;
-use std::time::Duration;
+use std::process;
 
-use dc_project::raft::dir::{raft_init, RAFT_COUNT, RAFT_PORT};
-use dc_project::raft::{Command, Message};
-use dc_project::{Params, LOOPBACK};
-use message_io::network::Transport;
-use message_io::node;
-use rand::distributions::{Distribution, Uniform};
-use rand::thread_rng;
-use serde_json::to_vec;
+use dc_project::client::Options;
+use dc_project::config::ClusterConfig;
+use dc_project::faults::Faults;
+use dc_project::invariants::Invariants;
+use dc_project::kv::KvStore;
+use dc_project::linearizability::{self, Kv};
+use dc_project::raft::client::Client;
+use dc_project::raft::dir::raft_init;
+use dc_project::Params;
 
 fn main() {
     let params = Params::new();
-    let sock = node::split::<()>();
-    let _ = sock
-        .0
-        .network()
-        .listen(Transport::Udp, SocketAddr::from((LOOPBACK, 10000)))
-        .unwrap();
-    let handles = raft_init(); // Server threads spawn
-    let u = Uniform::from(0.0..1.0);
-    let v = Uniform::from(0..RAFT_COUNT);
-    let rep_idx = v.sample(&mut thread_rng());
-    let rep = sock.0.network().connect(
-        Transport::Udp,
-        SocketAddr::from((LOOPBACK, RAFT_PORT + rep_idx as u16)),
-    ).unwrap().0;
+    let cfg = ClusterConfig::from_env();
+    let faults = Faults::from_env(&cfg);
+    let invariants = Invariants::from_env();
+    raft_init::<KvStore>(&cfg, faults, invariants.clone()); // Server threads spawn
+    let client = Client::new(&cfg, 0, Options::default());
 
-    thread::sleep(Duration::from_secs(3));
-
-    for i in 0..params.k {
-        let val = rand::random::<u64>();
-        let msg = Message::Request(Command {
-            client: SocketAddr::from((LOOPBACK, 10000)),
-            op_id: i,
-            op: val.to_string(),
-        });
-        sock.0.network().send(rep, &to_vec(&msg).unwrap());
-        params.sleep(u, &mut rand::thread_rng());
-    }
-
-    for h in handles {
-        h.join().unwrap();
+    let calls = params.drive(&client);
+    let answered = calls.iter().filter(|c| matches!(c.returned, Some((_, Ok(_))))).count();
+    println!("{answered} of {} requests answered.", calls.len());
+    let mut ok = match linearizability::check::<Kv>(&calls) {
+        Ok(()) => {
+            println!("Linearizable.");
+            true
+        }
+        Err(v) => {
+            println!("{v}");
+            false
+        }
+    };
+    if let Some(inv) = &invariants {
+        ok &= inv.report();
     }
+    process::exit(if ok { 0 } else { 1 });
 }
@@ -2,13 +2,18 @@
 //!
 //!
 
-use dc_project::paxos::{dir::replica_init, replica};
+use dc_project::{
+    config::ClusterConfig,
+    kv::KvStore,
+    paxos::{dir::replica_init, replica},
+};
 use std::env;
 
 fn main() {
     let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
     println!("Replica {}", id);
 
-    let sock = replica_init(id);
-    replica::listen(id, sock.1, sock.0);
+    let cfg = ClusterConfig::from_env();
+    let sock = replica_init(&cfg, id);
+    replica::listen::<KvStore>(id, &cfg, sock.1, sock.0, None, None);
 }
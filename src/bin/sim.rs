@@ -0,0 +1,106 @@
+//! Either cluster in one thread, on simulated time. See `dc_project::sim`.
+//!
+//! Same seed, same run, so anything odd can be replayed. Prints the seed for that reason.
+//! `FAULTS` works as in the threaded harnesses, on simulated time, and replays from the seed too.
+//! Exits with 1 if what the clients saw isn't linearizable, so a loop over seeds makes a test.
+//! With `INVARIANTS` set, also checks the nodes against each other after every event (see `dc_project::invariants`),
+//! and exits with 1 if they ever disagree.
+
+use std::{env, net::SocketAddr, process, time::Duration};
+
+use dc_project::{
+    config::ClusterConfig,
+    faults::Faults,
+    invariants::Invariants,
+    kv::KvStore,
+    paxos::client::Paxos,
+    raft::{client::Raft, Options},
+    history::Call,
+    linearizability::{self, Kv},
+    sim::{self, Sim, SimClient},
+};
+
+/// Give up after this much virtual time.
+const LIMIT: Duration = Duration::from_secs(600);
+
+/// ```sh
+/// cargo run --bin sim -- (raft|paxos) [seed] [ops per client] [clients]
+/// ```
+fn main() {
+    let proto = env::args().nth(1).unwrap();
+    let seed = env::args().nth(2).map_or_else(rand::random, |s| s.parse().unwrap());
+    let ops = env::args().nth(3).map_or(100, |s| s.parse().unwrap());
+    let n = env::args().nth(4).map_or(1, |s| s.parse().unwrap());
+    let cfg = ClusterConfig::from_env();
+    println!("Seed {seed}");
+
+    let mut sim = Sim::new(seed);
+    let faults = env::var("FAULTS").ok().map(|script| {
+        println!("Faults: {script}");
+        Faults::parse(seed, &cfg, &script).unwrap_or_else(|e| panic!("Bad FAULTS: {e}"))
+    });
+    if let Some(f) = &faults {
+        sim.set_faults(f.clone());
+    }
+    if let Some(inv) = Invariants::from_env() {
+        sim.set_invariants(inv);
+    }
+    let clients = match proto.as_str() {
+        "raft" => {
+            sim::raft_cluster::<KvStore>(&mut sim, &cfg, Options::default());
+            (0..n).map(|i| sim::raft_client(&mut sim, &cfg, i, ops)).collect::<Vec<_>>()
+        }
+        "paxos" => {
+            sim::paxos_cluster::<KvStore>(&mut sim, &cfg);
+            (0..n).map(|i| sim::paxos_client(&mut sim, &cfg, i, ops)).collect()
+        }
+        _ => panic!("Expected raft or paxos, got {proto}."),
+    };
+
+    let raft = proto == "raft";
+    let done = |h: &[Call]| h.len() == ops && h.last().is_none_or(|c| c.returned.is_some());
+    let finished = sim.run_until(LIMIT, |sim| clients.iter().all(|c| done(history(sim, raft, *c))));
+
+    for (i, c) in clients.iter().enumerate() {
+        let h = history(&sim, raft, *c);
+        let answered = h.iter().filter(|c| matches!(c.returned, Some((_, Ok(_))))).count();
+        println!("Client {i}: {answered} of {} ops answered", h.len());
+    }
+
+    if let Some(f) = &faults {
+        println!("{:?}", f.counts());
+    }
+    let (delivered, dropped) = sim.stats();
+    println!(
+        "{} after {:?} of simulated time. {delivered} messages delivered, {dropped} dropped. Digest {:x}",
+        if finished { "Done" } else { "Gave up" },
+        sim.now(),
+        sim.digest()
+    );
+
+    let calls = clients.iter().flat_map(|c| history(&sim, raft, *c)).cloned().collect::<Vec<_>>();
+    let mut ok = match linearizability::check::<Kv>(&calls) {
+        Ok(()) => {
+            println!("Linearizable.");
+            true
+        }
+        Err(v) => {
+            println!("Seed {seed}: {v}");
+            false
+        }
+    };
+    if let Some(inv) = sim.invariants() {
+        ok &= inv.report();
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
+
+fn history(sim: &Sim, raft: bool, c: SocketAddr) -> &[Call] {
+    if raft {
+        &sim.node::<SimClient<Raft>>(c).unwrap().history
+    } else {
+        &sim.node::<SimClient<Paxos>>(c).unwrap().history
+    }
+}
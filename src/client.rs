@@ -0,0 +1,369 @@
+//! Client handle for talking to either cluster.
+//!
+//! Ops go out as requests tagged with an `op_id`, and responses are matched back up by it.
+//! Anything unanswered after `timeout` is sent again (maybe somewhere else) up to `retries` times, then given up on.
+//! The protocol specific bits (what a request looks like, who to send it to) live in `paxos::client` and `raft::client`.
+//! Ops and results are whatever the cluster's `StateMachine` takes and gives back: `KvOp` and `KvResult` by default.
+//!
+//! ```no_run
+//! use dc_project::{client::Options, config::ClusterConfig, kv::KvOp, raft::client::Client};
+//!
+//! let cfg = ClusterConfig::from_env();
+//! let client = Client::new(&cfg, 0, Options::default());
+//! let res = client.submit(KvOp::Put("k0".into(), "v".into()));
+//! ```
+
+use std::{
+    net::SocketAddr,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hashbrown::HashMap;
+use message_io::{
+    network::{Endpoint, NetEvent, Transport},
+    node::{self, NodeEvent, NodeHandler, NodeTask},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{history::History, kv::KvResult, Payload};
+
+/// Knobs for a client.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// How long to wait for a response before trying again.
+    pub timeout: Duration,
+    /// Tries after the first, before giving up.
+    pub retries: usize,
+    /// Paxos only. How many replicas each try goes to.
+    pub fanout: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            retries: 5,
+            fanout: usize::MAX,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClientError {
+    /// Out of retries, and still nothing.
+    Timeout,
+    /// The servers have forgotten this op: it was applied long ago, or it never will be. They can't say which.
+    Expired,
+}
+
+/// What the client gets back for an op. `R` is what the state machine answers.
+pub type Outcome<R = KvResult> = Result<R, ClientError>;
+
+/// How to talk to a particular kind of cluster, running a particular state machine.
+pub trait Protocol: Send + 'static {
+    /// What a client asks for. `StateMachine::Op` on the other end.
+    type Op: Payload;
+    /// What it gets back. `StateMachine::Output` on the other end.
+    type Output: Payload;
+
+    /// The request for `op`, ready to go on the wire.
+    fn request(&self, op_id: usize, op: &Self::Op) -> Vec<u8>;
+    /// `(op_id, outcome)`, if `buf` is a response.
+    fn response(&self, buf: &[u8]) -> Option<(usize, Outcome<Self::Output>)>;
+    /// Where try number `attempt` (starting at 0) goes.
+    fn targets(&mut self, attempt: usize) -> Vec<SocketAddr>;
+    /// `from` just answered us.
+    fn heard_from(&mut self, _from: SocketAddr) {}
+}
+
+/// An op still waiting on a response.
+struct Pending<O, R> {
+    op: O,
+    attempt: usize,
+    done: Box<dyn FnOnce(Outcome<R>) + Send>,
+}
+
+struct Inner<P: Protocol> {
+    proto: P,
+    next_op: usize,
+    pending: HashMap<usize, Pending<P::Op, P::Output>>,
+    /// Where to record calls, if anywhere.
+    history: Option<History<P::Op, P::Output>>,
+}
+
+/// Talks to a cluster through protocol `P`. Cheap to share between threads by reference.
+///
+/// Requests go out of the socket we listen on, so the servers can answer straight back to it.
+pub struct Client<P: Protocol> {
+    id: usize,
+    inner: Arc<Mutex<Inner<P>>>,
+    /// Signals are `(op_id, attempt)`, for retry timeouts.
+    handler: NodeHandler<(usize, usize)>,
+    listen: Endpoint,
+    opts: Options,
+    _task: NodeTask,
+}
+
+impl<P: Protocol> Client<P> {
+    /// Client `id`, listening on `addr`. Starts handling responses and timeouts in the background.
+    pub fn start(id: usize, addr: SocketAddr, proto: P, opts: Options) -> Self {
+        let (handler, listener) = node::split::<(usize, usize)>();
+        let (res, addr) = handler.network().listen(Transport::Udp, addr).unwrap();
+        let listen = Endpoint::from_listener(res, addr);
+        // Servers remember op ids per client, so a restarted client must not start over at 0.
+        let first_op = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as usize;
+        let inner = Arc::new(Mutex::new(Inner {
+            proto,
+            next_op: first_op,
+            pending: HashMap::new(),
+            history: None,
+        }));
+
+        let (i, h) = (inner.clone(), handler.clone());
+        let task = listener.for_each_async(move |event| match event {
+            NodeEvent::Network(NetEvent::Message(ep, buf)) => {
+                let mut guard = i.lock().unwrap();
+                let Some((op_id, res)) = guard.proto.response(buf) else {
+                    return;
+                };
+                // Late or duplicate answers for ops we're done with are dropped here.
+                if let Some(p) = guard.pending.remove(&op_id) {
+                    guard.proto.heard_from(ep.addr());
+                    drop(guard);
+                    (p.done)(res);
+                }
+            }
+            NodeEvent::Network(_) => {}
+            NodeEvent::Signal((op_id, attempt)) => {
+                let mut guard = i.lock().unwrap();
+                // Answered already, or this is the timer of an earlier try.
+                match guard.pending.get(&op_id) {
+                    Some(p) if p.attempt == attempt => {}
+                    _ => return,
+                }
+                if attempt < opts.retries {
+                    guard.pending.get_mut(&op_id).unwrap().attempt += 1;
+                    Self::send(&mut guard, &h, listen, op_id, opts);
+                } else {
+                    let p = guard.pending.remove(&op_id).unwrap();
+                    drop(guard);
+                    (p.done)(Err(ClientError::Timeout));
+                }
+            }
+        });
+
+        Self {
+            id,
+            inner,
+            handler,
+            listen,
+            opts,
+            _task: task,
+        }
+    }
+
+    /// Send (or resend) `op_id`, and set the timer for it.
+    fn send(inner: &mut Inner<P>, handler: &NodeHandler<(usize, usize)>, listen: Endpoint, op_id: usize, opts: Options) {
+        let p = &inner.pending[&op_id];
+        let (buf, attempt) = (inner.proto.request(op_id, &p.op), p.attempt);
+        for addr in inner.proto.targets(attempt) {
+            handler
+                .network()
+                .send(Endpoint::from_listener(listen.resource_id(), addr), &buf);
+        }
+        handler.signals().send_with_timer((op_id, attempt), opts.timeout);
+    }
+
+    /// Submit `op`, and have `done` called with the outcome, from the client's own thread.
+    /// Returns the `op_id`.
+    ///
+    /// `done` can submit more ops with this, but not with `submit`: that would wait on the very thread it's blocking.
+    pub fn submit_with(&self, op: P::Op, done: impl FnOnce(Outcome<P::Output>) + Send + 'static) -> usize {
+        let mut guard = self.inner.lock().unwrap();
+        let op_id = guard.next_op;
+        guard.next_op += 1;
+        let done: Box<dyn FnOnce(Outcome<P::Output>) + Send> = match guard.history.clone() {
+            Some(h) => {
+                let call = h.invoke(self.id, op.clone());
+                Box::new(move |res: Outcome<P::Output>| {
+                    h.finish(call, res.clone());
+                    done(res)
+                })
+            }
+            None => Box::new(done),
+        };
+        guard.pending.insert(
+            op_id,
+            Pending {
+                op,
+                attempt: 0,
+                done,
+            },
+        );
+        Self::send(&mut guard, &self.handler, self.listen, op_id, self.opts);
+        op_id
+    }
+
+    /// Submit `op` and wait for the outcome.
+    pub fn submit(&self, op: P::Op) -> Outcome<P::Output> {
+        let (tx, rx) = mpsc::channel();
+        self.submit_with(op, move |res| {
+            let _ = tx.send(res);
+        });
+        // Always answered one way or the other, once the retries run out.
+        rx.recv().unwrap()
+    }
+
+    /// Record every op from here on in `history`, with when it went out and when and what came back.
+    pub fn record(&self, history: &History<P::Op, P::Output>) {
+        self.inner.lock().unwrap().history = Some(history.clone());
+    }
+
+    /// Where responses come back to.
+    pub fn addr(&self) -> SocketAddr {
+        self.listen.addr()
+    }
+}
+
+impl<P: Protocol> Drop for Client<P> {
+    fn drop(&mut self) {
+        // Otherwise the task never finishes, and dropping it hangs.
+        self.handler.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+    use crate::{
+        config::{ClusterConfig, Role},
+        kv::KvOp,
+        paxos::client::Paxos,
+        raft::client::Raft,
+    };
+
+    /// Requests are just the op id, answers the op id and a result. Everything goes to the one address.
+    struct Fake(SocketAddr);
+
+    impl Protocol for Fake {
+        type Op = KvOp;
+        type Output = KvResult;
+
+        fn request(&self, op_id: usize, _op: &KvOp) -> Vec<u8> {
+            serde_json::to_vec(&op_id).unwrap()
+        }
+
+        fn response(&self, buf: &[u8]) -> Option<(usize, Outcome)> {
+            let (op_id, res) = serde_json::from_slice::<(usize, KvResult)>(buf).ok()?;
+            Some((op_id, Ok(res)))
+        }
+
+        fn targets(&mut self, _attempt: usize) -> Vec<SocketAddr> {
+            vec![self.0]
+        }
+    }
+
+    /// A client, and a socket standing in for the whole cluster.
+    fn setup(opts: Options) -> (Client<Fake>, UdpSocket) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let proto = Fake(server.local_addr().unwrap());
+        let client = Client::start(0, "127.0.0.1:0".parse().unwrap(), proto, opts);
+        (client, server)
+    }
+
+    /// Op id of the next request to turn up.
+    fn recv(server: &UdpSocket) -> usize {
+        let mut buf = [0; 64];
+        let n = server.recv(&mut buf).unwrap();
+        serde_json::from_slice(&buf[..n]).unwrap()
+    }
+
+    fn answer(server: &UdpSocket, to: SocketAddr, op_id: usize, v: &str) {
+        let res: KvResult = Ok(Some(v.into()));
+        server.send_to(&serde_json::to_vec(&(op_id, res)).unwrap(), to).unwrap();
+    }
+
+    #[test]
+    fn answers_are_matched_by_op_id() {
+        let (client, server) = setup(Options::default());
+        let (tx, rx) = mpsc::channel();
+        let ops = (0..2)
+            .map(|i| {
+                let tx = tx.clone();
+                client.submit_with(KvOp::Get("k".into()), move |res| tx.send((i, res)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        recv(&server);
+        recv(&server);
+
+        // Second one first, then the first one twice.
+        answer(&server, client.addr(), ops[1], "b");
+        answer(&server, client.addr(), ops[0], "a");
+        answer(&server, client.addr(), ops[0], "a again");
+        let wait = Duration::from_secs(2);
+        assert_eq!(rx.recv_timeout(wait).unwrap(), (1, Ok(Ok(Some("b".into())))));
+        assert_eq!(rx.recv_timeout(wait).unwrap(), (0, Ok(Ok(Some("a".into())))));
+        // Nobody's waiting on the duplicate.
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn retries_then_gives_up() {
+        let opts = Options {
+            timeout: Duration::from_millis(20),
+            retries: 2,
+            ..Options::default()
+        };
+        let (client, server) = setup(opts);
+        assert_eq!(client.submit(KvOp::Get("k".into())), Err(ClientError::Timeout));
+        // The first try and both retries, all for the same op.
+        let op_id = recv(&server);
+        assert_eq!([recv(&server), recv(&server)], [op_id, op_id]);
+    }
+
+    #[test]
+    fn raft_goes_to_whoever_answered() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let servers = cfg.nodes(Role::Raft).filter(|n| !n.standby).map(|n| n.addr).collect::<Vec<_>>();
+        let mut raft = <Raft>::new(&cfg, 0, 0);
+        assert_eq!(raft.targets(0), [servers[0]]);
+        // Timed out, so the next one along. Only once, however often that try is resent.
+        assert_eq!(raft.targets(1), [servers[1]]);
+        assert_eq!(raft.targets(1), [servers[1]]);
+        // The answer comes from the leader, wherever the request went.
+        raft.heard_from(servers[3]);
+        assert_eq!(raft.targets(0), [servers[3]]);
+    }
+
+    #[test]
+    fn protocols_carry_any_state_machine() {
+        // Numbers in, numbers out. Nothing KV about it.
+        let cfg = ClusterConfig::load("cluster.json");
+        let raft = Raft::<usize, usize>::new(&cfg, 0, 0);
+        let req = serde_json::from_slice(&raft.request(1, &7)).unwrap();
+        assert!(matches!(req, crate::raft::Message::<usize, usize>::Request(c) if c.op == 7));
+        let res = serde_json::to_vec(&crate::raft::Message::<usize, usize>::Response(1, 12)).unwrap();
+        assert_eq!(raft.response(&res), Some((1, Ok(12))));
+
+        let paxos = Paxos::<usize, usize>::new(&cfg, 0, 1);
+        let req = serde_json::from_slice(&paxos.request(1, &7)).unwrap();
+        assert!(matches!(req, crate::paxos::Message::<usize, usize>::Request(c) if c.op == 7));
+        let res = serde_json::to_vec(&crate::paxos::Message::<usize, usize>::Expired(1)).unwrap();
+        assert_eq!(paxos.response(&res), Some((1, Err(ClientError::Expired))));
+    }
+
+    #[test]
+    fn paxos_fans_out_from_the_next_replica_each_try() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let replicas = cfg.nodes(Role::Replica).map(|n| n.addr).collect::<Vec<_>>();
+        let mut paxos = <Paxos>::new(&cfg, 0, 2);
+        assert_eq!(paxos.targets(0), [replicas[0], replicas[1]]);
+        assert_eq!(paxos.targets(1), [replicas[1], replicas[2]]);
+        assert_eq!(paxos.targets(2), [replicas[2], replicas[0]]);
+    }
+}
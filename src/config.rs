@@ -0,0 +1,149 @@
+//! Who's who in the cluster, and where to find them.
+//!
+//! Read from a JSON file (`cluster.json` unless `CLUSTER_CONFIG` says otherwise) by every binary and harness,
+//! so moving to multiple machines is an edit to the file, not a recompile.
+//!
+//! ```json
+//! {
+//!     "client_ip": "127.0.0.1",
+//!     "client_port": 10000,
+//!     "lease": { "duration_ms": 500, "max_drift": 0.1 },
+//!     "nodes": [
+//!         { "id": 0, "role": "Leader", "addr": "127.0.0.1:4000" },
+//!         { "id": 0, "role": "Raft", "addr": "127.0.0.1:9000" }
+//!     ]
+//! }
+//! ```
+
+use std::{
+    env, fs,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::LOOPBACK;
+
+/// Where the config lives if `CLUSTER_CONFIG` isn't set.
+pub const DEFAULT_PATH: &str = "cluster.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Role {
+    /// Paxos
+    Leader,
+    /// Paxos
+    Replica,
+    /// Paxos
+    Acceptor,
+    /// Raft server
+    Raft,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeConfig {
+    /// Unique among nodes of the same role.
+    pub id: usize,
+    pub role: Role,
+    pub addr: SocketAddr,
+    /// Starts outside the cluster, and waits to be brought in with `raft_admin` or `paxos_admin`.
+    #[serde(default)]
+    pub standby: bool,
+}
+
+/// Paxos leader leases, for serving reads without a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LeaseConfig {
+    /// How long an acceptor's grant lasts. 0 turns leases off, and reads go through the log like everything else.
+    pub duration_ms: u64,
+    /// Worst relative clock drift between a leader and the acceptors. The leader counts its lease as this much shorter.
+    pub max_drift: f64,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        Self {
+            duration_ms: 500,
+            max_drift: 0.1,
+        }
+    }
+}
+
+impl LeaseConfig {
+    pub fn enabled(&self) -> bool {
+        self.duration_ms > 0
+    }
+
+    /// As the acceptors see it.
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// As the leader sees it, counting from when it asked.
+    pub fn guaranteed(&self) -> Duration {
+        self.duration().mul_f64(1.0 - self.max_drift)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// Clients bind here. Raft servers reply to whatever address is in the command, so it had better be reachable.
+    #[serde(default = "loopback")]
+    pub client_ip: IpAddr,
+    /// Client `i` listens on `client_port + i`.
+    #[serde(default = "client_port")]
+    pub client_port: u16,
+    #[serde(default)]
+    pub lease: LeaseConfig,
+    pub nodes: Vec<NodeConfig>,
+}
+
+fn loopback() -> IpAddr {
+    IpAddr::from(LOOPBACK)
+}
+
+fn client_port() -> u16 {
+    10000
+}
+
+impl ClusterConfig {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let buf = fs::read(path).unwrap_or_else(|e| panic!("Can't read {}: {e}", path.display()));
+        serde_json::from_slice(&buf).unwrap_or_else(|e| panic!("Bad config {}: {e}", path.display()))
+    }
+
+    /// `$CLUSTER_CONFIG`, or `cluster.json` in the working directory.
+    pub fn from_env() -> Self {
+        Self::load(env::var("CLUSTER_CONFIG").unwrap_or(DEFAULT_PATH.to_string()))
+    }
+
+    /// Everything with this role, in file order.
+    pub fn nodes(&self, role: Role) -> impl Iterator<Item = &NodeConfig> {
+        self.nodes.iter().filter(move |n| n.role == role)
+    }
+
+    pub fn ids(&self, role: Role) -> Vec<usize> {
+        self.nodes(role).map(|n| n.id).collect()
+    }
+
+    pub fn count(&self, role: Role) -> usize {
+        self.nodes(role).count()
+    }
+
+    pub fn node(&self, role: Role, id: usize) -> &NodeConfig {
+        self.nodes(role)
+            .find(|n| n.id == id)
+            .unwrap_or_else(|| panic!("No {role:?} with id {id} in the config."))
+    }
+
+    pub fn addr(&self, role: Role, id: usize) -> SocketAddr {
+        self.node(role, id).addr
+    }
+
+    /// Where client `client_id` listens for replies.
+    pub fn client_addr(&self, client_id: usize) -> SocketAddr {
+        SocketAddr::new(self.client_ip, self.client_port + client_id as u16)
+    }
+}
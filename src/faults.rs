@@ -0,0 +1,415 @@
+//! A network that misbehaves on purpose: drops, delays, duplicates and reorders messages, and cuts nodes off.
+//!
+//! `Faults` is shared by every node in a cluster. `wrap` goes around a node's `Io` before the node gets it,
+//! and everything the node sends goes past the faults on the way out. Works the same on message-io sockets
+//! (`raft_threads`, `paxos_threads`) and under `sim`, whatever the messages are.
+//!
+//! What happens to a message is decided by hashing the seed and the message, like `sim`'s delays,
+//! so a simulated run with faults still replays from its seed.
+//!
+//! Scenarios are a list of steps, each at some time after the first message goes out:
+//!
+//! ```text
+//! drop 0.05; delay 1-20ms; 3s isolate raft 2; 6s heal; 8s partition leader 0 acceptor 0 / leader 1 acceptor 1,2
+//! ```
+//!
+//! Clients aren't wrapped, so they can still reach a node that's been cut off. It just can't answer.
+
+use std::{
+    env,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use message_io::network::Endpoint;
+
+use crate::{
+    config::{ClusterConfig, Role},
+    net::{Clock, Io, Network, TimerId},
+};
+
+/// How badly behaved every link is, partitions aside.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Rates {
+    /// Chance a message is lost.
+    pub drop: f64,
+    /// Chance it arrives twice.
+    pub duplicate: f64,
+    /// Extra delay, uniform in this range. Anything but a fixed delay reorders messages too.
+    pub delay: (Duration, Duration),
+    /// Chance a message is held back a further `delay.1`, so that later ones overtake it.
+    pub reorder: f64,
+}
+
+/// One step of a scenario.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Set(Rates),
+    /// Cut one node off from every other.
+    Isolate(SocketAddr),
+    /// Only nodes in the same group can talk. Nodes in no group can talk to anyone.
+    Partition(Vec<Vec<SocketAddr>>),
+    /// Undo every partition and isolation. Rates stay as they are.
+    Heal,
+}
+
+/// What the faults have done so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Counts {
+    pub sent: usize,
+    pub dropped: usize,
+    /// Didn't get past a partition.
+    pub cut: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+}
+
+struct Inner {
+    seed: u64,
+    rates: Rates,
+    isolated: HashSet<SocketAddr>,
+    /// Group of each partitioned node.
+    groups: HashMap<SocketAddr, usize>,
+    /// Steps still to come, soonest first.
+    script: Vec<(Duration, Action)>,
+    /// When the first message went out. Scripts count from here.
+    start: Option<Instant>,
+    /// Times each (sender, receiver, message) has been sent, so resends get their own fate.
+    seen: HashMap<u64, u64>,
+    counts: Counts,
+}
+
+impl Inner {
+    fn apply(&mut self, a: Action) {
+        match a {
+            Action::Set(r) => self.rates = r,
+            Action::Isolate(addr) => {
+                self.isolated.insert(addr);
+            }
+            Action::Partition(groups) => {
+                self.groups = groups
+                    .into_iter()
+                    .enumerate()
+                    .flat_map(|(i, g)| g.into_iter().map(move |a| (a, i)))
+                    .collect();
+            }
+            Action::Heal => {
+                self.isolated.clear();
+                self.groups.clear();
+            }
+        }
+    }
+
+    fn connected(&self, from: SocketAddr, to: SocketAddr) -> bool {
+        if from == to {
+            return true;
+        }
+        if self.isolated.contains(&from) || self.isolated.contains(&to) {
+            return false;
+        }
+        match (self.groups.get(&from), self.groups.get(&to)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    /// Uniform in [0, 1), from the message and what we're deciding about it.
+    fn roll(&self, id: u64, what: &str) -> f64 {
+        let mut h = DefaultHasher::new();
+        (self.seed, id, what).hash(&mut h);
+        (h.finish() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Extra delay for each copy of the message that gets through.
+    fn fate(&mut self, now: Instant, from: SocketAddr, to: SocketAddr, buf: &[u8]) -> Vec<Duration> {
+        let start = *self.start.get_or_insert(now);
+        while matches!(self.script.first(), Some((t, _)) if start + *t <= now) {
+            let (_, a) = self.script.remove(0);
+            self.apply(a);
+        }
+
+        self.counts.sent += 1;
+        if !self.connected(from, to) {
+            self.counts.cut += 1;
+            return vec![];
+        }
+        let mut h = DefaultHasher::new();
+        (from, to, buf).hash(&mut h);
+        let n = self.seen.entry(h.finish()).or_default();
+        *n += 1;
+        let id = {
+            let mut h2 = DefaultHasher::new();
+            (h.finish(), *n).hash(&mut h2);
+            h2.finish()
+        };
+
+        let r = self.rates;
+        if self.roll(id, "drop") < r.drop {
+            self.counts.dropped += 1;
+            return vec![];
+        }
+        let copies = if self.roll(id, "duplicate") < r.duplicate {
+            self.counts.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let (lo, hi) = r.delay;
+        (0..copies)
+            .map(|c| {
+                let mut d = lo + (hi - lo).mul_f64(self.roll(id, &format!("delay {c}")));
+                if self.roll(id, &format!("reorder {c}")) < r.reorder {
+                    self.counts.reordered += 1;
+                    d += hi;
+                }
+                d
+            })
+            .collect()
+    }
+}
+
+/// Faults for a whole cluster. Clones share them, so a scenario can be changed on the fly from anywhere.
+#[derive(Clone)]
+pub struct Faults(Arc<Mutex<Inner>>);
+
+impl Faults {
+    /// A perfectly good network, to start with.
+    pub fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            seed,
+            rates: Rates::default(),
+            isolated: HashSet::new(),
+            groups: HashMap::new(),
+            script: vec![],
+            start: None,
+            seen: HashMap::new(),
+            counts: Counts::default(),
+        })))
+    }
+
+    /// Do `a` now.
+    pub fn apply(&self, a: Action) {
+        self.0.lock().unwrap().apply(a);
+    }
+
+    /// Do `a` at `t` after the first message.
+    pub fn at(&self, t: Duration, a: Action) -> &Self {
+        let mut inner = self.0.lock().unwrap();
+        inner.script.push((t, a));
+        // Stable, so steps at the same time happen in the order they were given.
+        inner.script.sort_by_key(|s| s.0);
+        self
+    }
+
+    pub fn set(&self, r: Rates) {
+        self.apply(Action::Set(r));
+    }
+
+    pub fn isolate(&self, addr: SocketAddr) {
+        self.apply(Action::Isolate(addr));
+    }
+
+    pub fn partition(&self, groups: Vec<Vec<SocketAddr>>) {
+        self.apply(Action::Partition(groups));
+    }
+
+    pub fn heal(&self) {
+        self.apply(Action::Heal);
+    }
+
+    pub fn counts(&self) -> Counts {
+        self.0.lock().unwrap().counts
+    }
+
+    /// `io`, with everything the node at `addr` sends going past the faults first.
+    pub fn wrap<T: Send + 'static>(&self, addr: SocketAddr, io: Box<dyn Io<T>>) -> Box<dyn Io<T>> {
+        Box::new(Faulty {
+            addr,
+            io,
+            faults: self.clone(),
+        })
+    }
+
+    /// Faults following the script in `$FAULTS`, if it's set. Panics if the script doesn't make sense.
+    pub fn from_env(cfg: &ClusterConfig) -> Option<Self> {
+        let script = env::var("FAULTS").ok()?;
+        let seed = rand::random();
+        println!("Faults with seed {seed}: {script}");
+        Some(Self::parse(seed, cfg, &script).unwrap_or_else(|e| panic!("Bad FAULTS: {e}")))
+    }
+
+    /// Faults following `script`, which names nodes as they are in `cfg`. See the module docs for what it looks like.
+    /// Steps are separated by `;` or newlines, and start with a time (`3s`, `500ms`) unless they're for the start.
+    ///
+    /// - `drop P`, `duplicate P`, `reorder P`: chances, between 0 and 1
+    /// - `delay 5ms` or `delay 1-20ms`: extra delay, fixed or in a range
+    /// - `isolate ROLE ID`
+    /// - `partition ROLE IDS ... / ROLE IDS ... / ...`: groups split by `/`, ids by `,`
+    /// - `heal`
+    ///
+    /// Rates carry over from one step to the next, so `5s drop 0` stops the dropping and leaves any delay as it is.
+    pub fn parse(seed: u64, cfg: &ClusterConfig, script: &str) -> Result<Self, String> {
+        let out = Self::new(seed);
+        let mut rates = Rates::default();
+        for step in script.split([';', '\n']).map(str::trim).filter(|s| !s.is_empty()) {
+            let mut words = step.split_whitespace().peekable();
+            let at = match words.peek().and_then(|w| duration(w)) {
+                Some(d) => {
+                    words.next();
+                    d
+                }
+                None => Duration::ZERO,
+            };
+            let what = words.next().ok_or(format!("Nothing to do in {step:?}"))?;
+            let rest = words.collect::<Vec<_>>();
+            let chance = || -> Result<f64, String> {
+                match rest.as_slice() {
+                    [p] => p.parse().map_err(|_| format!("Expected a chance in {step:?}")),
+                    _ => Err(format!("Expected one chance in {step:?}")),
+                }
+            };
+            let action = match what {
+                "drop" => {
+                    rates.drop = chance()?;
+                    Action::Set(rates)
+                }
+                "duplicate" => {
+                    rates.duplicate = chance()?;
+                    Action::Set(rates)
+                }
+                "reorder" => {
+                    rates.reorder = chance()?;
+                    Action::Set(rates)
+                }
+                "delay" => match rest.as_slice() {
+                    [d] => {
+                        rates.delay = delays(d).ok_or(format!("Bad delay in {step:?}"))?;
+                        Action::Set(rates)
+                    }
+                    _ => return Err(format!("Expected one delay in {step:?}")),
+                },
+                "isolate" => match nodes(cfg, &rest)?.as_slice() {
+                    [a] => Action::Isolate(*a),
+                    _ => return Err(format!("Expected one node in {step:?}")),
+                },
+                "partition" => Action::Partition(
+                    rest.split(|w| *w == "/")
+                        .map(|g| nodes(cfg, g))
+                        .collect::<Result<_, _>>()?,
+                ),
+                "heal" => Action::Heal,
+                _ => return Err(format!("Don't know how to {what:?}")),
+            };
+            out.at(at, action);
+        }
+        Ok(out)
+    }
+}
+
+/// `io` wrapped in `faults`, if there are any.
+pub fn wrap<T: Send + 'static>(faults: Option<&Faults>, addr: SocketAddr, io: Box<dyn Io<T>>) -> Box<dyn Io<T>> {
+    match faults {
+        Some(f) => f.wrap(addr, io),
+        None => io,
+    }
+}
+
+/// `3s`, `500ms`, `2.5s`.
+fn duration(s: &str) -> Option<Duration> {
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms.parse::<f64>().ok().map(|ms| Duration::from_secs_f64(ms / 1000.0));
+    }
+    s.strip_suffix('s')?.parse::<f64>().ok().map(Duration::from_secs_f64)
+}
+
+/// `5ms`, or a range like `1-20ms`.
+fn delays(s: &str) -> Option<(Duration, Duration)> {
+    let Some((lo, hi)) = s.split_once('-') else {
+        let d = duration(s)?;
+        return Some((d, d));
+    };
+    let hi = duration(hi)?;
+    // The unit can be left off the first, as in 1-20ms.
+    let unit = s.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.' || c == '-');
+    let lo = duration(lo).or_else(|| duration(&format!("{lo}{unit}")))?;
+    (lo <= hi).then_some((lo, hi))
+}
+
+/// `raft 1,2 leader 0` to addresses.
+fn nodes(cfg: &ClusterConfig, words: &[&str]) -> Result<Vec<SocketAddr>, String> {
+    let mut out = vec![];
+    for pair in words.chunks(2) {
+        let [role, ids] = pair else {
+            return Err(format!("Expected a role and ids in {words:?}"));
+        };
+        let role = match *role {
+            "leader" => Role::Leader,
+            "replica" => Role::Replica,
+            "acceptor" => Role::Acceptor,
+            "raft" => Role::Raft,
+            _ => return Err(format!("No such role {role:?}")),
+        };
+        for id in ids.split(',') {
+            let id = id.parse::<usize>().map_err(|_| format!("Bad id {id:?}"))?;
+            let n = cfg
+                .nodes(role)
+                .find(|n| n.id == id)
+                .ok_or(format!("No {role:?} {id} in the config"))?;
+            out.push(n.addr);
+        }
+    }
+    Ok(out)
+}
+
+/// A node's `Io`, with faults on the way out.
+struct Faulty<T> {
+    addr: SocketAddr,
+    io: Box<dyn Io<T>>,
+    faults: Faults,
+}
+
+impl<T: Send + 'static> Network for Faulty<T> {
+    fn send(&self, to: Endpoint, buf: &[u8]) {
+        self.send_later(to, buf, Duration::ZERO);
+    }
+
+    fn send_later(&self, to: Endpoint, buf: &[u8], after: Duration) {
+        let now = self.io.now();
+        let fate = self.faults.0.lock().unwrap().fate(now, self.addr, to.addr(), buf);
+        for d in fate {
+            match after + d {
+                Duration::ZERO => self.io.send(to, buf),
+                d => self.io.send_later(to, buf, d),
+            }
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Endpoint {
+        self.io.connect(addr)
+    }
+}
+
+impl<T: Send + 'static> Clock<T> for Faulty<T> {
+    fn now(&self) -> Instant {
+        self.io.now()
+    }
+
+    fn timer(&self, t: T, after: Duration) -> TimerId {
+        self.io.timer(t, after)
+    }
+
+    fn cancel(&self, id: TimerId) {
+        self.io.cancel(id)
+    }
+
+    fn signal(&self, t: T) {
+        self.io.signal(t)
+    }
+
+    fn random(&self) -> f64 {
+        self.io.random()
+    }
+}
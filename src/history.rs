@@ -0,0 +1,97 @@
+//! What clients saw: every op, when it went in, and when and what came back.
+//!
+//! Clients in one process share a `History` (`Client::record`), and `SimClient` keeps its own on virtual time.
+//! `linearizability` checks one. Histories from several processes can be written out with `save` and put back
+//! together with `load`, as long as the processes share a clock.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::Outcome,
+    kv::{KvOp, KvResult},
+    Payload,
+};
+
+/// One op, as a client saw it. `O` and `R` are the state machine's ops and outputs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Call<O = KvOp, R = KvResult> {
+    pub client: usize,
+    pub op: O,
+    pub invoked: Duration,
+    /// None if it's still going.
+    pub returned: Option<(Duration, Outcome<R>)>,
+}
+
+/// Calls from any number of clients, on the wall clock. Clones share it.
+#[derive(Debug)]
+pub struct History<O = KvOp, R = KvResult>(Arc<Mutex<Vec<Call<O, R>>>>);
+
+// Not derived: that would want O and R to be Clone and Default too.
+impl<O, R> Clone for History<O, R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<O, R> Default for History<O, R> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<O: Payload, R: Payload> History<O, R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Since the epoch, so histories from different processes on one machine line up.
+    pub fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    /// `client` just sent `op`. Returns where to `finish` it.
+    pub fn invoke(&self, client: usize, op: O) -> usize {
+        let mut calls = self.0.lock().unwrap();
+        calls.push(Call {
+            client,
+            op,
+            invoked: Self::now(),
+            returned: None,
+        });
+        calls.len() - 1
+    }
+
+    pub fn finish(&self, i: usize, res: Outcome<R>) {
+        self.0.lock().unwrap()[i].returned = Some((Self::now(), res));
+    }
+
+    pub fn calls(&self) -> Vec<Call<O, R>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// One call per line, as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) {
+        let out = self
+            .calls()
+            .iter()
+            .map(|c| serde_json::to_string(c).unwrap() + "\n")
+            .collect::<String>();
+        fs::write(path, out).unwrap();
+    }
+}
+
+/// Whatever `History::save` wrote.
+pub fn load(path: impl AsRef<Path>) -> Vec<Call> {
+    let path = path.as_ref();
+    let buf = fs::read_to_string(path).unwrap_or_else(|e| panic!("Can't read {}: {e}", path.display()));
+    buf.lines()
+        .map(|l| serde_json::from_str(l).unwrap_or_else(|e| panic!("Bad call in {}: {e}", path.display())))
+        .collect()
+}
@@ -0,0 +1,493 @@
+//! Safety properties checked across every node of a cluster while it runs.
+//!
+//! One `Invariants` is shared by the whole cluster. After each message or timer, a Raft server hands it a
+//! `RaftView` (`Server::observe`) and a Paxos replica its decisions (`Replica::observe`). Only what changed since
+//! the node's last report gets checked, against what every other node has reported so far:
+//!
+//! - Paxos: no two replicas decide different values for a slot.
+//! - Raft, Log Matching: two logs with an entry of the same index and term are the same up to there. Checked as
+//!   "an (index, term) always has the same entry, after the same term", which gives the rest by induction.
+//! - Raft, State Machine Safety: nobody commits a different entry at an index somebody else committed.
+//! - Raft, Leader Completeness: a leader has every entry committed in an earlier term.
+//!
+//! `sim` checks these after every event with `Sim::set_invariants`. The threaded harnesses do with `INVARIANTS` set.
+//! Violations are printed as they're found, and kept for `violations`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{kv::KvOp, paxos::Value, raft::Log, Payload};
+
+/// What a Raft server looks like right now, as far as the invariants go.
+pub struct RaftView<'a, O = KvOp> {
+    pub term: usize,
+    pub leader: bool,
+    pub commit_index: usize,
+    /// Index of `log[0]`. That's the snapshot point, standing in for everything compacted.
+    pub first: usize,
+    pub log: &'a [Log<O>],
+}
+
+/// `O` is the ops in the logs and decisions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation<O = KvOp> {
+    /// Two replicas decided different values for one slot.
+    Agreement { slot: usize, replicas: (usize, usize), values: (Value<O>, Value<O>) },
+    /// Two servers have an entry at `index` from `term`, but not the same one, or not after the same term.
+    LogMatching {
+        index: usize,
+        term: usize,
+        servers: (usize, usize),
+        entries: (Log<O>, Log<O>),
+        prev_terms: (usize, usize),
+    },
+    /// `leader` leads `term` without the entry `committer` committed at `index`. `found` is the term of what it has there.
+    LeaderCompleteness {
+        leader: usize,
+        term: usize,
+        index: usize,
+        committer: usize,
+        entry_term: usize,
+        found: Option<usize>,
+    },
+    /// Two servers committed entries from different terms at `index`.
+    StateMachineSafety { index: usize, servers: (usize, usize), terms: (usize, usize) },
+}
+
+impl<O: fmt::Debug> fmt::Display for Violation<O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Agreement { slot, replicas: (a, b), values: (x, y) } if a == b => {
+                writeln!(f, "Replica {a} decided slot {slot} again, differently:")?;
+                writeln!(f, "    first: {x:?}")?;
+                write!(f, "    then: {y:?}")
+            }
+            Violation::Agreement { slot, replicas: (a, b), values: (x, y) } => {
+                writeln!(f, "Replicas {a} and {b} decided different values for slot {slot}:")?;
+                writeln!(f, "    replica {a}: {x:?}")?;
+                write!(f, "    replica {b}: {y:?}")
+            }
+            Violation::LogMatching { index, term, servers: (a, b), entries: (x, y), prev_terms: (p, q) } => {
+                writeln!(f, "Log Matching: servers {a} and {b} both have index {index} from term {term}, but their logs differ up to it:")?;
+                writeln!(f, "    server {a}: {x:?}, after an entry from term {p}")?;
+                write!(f, "    server {b}: {y:?}, after an entry from term {q}")
+            }
+            Violation::LeaderCompleteness { leader, term, index, committer, entry_term, found } => {
+                write!(
+                    f,
+                    "Leader Completeness: server {leader} leads term {term}, but server {committer} committed index {index} \
+                     from term {entry_term} and the leader has "
+                )?;
+                match found {
+                    Some(t) => write!(f, "an entry from term {t} there"),
+                    None => write!(f, "nothing there"),
+                }
+            }
+            Violation::StateMachineSafety { index, servers: (a, b), terms: (x, y) } => write!(
+                f,
+                "State Machine Safety: servers {a} and {b} committed different entries at index {index}, from terms {x} and {y}"
+            ),
+        }
+    }
+}
+
+/// What a Raft server looked like last time.
+struct Seen<O> {
+    term: usize,
+    leader: bool,
+    commit_index: usize,
+    first: usize,
+    log: Vec<Log<O>>,
+}
+
+// Not derived: that would want O: Default. So would the ones below.
+impl<O> Default for Seen<O> {
+    fn default() -> Self {
+        Self {
+            term: 0,
+            leader: false,
+            commit_index: 0,
+            first: 0,
+            log: vec![],
+        }
+    }
+}
+
+impl<O> Seen<O> {
+    fn term_at(&self, index: usize) -> Option<usize> {
+        self.log.get(index.checked_sub(self.first)?).map(|l| l.term())
+    }
+}
+
+/// Somebody committed the entry from `term` at this index, while in `at`.
+struct Commit {
+    server: usize,
+    term: usize,
+    at: usize,
+}
+
+struct Inner<O> {
+    servers: HashMap<usize, Seen<O>>,
+    /// Every (index, term) seen in any log: who had it first, the term before it, and the entry.
+    entries: HashMap<(usize, usize), (usize, usize, Log<O>)>,
+    committed: BTreeMap<usize, Commit>,
+    /// Each replica's decisions, last time.
+    replicas: HashMap<usize, HashMap<usize, Value<O>>>,
+    /// First decision seen for each slot, and which replica had it.
+    decided: HashMap<usize, (usize, Value<O>)>,
+    violations: Vec<Violation<O>>,
+    /// Leaders (and their terms) and servers already reported missing something or disagreeing on commits.
+    incomplete: HashSet<(usize, usize)>,
+    diverged: HashSet<usize>,
+}
+
+impl<O> Default for Inner<O> {
+    fn default() -> Self {
+        Self {
+            servers: HashMap::new(),
+            entries: HashMap::new(),
+            committed: BTreeMap::new(),
+            replicas: HashMap::new(),
+            decided: HashMap::new(),
+            violations: vec![],
+            incomplete: HashSet::new(),
+            diverged: HashSet::new(),
+        }
+    }
+}
+
+impl<O: Payload> Inner<O> {
+    fn violated(&mut self, v: Violation<O>) {
+        // Once a leader's missing a committed entry, or a server has committed the wrong one, the ones after
+        // go wrong too. Only the first is news.
+        let news = match &v {
+            Violation::LeaderCompleteness { leader, term, .. } => self.incomplete.insert((*leader, *term)),
+            Violation::StateMachineSafety { servers: (_, s), .. } => self.diverged.insert(*s),
+            _ => true,
+        };
+        if !news {
+            return;
+        }
+        println!("Invariant violated. {v}");
+        self.violations.push(v);
+    }
+
+    fn raft(&mut self, id: usize, view: RaftView<O>) {
+        let mut seen = self.servers.remove(&id).unwrap_or_default();
+        // Where the log changed. All of it, if the snapshot moved.
+        let from = match seen.first == view.first {
+            true => (0..view.log.len())
+                .find(|k| seen.log.get(*k) != Some(&view.log[*k]))
+                .unwrap_or(view.log.len()),
+            false => 0,
+        };
+        // log[0] only has a term, the entry itself is compacted.
+        for k in from.max(1)..view.log.len() {
+            let (index, entry) = (view.first + k, &view.log[k]);
+            let prev = view.log[k - 1].term();
+            match self.entries.get(&(index, entry.term())) {
+                Some((other, p, e)) if *p != prev || e != entry => {
+                    let v = Violation::LogMatching {
+                        index,
+                        term: entry.term(),
+                        servers: (*other, id),
+                        entries: (e.clone(), entry.clone()),
+                        prev_terms: (*p, prev),
+                    };
+                    self.violated(v);
+                }
+                Some(_) => {}
+                None => {
+                    self.entries.insert((index, entry.term()), (id, prev, entry.clone()));
+                }
+            }
+        }
+
+        let crowned = view.leader && (!seen.leader || seen.term != view.term);
+        seen.term = view.term;
+        seen.leader = view.leader;
+        seen.first = view.first;
+        seen.log.truncate(from);
+        seen.log.extend_from_slice(&view.log[from..]);
+
+        // Newly committed. Anything below the snapshot point went by between reports, nothing left to check it by.
+        for index in (seen.commit_index + 1).max(view.first)..=view.commit_index {
+            let Some(term) = seen.term_at(index) else {
+                continue;
+            };
+            match self.committed.get(&index) {
+                Some(c) if c.term != term => {
+                    let v = Violation::StateMachineSafety {
+                        index,
+                        servers: (c.server, id),
+                        terms: (c.term, term),
+                    };
+                    self.violated(v);
+                }
+                Some(_) => {}
+                None => {
+                    self.committed.insert(index, Commit { server: id, term, at: view.term });
+                    // Any leader of a later term should have it already.
+                    let leaders = self.servers.iter().filter(|(_, s)| s.leader && s.term > view.term);
+                    let missing = leaders
+                        .filter(|(_, s)| index > s.first && s.term_at(index) != Some(term))
+                        .map(|(l, s)| Violation::LeaderCompleteness {
+                            leader: *l,
+                            term: s.term,
+                            index,
+                            committer: id,
+                            entry_term: term,
+                            found: s.term_at(index),
+                        })
+                        .collect::<Vec<_>>();
+                    for v in missing {
+                        self.violated(v);
+                    }
+                }
+            }
+        }
+        seen.commit_index = seen.commit_index.max(view.commit_index);
+
+        // A new leader has to have everything committed before its term.
+        if crowned {
+            let missing = self
+                .committed
+                .range(seen.first + 1..)
+                .filter(|(i, c)| c.at < seen.term && seen.term_at(**i) != Some(c.term))
+                .map(|(i, c)| Violation::LeaderCompleteness {
+                    leader: id,
+                    term: seen.term,
+                    index: *i,
+                    committer: c.server,
+                    entry_term: c.term,
+                    found: seen.term_at(*i),
+                })
+                .collect::<Vec<_>>();
+            for v in missing {
+                self.violated(v);
+            }
+        }
+        self.servers.insert(id, seen);
+    }
+
+    fn replica<'a>(&mut self, id: usize, decisions: impl Iterator<Item = (&'a usize, &'a Value<O>)>) {
+        let mut seen = self.replicas.remove(&id).unwrap_or_default();
+        for (slot, value) in decisions {
+            if seen.get(slot) == Some(value) {
+                continue;
+            }
+            seen.insert(*slot, value.clone());
+            match self.decided.get(slot) {
+                Some((other, v)) if v != value => {
+                    let v = Violation::Agreement {
+                        slot: *slot,
+                        replicas: (*other, id),
+                        values: (v.clone(), value.clone()),
+                    };
+                    self.violated(v);
+                }
+                Some(_) => {}
+                None => {
+                    self.decided.insert(*slot, (id, value.clone()));
+                }
+            }
+        }
+        self.replicas.insert(id, seen);
+    }
+}
+
+/// Invariants for a whole cluster. Clones share them.
+pub struct Invariants<O = KvOp>(Arc<Mutex<Inner<O>>>);
+
+impl<O> Clone for Invariants<O> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<O> Default for Invariants<O> {
+    fn default() -> Self {
+        Self(Arc::default())
+    }
+}
+
+impl<O: Payload> Invariants<O> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checking on, if `$INVARIANTS` is set.
+    pub fn from_env() -> Option<Self> {
+        env::var_os("INVARIANTS").map(|_| Self::new())
+    }
+
+    /// Raft server `id`, as it is now.
+    pub fn raft(&self, id: usize, view: RaftView<O>) {
+        self.0.lock().unwrap().raft(id, view);
+    }
+
+    /// Paxos replica `id`'s decisions, as they are now.
+    pub fn replica<'a>(&self, id: usize, decisions: impl IntoIterator<Item = (&'a usize, &'a Value<O>)>) {
+        self.0.lock().unwrap().replica(id, decisions.into_iter());
+    }
+
+    /// Everything found so far, in the order it was found.
+    pub fn violations(&self) -> Vec<Violation<O>> {
+        self.0.lock().unwrap().violations.clone()
+    }
+
+    /// Print everything found so far. Whether that was nothing.
+    pub fn report(&self) -> bool {
+        let vs = self.violations();
+        match vs.len() {
+            0 => println!("Invariants held."),
+            n => println!("{n} invariant violations:"),
+        }
+        for v in &vs {
+            println!("{v}");
+        }
+        vs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::paxos::Command;
+
+    fn put(client_id: usize, v: &str) -> Value {
+        Value::Command(Command {
+            client_id,
+            op_id: 0,
+            op: KvOp::Put("k".into(), v.into()),
+        })
+    }
+
+    #[test]
+    fn replicas_disagreeing_on_a_slot_is_caught() {
+        let inv = Invariants::new();
+        inv.replica(0, &HashMap::from([(0, put(0, "a")), (1, put(1, "b"))]));
+        inv.replica(1, &HashMap::from([(0, put(0, "a"))]));
+        assert!(inv.violations().is_empty());
+
+        // Replica 1 gets slot 1 wrong, then keeps telling us. Once is enough.
+        let wrong = HashMap::from([(0, put(0, "a")), (1, put(2, "c"))]);
+        inv.replica(1, &wrong);
+        inv.replica(1, &wrong);
+        assert_eq!(
+            inv.violations(),
+            vec![Violation::Agreement {
+                slot: 1,
+                replicas: (0, 1),
+                values: (put(1, "b"), put(2, "c")),
+            }]
+        );
+    }
+
+    /// Fields are private to `raft`, so make it the way a follower gets it, off the wire.
+    fn entry(term: usize, v: &str) -> Log {
+        let cmd = json!({ "client": "127.0.0.1:10000", "op_id": 0, "op": { "Put": ["k", v] } });
+        serde_json::from_value(json!({ "term": term, "command": cmd })).unwrap()
+    }
+
+    /// What `log[0]` looks like, at a snapshot point from `term`.
+    fn sentinel(term: usize) -> Log {
+        serde_json::from_value(json!({ "term": term, "command": null })).unwrap()
+    }
+
+    fn view(term: usize, leader: bool, commit_index: usize, first: usize, log: &[Log]) -> RaftView<'_> {
+        RaftView { term, leader, commit_index, first, log }
+    }
+
+    #[test]
+    fn raft_cluster_doing_its_job_is_fine() {
+        let inv = Invariants::new();
+        let (a, b, c) = (entry(1, "a"), entry(1, "b"), entry(2, "c"));
+        // Leader 0 of term 1 gets two entries out to 1, and commits them.
+        inv.raft(0, view(1, true, 0, 0, &[sentinel(0), a.clone(), b.clone()]));
+        inv.raft(1, view(1, false, 0, 0, &[sentinel(0), a.clone(), b.clone()]));
+        inv.raft(0, view(1, true, 2, 0, &[sentinel(0), a.clone(), b.clone()]));
+        // Server 2 only got the first.
+        inv.raft(2, view(1, false, 0, 0, &[sentinel(0), a.clone()]));
+        // 1 takes over with everything committed, and 2 catches up.
+        inv.raft(1, view(2, true, 2, 0, &[sentinel(0), a.clone(), b.clone(), c.clone()]));
+        inv.raft(2, view(2, false, 3, 0, &[sentinel(0), a.clone(), b.clone(), c.clone()]));
+        // And compacts. The snapshot point moves, and only its term is left of what's under it.
+        inv.raft(2, view(2, false, 3, 2, &[sentinel(1), c.clone()]));
+        inv.raft(1, view(2, true, 3, 3, &[sentinel(2)]));
+        assert!(inv.violations().is_empty());
+    }
+
+    #[test]
+    fn raft_logs_matching_at_an_index_and_term_but_differing_is_caught() {
+        let inv = Invariants::new();
+        inv.raft(0, view(1, true, 0, 0, &[sentinel(0), entry(1, "a")]));
+        inv.raft(1, view(1, false, 0, 0, &[sentinel(0), entry(1, "b")]));
+        // Same entry, different history before it.
+        inv.raft(2, view(2, false, 0, 0, &[sentinel(0), entry(2, "c"), entry(2, "d")]));
+        inv.raft(3, view(2, false, 0, 1, &[sentinel(1), entry(2, "d")]));
+        assert_eq!(
+            inv.violations(),
+            vec![
+                Violation::LogMatching {
+                    index: 1,
+                    term: 1,
+                    servers: (0, 1),
+                    entries: (entry(1, "a"), entry(1, "b")),
+                    prev_terms: (0, 0),
+                },
+                Violation::LogMatching {
+                    index: 2,
+                    term: 2,
+                    servers: (2, 3),
+                    entries: (entry(2, "d"), entry(2, "d")),
+                    prev_terms: (2, 1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn raft_servers_committing_different_entries_is_caught() {
+        let inv = Invariants::new();
+        inv.raft(0, view(1, true, 1, 0, &[sentinel(0), entry(1, "a")]));
+        // Only news the first time it says so.
+        for _ in 0..2 {
+            inv.raft(1, view(2, false, 1, 0, &[sentinel(0), entry(2, "b")]));
+        }
+        // Straight out of a snapshot. Index 1 is under it, but index 2 isn't.
+        inv.raft(0, view(1, true, 2, 0, &[sentinel(0), entry(1, "a"), entry(1, "c")]));
+        inv.raft(2, view(3, false, 2, 1, &[sentinel(1), entry(3, "d")]));
+        assert_eq!(
+            inv.violations(),
+            vec![
+                Violation::StateMachineSafety { index: 1, servers: (0, 1), terms: (1, 2) },
+                Violation::StateMachineSafety { index: 2, servers: (0, 2), terms: (1, 3) },
+            ]
+        );
+    }
+
+    #[test]
+    fn raft_leader_missing_a_committed_entry_is_caught() {
+        let inv = Invariants::new();
+        inv.raft(0, view(1, true, 1, 0, &[sentinel(0), entry(1, "a")]));
+        // Elected without it.
+        inv.raft(1, view(2, true, 0, 0, &[sentinel(0)]));
+        // Already leading when a server from an earlier term commits something it doesn't have.
+        inv.raft(2, view(3, true, 0, 0, &[sentinel(0), entry(1, "a"), entry(3, "c")]));
+        // Leader 1 is missing that one too, but it's already been reported.
+        inv.raft(0, view(1, false, 2, 0, &[sentinel(0), entry(1, "a"), entry(1, "b")]));
+        // Snapshotted past it, so there's nothing left to tell. Not a violation.
+        inv.raft(3, view(4, true, 2, 2, &[sentinel(1)]));
+        let expected = vec![
+            Violation::LeaderCompleteness { leader: 1, term: 2, index: 1, committer: 0, entry_term: 1, found: None },
+            Violation::LeaderCompleteness { leader: 2, term: 3, index: 2, committer: 0, entry_term: 1, found: Some(3) },
+        ];
+        assert_eq!(inv.violations(), expected);
+    }
+}
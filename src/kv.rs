@@ -0,0 +1,114 @@
+//! A replicated key-value store, usable under both Paxos and Raft.
+//!
+//! Commands are applied to a `BTreeMap`, so iteration (and hence snapshots) are deterministic.
+
+use std::collections::BTreeMap;
+
+use rand::{
+    distributions::{Distribution, Uniform},
+    Rng,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::StateMachine;
+
+/// Number of distinct keys the random workload touches. Small, so that commands actually conflict.
+pub const KEY_SPACE: usize = 10;
+
+/// The commands understood by the store.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum KvOp {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+    /// Compare-and-swap. Sets `key` to `new` only if it currently holds `expected`, `None` meaning absent.
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+}
+
+/// `Ok` carries the value before the command ran (the current value for a `Get`).
+/// `Err` is only produced by a failed `Cas`, and carries the value that was actually there.
+pub type KvResult = Result<Option<String>, Option<String>>;
+
+impl KvOp {
+    /// The key this command touches.
+    pub fn key(&self) -> &str {
+        match self {
+            KvOp::Get(k) | KvOp::Put(k, _) | KvOp::Delete(k) => k,
+            KvOp::Cas { key, .. } => key,
+        }
+    }
+
+    /// Some command over a small key space. Mostly puts and gets.
+    pub fn random<R: Rng>(rng: &mut R) -> Self {
+        let key = format!("k{}", Uniform::from(0..KEY_SPACE).sample(rng));
+        let val = rng.gen::<u64>().to_string();
+        match Uniform::from(0..10).sample(rng) {
+            0..=3 => KvOp::Put(key, val),
+            4..=7 => KvOp::Get(key),
+            8 => KvOp::Delete(key),
+            _ => KvOp::Cas {
+                key,
+                expected: None,
+                new: val,
+            },
+        }
+    }
+}
+
+/// The store itself.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KvStore {
+    map: BTreeMap<String, String>,
+}
+
+impl KvStore {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.map.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl StateMachine for KvStore {
+    type Op = KvOp;
+    type Output = KvResult;
+
+    fn apply(&mut self, op: &KvOp) -> KvResult {
+        match op {
+            KvOp::Get(k) => Ok(self.map.get(k).cloned()),
+            KvOp::Put(k, v) => Ok(self.map.insert(k.clone(), v.clone())),
+            KvOp::Delete(k) => Ok(self.map.remove(k)),
+            KvOp::Cas { key, expected, new } => {
+                let current = self.map.get(key).cloned();
+                if current == *expected {
+                    self.map.insert(key.clone(), new.clone());
+                    Ok(current)
+                } else {
+                    Err(current)
+                }
+            }
+        }
+    }
+
+    fn is_read_only(op: &KvOp) -> bool {
+        matches!(op, KvOp::Get(_))
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self = serde_json::from_slice(snapshot).unwrap();
+    }
+}
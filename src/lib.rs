@@ -1,15 +1,30 @@
-use std::{fs::File, io::Read, thread, time::Duration};
+use std::{fmt::Debug, fs::File, io::Read, sync::mpsc, thread, time::Duration};
+
+use client::{Client, Protocol};
+use history::{Call, History};
 
 use rand::{
     distributions::{Distribution, Uniform},
     rngs::ThreadRng,
 };
-use serde::{Deserialize, Serialize};
+use kv::{KvOp, KvResult};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 pub const LOOPBACK: [u8; 4] = [127, 0, 0, 1];
 
+pub mod bench;
+pub mod client;
+pub mod config;
+pub mod faults;
+pub mod history;
+pub mod invariants;
+pub mod kv;
+pub mod linearizability;
+pub mod net;
 pub mod paxos;
 pub mod raft;
+pub mod session;
+pub mod sim;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Params {
@@ -17,6 +32,12 @@ pub struct Params {
     l: f64,
 }
 
+impl Default for Params {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Params {
     pub fn new() -> Self {
         let mut file = File::open("inp-params.txt").unwrap();
@@ -35,23 +56,83 @@ impl Params {
     }
 
     pub fn get_delay(u: Uniform<f64>, rng: &mut ThreadRng, l: f64) -> Duration {
-        let ts = -(u.sample(rng) as f64).ln() * l;
+        let ts = -u.sample(rng).ln() * l;
         Duration::from_millis(ts as u64)
     }
 
     pub fn sleep(&self, u: Uniform<f64>, rng: &mut ThreadRng) {
         thread::sleep(Self::get_delay(u, rng, self.l));
     }
+
+    /// k random ops through `client`, the usual random gaps apart, without waiting for answers in between.
+    /// Then waits for them all, and returns every call.
+    pub fn drive<P: Protocol<Op = KvOp, Output = KvResult>>(&self, client: &Client<P>) -> Vec<Call> {
+        let history = History::new();
+        client.record(&history);
+        let u = Uniform::from(0.0..1.0);
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..self.k {
+            let tx = tx.clone();
+            client.submit_with(KvOp::random(&mut rand::thread_rng()), move |res| {
+                let _ = tx.send(res);
+            });
+            self.sleep(u, &mut rand::thread_rng());
+        }
+        for _ in 0..self.k {
+            let _ = rx.recv();
+        }
+        history.calls()
+    }
 }
 
+/// Anything that the consensus layer can replicate.
+///
+/// Both Paxos replicas and Raft servers feed decided commands through `apply`, in the same order everywhere,
+/// so implementations must be deterministic. `snapshot` and `restore` are for moving whole states around.
+pub trait StateMachine: Default + Send + 'static {
+    /// What a client asks for.
+    type Op: Payload;
+    /// What the client gets back.
+    type Output: Payload;
+
+    /// Apply one decided command.
+    fn apply(&mut self, op: &Self::Op) -> Self::Output;
+    /// Ops that only look. These can skip the log, and `apply` must not change anything for them.
+    fn is_read_only(_op: &Self::Op) -> bool {
+        false
+    }
+    /// Serialise the entire state.
+    fn snapshot(&self) -> Vec<u8>;
+    /// Replace the entire state with one produced by `snapshot`.
+    fn restore(&mut self, snapshot: &[u8]);
+}
+
+/// Anything that goes over the wire, into the log and into checkpoints: ops and outputs.
+pub trait Payload: Debug + Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static {}
+
+impl<T> Payload for T where T: Debug + Clone + PartialEq + Serialize + DeserializeOwned + Send + 'static {}
+
 /// Right now this is just a `usize`, but it can really be anything. The rest of the code is general enough.
+///
+/// Trivial state machine: ignores the command, never changes, and echoes the command back.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Default, Copy)]
 pub struct ReplicaState {
     n: usize,
 }
 
-impl ReplicaState {
-    pub fn triv(s: String) -> impl Fn(&ReplicaState) -> (ReplicaState, Result<String, String>) {
-        move |q| (*q, Ok(s.clone()))
+impl StateMachine for ReplicaState {
+    type Op = String;
+    type Output = Result<String, String>;
+
+    fn apply(&mut self, op: &String) -> Result<String, String> {
+        Ok(op.clone())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self = serde_json::from_slice(snapshot).unwrap();
     }
 }
@@ -0,0 +1,346 @@
+//! Checks a history of client calls is linearizable: that there's some order to put every op in,
+//! one at a time, that respects real time (an op that returned before another was invoked comes first)
+//! and gets every client exactly the answer it got.
+//!
+//! This is the Wing & Gong search with Lowe's memoisation, the way Porcupine does it. Histories are split
+//! up by key first (`Model::partition`), since a history is linearizable if and only if each key's is.
+//!
+//! Ops that never came back (timed out, or still going when the run ended) may or may not have happened.
+//! They're treated as returning at the end of time with whatever answer fits.
+//!
+//! ```no_run
+//! use dc_project::{history, linearizability::{self, Kv}};
+//!
+//! let calls = history::load("history-0.json");
+//! if let Err(v) = linearizability::check::<Kv>(&calls) {
+//!     panic!("{v}");
+//! }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    hash::Hash,
+    time::Duration,
+};
+
+use crate::{
+    history::Call,
+    kv::{KvOp, KvResult},
+};
+
+/// What a correct store would do.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+
+    fn init() -> Self::State;
+    /// `state` after `op`, if `op` could have answered `res` there. `res` is None if nobody knows what it answered.
+    fn step(state: &Self::State, op: &KvOp, res: Option<&KvResult>) -> Option<Self::State>;
+    /// Split into histories that can be checked on their own.
+    fn partition(calls: &[Call]) -> Vec<Vec<Call>> {
+        vec![calls.to_vec()]
+    }
+}
+
+/// One value, whatever the key. Put, Delete and Cas as in `KvStore`.
+pub struct Register;
+
+impl Model for Register {
+    type State = Option<String>;
+
+    fn init() -> Self::State {
+        None
+    }
+
+    fn step(state: &Self::State, op: &KvOp, res: Option<&KvResult>) -> Option<Self::State> {
+        let mut next = state.clone();
+        let out = match op {
+            KvOp::Get(_) => Ok(next.clone()),
+            KvOp::Put(_, v) => Ok(next.replace(v.clone())),
+            KvOp::Delete(_) => Ok(next.take()),
+            KvOp::Cas { expected, new, .. } if next == *expected => Ok(next.replace(new.clone())),
+            KvOp::Cas { .. } => Err(next.clone()),
+        };
+        match res {
+            Some(r) if *r != out => None,
+            _ => Some(next),
+        }
+    }
+}
+
+/// A map of registers. Ops only ever touch one key, so each key is checked on its own.
+pub struct Kv;
+
+impl Model for Kv {
+    type State = BTreeMap<String, String>;
+
+    fn init() -> Self::State {
+        BTreeMap::new()
+    }
+
+    fn step(state: &Self::State, op: &KvOp, res: Option<&KvResult>) -> Option<Self::State> {
+        let key = op.key();
+        let reg = Register::step(&state.get(key).cloned(), op, res)?;
+        let mut next = state.clone();
+        match reg {
+            Some(v) => next.insert(key.to_string(), v),
+            None => next.remove(key),
+        };
+        Some(next)
+    }
+
+    fn partition(calls: &[Call]) -> Vec<Vec<Call>> {
+        let mut keys = BTreeMap::<&str, Vec<Call>>::new();
+        for c in calls {
+            keys.entry(c.op.key()).or_default().push(c.clone());
+        }
+        keys.into_values().collect()
+    }
+}
+
+/// A history that isn't linearizable, cut down to the part that shows it.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    /// Every call in the offending partition, by when it was invoked.
+    pub calls: Vec<Call>,
+    /// The longest order found that works for a prefix of the history. Indices into `calls`.
+    pub linearized: Vec<usize>,
+    /// Calls that had to go next after that, and couldn't. Indices into `calls`.
+    pub stuck: Vec<usize>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |f: &mut fmt::Formatter, i: usize| {
+            let c = &self.calls[i];
+            let ret = match &c.returned {
+                Some((t, res)) => format!("{res:?} at {t:?}"),
+                None => "nothing".to_string(),
+            };
+            writeln!(f, "    #{i} client {} {:?} at {:?} -> {ret}", c.client, c.op, c.invoked)
+        };
+        writeln!(f, "Not linearizable. This much goes in order:")?;
+        for i in &self.linearized {
+            show(f, *i)?;
+        }
+        writeln!(f, "but none of these can go next:")?;
+        for i in &self.stuck {
+            show(f, *i)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ok if `calls` is linearizable under `M`, or what's wrong with it.
+pub fn check<M: Model>(calls: &[Call]) -> Result<(), Violation> {
+    for mut part in M::partition(calls) {
+        part.sort_by_key(|c| c.invoked);
+        check_one::<M>(&part)?;
+    }
+    Ok(())
+}
+
+/// The call or return of op `op`, in a doubly linked list by time.
+#[derive(Clone, Copy)]
+struct Entry {
+    op: usize,
+    call: bool,
+    /// For a call, where its return is.
+    ret: usize,
+    prev: usize,
+    next: usize,
+}
+
+/// Unlink a call and its return. They stay pointing where they were, for `unlift`.
+fn lift(es: &mut [Entry], i: usize) {
+    for j in [i, es[i].ret] {
+        let (p, n) = (es[j].prev, es[j].next);
+        es[p].next = n;
+        if n != usize::MAX {
+            es[n].prev = p;
+        }
+    }
+}
+
+fn unlift(es: &mut [Entry], i: usize) {
+    for j in [es[i].ret, i] {
+        let (p, n) = (es[j].prev, es[j].next);
+        es[p].next = j;
+        if n != usize::MAX {
+            es[n].prev = j;
+        }
+    }
+}
+
+fn check_one<M: Model>(calls: &[Call]) -> Result<(), Violation> {
+    let n = calls.len();
+    // (time, returns after calls at the same time, op). Same time counts as overlapping, to give the benefit of the doubt.
+    let mut times = vec![];
+    for (i, c) in calls.iter().enumerate() {
+        let ret = match &c.returned {
+            Some((t, Ok(_))) => *t,
+            _ => Duration::MAX,
+        };
+        times.push((c.invoked, false, i));
+        times.push((ret, true, i));
+    }
+    times.sort();
+
+    // Entry 0 is the head. The rest in time order.
+    let mut es = vec![Entry {
+        op: usize::MAX,
+        call: false,
+        ret: 0,
+        prev: usize::MAX,
+        next: 1,
+    }];
+    let mut ret_at = vec![0; n];
+    for (k, (_, is_ret, op)) in times.iter().enumerate() {
+        let j = k + 1;
+        es.push(Entry {
+            op: *op,
+            call: !is_ret,
+            ret: 0,
+            prev: j - 1,
+            next: if j == 2 * n { usize::MAX } else { j + 1 },
+        });
+        if *is_ret {
+            ret_at[*op] = j;
+        }
+    }
+    if n == 0 {
+        es[0].next = usize::MAX;
+    }
+    for e in es.iter_mut().skip(1).filter(|e| e.call) {
+        e.ret = ret_at[e.op];
+    }
+
+    let res = |i: usize| match &calls[i].returned {
+        Some((_, Ok(r))) => Some(r),
+        _ => None,
+    };
+
+    let words = n.div_ceil(64);
+    let mut linearized = vec![0u64; words];
+    let mut cache = HashSet::<(Vec<u64>, M::State)>::new();
+    // Calls linearized so far, and the state before each.
+    let mut stack = Vec::<(usize, M::State)>::new();
+    let mut state = M::init();
+    let mut best = vec![];
+    let mut entry = es[0].next;
+
+    while es[0].next != usize::MAX {
+        let e = es[entry];
+        if e.call {
+            if let Some(next) = M::step(&state, &calls[e.op].op, res(e.op)) {
+                let mut l = linearized.clone();
+                l[e.op / 64] |= 1 << (e.op % 64);
+                if cache.insert((l.clone(), next.clone())) {
+                    stack.push((entry, state));
+                    state = next;
+                    linearized = l;
+                    lift(&mut es, entry);
+                    entry = es[0].next;
+                    continue;
+                }
+            }
+            entry = e.next;
+        } else {
+            // Got to a return with its call not placed yet. Something placed before has to move.
+            if stack.len() >= best.len() {
+                best = stack.iter().map(|(i, _)| es[*i].op).collect();
+            }
+            let Some((i, s)) = stack.pop() else {
+                return Err(violation(calls, best));
+            };
+            state = s;
+            let op = es[i].op;
+            linearized[op / 64] &= !(1 << (op % 64));
+            unlift(&mut es, i);
+            entry = es[i].next;
+        }
+    }
+    Ok(())
+}
+
+fn violation(calls: &[Call], linearized: Vec<usize>) -> Violation {
+    let placed = linearized.iter().copied().collect::<HashSet<_>>();
+    // Whatever was still to go and had already been called by the time the first of them returned.
+    let rest = (0..calls.len()).filter(|i| !placed.contains(i)).collect::<Vec<_>>();
+    let first_ret = rest
+        .iter()
+        .filter_map(|i| calls[*i].returned.as_ref().map(|(t, _)| *t))
+        .min()
+        .unwrap_or(Duration::MAX);
+    let stuck = rest.into_iter().filter(|i| calls[*i].invoked <= first_ret).collect();
+    Violation {
+        calls: calls.to_vec(),
+        linearized,
+        stuck,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientError;
+
+    fn call(client: usize, op: KvOp, from: u64, to: Option<u64>, res: KvResult) -> Call {
+        Call {
+            client,
+            op,
+            invoked: Duration::from_millis(from),
+            returned: to.map(|t| (Duration::from_millis(t), Ok(res))),
+        }
+    }
+
+    fn put(k: &str, v: &str) -> KvOp {
+        KvOp::Put(k.into(), v.into())
+    }
+
+    fn get(k: &str) -> KvOp {
+        KvOp::Get(k.into())
+    }
+
+    #[test]
+    fn overlapping_ops_can_go_either_way() {
+        let calls = vec![
+            call(0, put("x", "1"), 0, Some(10), Ok(None)),
+            call(1, get("x"), 5, Some(15), Ok(None)),
+            call(2, get("x"), 6, Some(16), Ok(Some("1".into()))),
+        ];
+        assert!(check::<Kv>(&calls).is_ok());
+        assert!(check::<Register>(&calls).is_ok());
+    }
+
+    #[test]
+    fn stale_read_is_caught() {
+        let calls = vec![
+            call(0, put("x", "1"), 0, Some(10), Ok(None)),
+            call(1, put("y", "1"), 0, Some(10), Ok(None)),
+            // Starts after the put came back, and doesn't see it.
+            call(1, get("x"), 20, Some(30), Ok(None)),
+        ];
+        let v = check::<Kv>(&calls).unwrap_err();
+        assert_eq!(v.calls.len(), 2, "only key x should be reported");
+        assert_eq!(v.linearized, vec![0]);
+        assert_eq!(v.stuck, vec![1]);
+        // All one register, so y's put is in it too.
+        assert!(check::<Register>(&calls).is_err());
+    }
+
+    #[test]
+    fn timed_out_ops_may_or_may_not_happen() {
+        let mut lost = call(0, put("x", "1"), 0, None, Ok(None));
+        lost.returned = Some((Duration::from_millis(50), Err(ClientError::Timeout)));
+        let saw = call(1, get("x"), 60, Some(70), Ok(Some("1".into())));
+        let missed = call(1, get("x"), 60, Some(70), Ok(None));
+        assert!(check::<Kv>(&[lost.clone(), saw.clone()]).is_ok());
+        assert!(check::<Kv>(&[lost.clone(), missed.clone()]).is_ok());
+        // But not first one and then the other.
+        let mut later = missed;
+        later.invoked = Duration::from_millis(80);
+        later.returned = Some((Duration::from_millis(90), Ok(Ok(None))));
+        assert!(check::<Kv>(&[lost, saw, later]).is_err());
+    }
+}
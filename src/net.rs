@@ -0,0 +1,127 @@
+//! What a node needs from the outside world: somewhere to send datagrams, timers, and the time.
+//!
+//! On a real cluster that's message-io's `NodeHandler`, with UDP sockets and the wall clock.
+//! Under `sim` it's a simulated network and a virtual clock, the whole cluster in one thread, so a run replays from its seed.
+//! Nodes hold a `Box<dyn Io<T>>`, `T` being whatever their timers carry, and never touch message-io or `Instant::now` directly.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{mpsc, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use message_io::{
+    events,
+    network::{Endpoint, Transport},
+    node::NodeHandler,
+};
+
+/// Sending. Fire and forget, like UDP: nothing says whether it got there.
+pub trait Network: Send {
+    fn send(&self, to: Endpoint, buf: &[u8]);
+    /// `send`, but only after `after`. For holding messages back on purpose, see `faults`.
+    fn send_later(&self, to: Endpoint, buf: &[u8], after: Duration);
+    /// An endpoint to send to `addr` with.
+    fn connect(&self, addr: SocketAddr) -> Endpoint;
+}
+
+/// Time, and events for later. `T` is what the node's timers carry.
+pub trait Clock<T>: Send {
+    fn now(&self) -> Instant;
+    /// `t` comes back to the node's event loop after `after`.
+    fn timer(&self, t: T, after: Duration) -> TimerId;
+    fn cancel(&self, id: TimerId);
+    /// `t` comes back to the event loop as soon as it can.
+    fn signal(&self, t: T);
+    /// Uniform in [0, 1). Randomised timeouts come from here, so a simulated run can be replayed.
+    fn random(&self) -> f64;
+}
+
+/// Everything a node needs.
+pub trait Io<T>: Network + Clock<T> {}
+
+impl<T, X: Network + Clock<T>> Io<T> for X {}
+
+/// For cancelling a timer. Only means anything to the `Clock` that handed it out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimerId {
+    Real(events::TimerId),
+    Sim(u64),
+}
+
+impl<T: Send + 'static> Network for NodeHandler<T> {
+    fn send(&self, to: Endpoint, buf: &[u8]) {
+        self.network().send(to, buf);
+    }
+
+    fn send_later(&self, to: Endpoint, buf: &[u8], after: Duration) {
+        let (handler, buf) = (self.clone(), buf.to_vec());
+        later(after, Box::new(move || {
+            handler.network().send(to, &buf);
+        }));
+    }
+
+    /// Lazy, like every UDP connect in here. Anything sent before its `Connected` event may be dropped.
+    fn connect(&self, addr: SocketAddr) -> Endpoint {
+        self.network().connect(Transport::Udp, addr).unwrap().0
+    }
+}
+
+impl<T: Send + 'static> Clock<T> for NodeHandler<T> {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn timer(&self, t: T, after: Duration) -> TimerId {
+        TimerId::Real(self.signals().send_with_timer(t, after))
+    }
+
+    fn cancel(&self, id: TimerId) {
+        if let TimerId::Real(id) = id {
+            self.signals().cancel_timer(id);
+        }
+    }
+
+    fn signal(&self, t: T) {
+        self.signals().send(t);
+    }
+
+    fn random(&self) -> f64 {
+        rand::random()
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Run `f` after `after`, on one thread shared by everything in the process.
+fn later(after: Duration, f: Job) {
+    static TX: OnceLock<mpsc::Sender<(Instant, Job)>> = OnceLock::new();
+    let tx = TX.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<(Instant, Job)>();
+        thread::spawn(move || {
+            // By when, then by order of arrival.
+            let mut jobs = BTreeMap::<(Instant, usize), Job>::new();
+            let mut n = 0;
+            loop {
+                let got = match jobs.first_key_value() {
+                    Some(((at, _), _)) => rx.recv_timeout(at.saturating_duration_since(Instant::now())).ok(),
+                    None => rx.recv().ok(),
+                };
+                if let Some((at, f)) = got {
+                    n += 1;
+                    jobs.insert((at, n), f);
+                }
+                while let Some(e) = jobs.first_entry() {
+                    if e.key().0 > Instant::now() {
+                        break;
+                    }
+                    (e.remove())();
+                }
+            }
+        });
+        tx
+    });
+    tx.send((Instant::now() + after, f)).unwrap();
+}
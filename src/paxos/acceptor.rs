@@ -1,18 +1,37 @@
 #![allow(dead_code)]
 
-use itertools::Itertools;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use hashbrown::HashMap;
 use message_io::{
-    network::NetEvent,
+    network::{Endpoint, NetEvent},
     node::{NodeHandler, NodeListener},
 };
 use serde_json::{from_slice, to_vec};
 
-use crate::paxos::{Ballot, Message, Proposal};
+use crate::{
+    config::{ClusterConfig, Role},
+    faults::{self, Faults},
+    net::Io,
+    paxos::{Ballot, Raw},
+};
+
+use super::storage::Storage;
+
+// Acceptors never look inside an op or a result, so they're carried along as plain JSON.
+type Message = super::Message<Raw, Raw>;
+type Proposal = super::Proposal<Raw>;
+
+/// Don't bother compacting the write-ahead file before it has this many records.
+const COMPACT_AFTER: usize = 1024;
 
 // type AcceptList = Arc<Mutex<Vec<Proposal>>>;
 
 /// Acceptor struct.
-struct Acceptor {
+pub struct Acceptor {
     /// Just a lil number. Unique among all acceptors.
     pub id: usize,
     // pub ballot: Arc<Mutex<Ballot>>,
@@ -21,40 +40,81 @@ struct Acceptor {
 
     /// All the stuff so far.
     pub accepted: Vec<Proposal>,
+    /// Everything below this slot is in enough replicas' checkpoints. Nothing is kept, or accepted, below it.
+    floor: usize,
 
     /// This is us.
     // pub sock: UdpSocket,
     // pub listener: NodeListener<()>,
-    pub handler: NodeHandler<()>,
+    io: Box<dyn Io<()>>,
     buf: Vec<u8>,
+
+    /// Promises and accepts go here before we reply.
+    storage: Box<dyn Storage>,
+    /// Compact once the write-ahead file has this many records.
+    compact_at: usize,
+
+    /// Leader holding a lease from us, and till when. No other leader gets a promise before then.
+    lease: Option<(usize, Instant)>,
+    /// How long a grant lasts. Zero for no leases.
+    lease_duration: Duration,
 }
 
 impl Acceptor {
-    pub fn new(id: usize, handler: NodeHandler<()>) -> Acceptor {
-        Acceptor {
+    pub fn new(id: usize, io: Box<dyn Io<()>>, mut storage: Box<dyn Storage>, lease_duration: Duration) -> Acceptor {
+        // Whatever we promised before the crash still stands.
+        let (ballot, floor, accepted) = storage.load();
+        let mut out = Acceptor {
             id,
-            ballot: Ballot::new(0, 0),
-            accepted: vec![],
+            ballot,
+            accepted,
+            floor,
             // listener,
-            handler,
+            io,
             buf: vec![],
+            storage,
+            compact_at: COMPACT_AFTER,
+            lease: None,
+            lease_duration,
+        };
+        out.compact();
+        out
+    }
+
+    /// Highest-ballot pvalue for each slot from `start` on. Nothing else matters to a scout.
+    fn get_latest_accepts(&self, start: usize) -> Vec<Proposal> {
+        let mut latest = HashMap::<usize, &Proposal>::new();
+        for p in self.accepted.iter().filter(|p| p.slot >= start) {
+            let e = latest.entry(p.slot).or_insert(p);
+            if p.ballot > e.ballot {
+                *e = p;
+            }
         }
+        let mut out = latest.into_values().cloned().collect::<Vec<_>>();
+        // Same answer every time, whatever order the map comes out in.
+        out.sort_by_key(|p| p.slot);
+        out
     }
 
-    fn get_latest_accepts(&self) -> Vec<Proposal> {
-        self.accepted
-            .iter()
-            .max_set()
-            .into_iter()
-            .cloned()
-            .collect()
+    /// Throw away superseded and trimmed pvalues, in memory and on disk, once the file gets big.
+    fn compact(&mut self) {
+        if self.storage.records() < self.compact_at {
+            return;
+        }
+        self.accepted = self.get_latest_accepts(self.floor);
+        self.storage.compact(self.ballot, self.floor, &self.accepted);
+        self.compact_at = COMPACT_AFTER.max(2 * self.storage.records());
     }
 
-    /// Promise
-    fn receive_p1(&mut self, ballot: Ballot) -> Message {
+    /// Promise. Only pvalues from slot `start` on go back, the scout knows everything before is checkpointed.
+    fn receive_p1(&mut self, ballot: Ballot, start: usize) -> Message {
+        // Someone else has a lease off us. Their ballot stands till it runs out.
+        let leased = matches!(self.lease, Some((lid, until)) if lid != ballot.leader_id && self.io.now() < until);
         // Just do it.
-        if ballot > self.ballot {
+        if ballot > self.ballot && !leased {
             self.ballot = ballot;
+            // Durable before the promise goes out.
+            self.storage.promise(ballot);
         }
 
         // Send that damnation message.
@@ -62,44 +122,96 @@ impl Acceptor {
             ballot.leader_id,
             self.id,
             self.ballot,
-            self.get_latest_accepts(),
+            self.get_latest_accepts(start),
         )
     }
 
-    /// Accept
-    fn receive_p2(&mut self, leader_id: usize, proposal: Proposal) -> Message {
+    /// Accept. Nothing below the floor: whatever was decided there is gone from here, and no scout would hear of it.
+    fn receive_p2(&mut self, leader_id: usize, proposal: Proposal) -> Option<Message> {
+        if proposal.slot < self.floor {
+            return None;
+        }
         if proposal.ballot == self.ballot {
+            // Durable before the accept goes out.
+            self.storage.accept(&proposal);
             self.accepted.push(proposal.clone());
+            self.compact();
         }
-        Message::Phase2b(leader_id, self.id, proposal.ballot)
+        // Our ballot, not theirs. That's how a commander finds out it's been preempted.
+        // Slot and their ballot say which commander it's for.
+        Some(Message::Phase2b(leader_id, self.id, self.ballot, proposal.slot, proposal.ballot))
+    }
+
+    /// A majority of replicas have checkpointed everything below `floor`. Durable before anything's dropped.
+    fn receive_trim(&mut self, floor: usize) {
+        if floor <= self.floor {
+            return;
+        }
+        self.storage.trim(floor);
+        self.floor = floor;
+        self.accepted.retain(|p| p.slot >= floor);
+    }
+
+    /// Lease. Only for the leader we've promised. Replies with our ballot either way, like a 2b.
+    fn receive_lease(&mut self, ballot: Ballot, round: usize) -> Message {
+        if ballot == self.ballot && !self.lease_duration.is_zero() {
+            self.lease = Some((ballot.leader_id, self.io.now() + self.lease_duration));
+        }
+        Message::LeaseGrant(self.id, self.ballot, round)
     }
 
     /// Mux
-    fn handle(&mut self, req: Message) -> Message {
-        dbg!(&req);
+    fn handle(&mut self, req: Message) -> Option<Message> {
         match req {
-            Message::Phase1a(_num, ballot) => self.receive_p1(ballot),
+            Message::Phase1a(_lid, ballot, start) => Some(self.receive_p1(ballot, start)),
             Message::Phase2a(lid, prop) => self.receive_p2(lid, prop),
-            _ => unreachable!(),
+            Message::LeaseRequest(_lid, ballot, round) => Some(self.receive_lease(ballot, round)),
+            Message::Trim(floor) => {
+                self.receive_trim(floor);
+                None
+            }
+            msg => {
+                println!("Acceptor {} got a message it has no use for: {msg:?}", self.id);
+                None
+            }
         }
     }
+
+    /// Answers go straight back to whoever sent it.
+    pub fn on_message(&mut self, endpoint: Endpoint, buf: &[u8]) {
+        let res = if let Ok(req) = from_slice(buf) {
+            match self.handle(req) {
+                Some(res) => to_vec(&res).unwrap(),
+                None => return,
+            }
+        } else {
+            "Invalid message.".as_bytes().to_vec()
+        };
+        self.io.send(endpoint, &res);
+    }
 }
 
 /// This is the main loop for the acceptor.
 /// Acceptors are pretty dumb, so there's not much going on here.
-pub fn listen(id: usize, listener: NodeListener<()>, handler: NodeHandler<()>) {
-    let mut q = Acceptor::new(id, handler);
+///
+/// State is reloaded from `storage` first, so a restarted acceptor keeps its promises.
+pub fn listen(
+    id: usize,
+    cfg: &ClusterConfig,
+    listener: NodeListener<()>,
+    handler: NodeHandler<()>,
+    storage: Box<dyn Storage>,
+    faults: Option<&Faults>,
+) {
+    let lease = cfg.lease.duration();
+    // We don't remember leases across restarts. Sit out the longest one we could have handed out before the crash.
+    thread::sleep(lease);
+    let io = faults::wrap(faults, cfg.addr(Role::Acceptor, id), Box::new(handler));
+    let mut q = Acceptor::new(id, io, storage, lease);
     println!("Inited acceptor {id}.");
 
     let _ = listener.for_each_async(move |event| match event.network() {
-        NetEvent::Message(endpoint, buf) => {
-            let res = if let Ok(req) = from_slice(&buf) {
-                to_vec(&q.handle(req)).unwrap()
-            } else {
-                "Invalid message.".as_bytes().to_vec()
-            };
-            q.handler.network().send(endpoint, &res);
-        }
+        NetEvent::Message(endpoint, buf) => q.on_message(endpoint, buf),
         NetEvent::Connected(ep, _) => {
             println!("Acceptor {id} Connected to {ep}.");
         }
@@ -111,3 +223,131 @@ pub fn listen(id: usize, listener: NodeListener<()>, handler: NodeHandler<()>) {
         } // _ => {}
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process};
+
+    use message_io::node;
+
+    use super::*;
+    use crate::{
+        paxos::{
+            storage::{FileStorage, MemStorage},
+            Command, Value,
+        },
+        sim::Sim,
+    };
+
+    /// Somewhere in the temp dir for this test's write-ahead file, gone once the test is done.
+    struct Wal(PathBuf);
+
+    impl Wal {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("acceptor-{}-{name}.wal", process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+
+        /// An acceptor on this file, as if it had just been restarted.
+        fn acceptor(&self) -> Acceptor {
+            let (handler, _) = node::split::<()>();
+            Acceptor::new(0, Box::new(handler), Box::new(FileStorage::open(&self.0)), Duration::ZERO)
+        }
+    }
+
+    impl Drop for Wal {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn p2a(ballot: Ballot, slot: usize) -> Message {
+        let op = Raw::from(format!("op {slot}"));
+        let value = Value::Command(Command { client_id: 0, op_id: slot, op });
+        Message::Phase2a(ballot.leader_id, Proposal { slot, ballot, value })
+    }
+
+    fn slots(accepts: &[Proposal]) -> Vec<usize> {
+        accepts.iter().map(|p| p.slot).collect()
+    }
+
+    #[test]
+    fn restart_keeps_promises_and_floor() {
+        let wal = Wal::new("restart");
+        let ballot = Ballot::new(1, 1);
+        let mut acc = wal.acceptor();
+        acc.handle(Message::Phase1a(1, ballot, 0));
+        for slot in 0..6 {
+            assert!(matches!(acc.handle(p2a(ballot, slot)), Some(Message::Phase2b(..))));
+        }
+        acc.handle(Message::Trim(4));
+        assert!(acc.handle(p2a(ballot, 2)).is_none());
+        drop(acc);
+
+        let mut acc = wal.acceptor();
+        assert_eq!(acc.ballot, ballot);
+        assert_eq!(slots(&acc.accepted), [4, 5]);
+        // Still nothing below the floor.
+        assert!(acc.handle(p2a(ballot, 3)).is_none());
+        // A scout that knows about the checkpoint at 5 only hears about 5 on.
+        let Some(Message::Phase1b(_, _, blt, accepts)) = acc.handle(Message::Phase1a(2, Ballot::new(2, 2), 5)) else {
+            panic!("no promise");
+        };
+        assert_eq!(blt, Ballot::new(2, 2));
+        assert_eq!(slots(&accepts), [5]);
+    }
+
+    #[test]
+    fn compaction_drops_superseded_and_trimmed() {
+        let wal = Wal::new("compact");
+        let (old, new) = (Ballot::new(1, 1), Ballot::new(2, 2));
+        let mut acc = wal.acceptor();
+        acc.handle(Message::Phase1a(1, old, 0));
+        for slot in 0..4 {
+            acc.handle(p2a(old, slot));
+        }
+        acc.handle(Message::Phase1a(2, new, 0));
+        acc.handle(p2a(new, 3));
+        acc.handle(Message::Trim(2));
+        acc.compact_at = 0;
+        acc.handle(p2a(new, 4));
+        // One promise, the floor, and the latest pvalue for slots 2, 3 and 4.
+        assert_eq!(acc.storage.records(), 5);
+        drop(acc);
+
+        let acc = wal.acceptor();
+        assert_eq!(acc.ballot, new);
+        assert_eq!(acc.floor, 2);
+        assert_eq!(slots(&acc.accepted), [2, 3, 4]);
+        assert_eq!(acc.accepted[1].ballot, new);
+    }
+
+    #[test]
+    fn lease_holds_off_other_ballots() {
+        let mut sim = Sim::new(1);
+        let lease = Duration::from_millis(500);
+        let io = sim.io(ClusterConfig::load("cluster.json").addr(Role::Acceptor, 0));
+        let mut acc = Acceptor::new(0, io, Box::new(MemStorage::new()), lease);
+        let (first, second) = (Ballot::new(1, 1), Ballot::new(2, 2));
+        acc.handle(Message::Phase1a(1, first, 0));
+        let granted = acc.handle(Message::LeaseRequest(1, first, 1));
+        assert!(matches!(granted, Some(Message::LeaseGrant(0, blt, 1)) if blt == first));
+
+        // A higher ballot gets turned away while it's running. The 1b says who it lost to.
+        sim.run_for(lease / 2);
+        let Some(Message::Phase1b(_, _, blt, _)) = acc.handle(Message::Phase1a(2, second, 0)) else {
+            panic!("no 1b");
+        };
+        assert_eq!(blt, first);
+
+        // Then it runs out, and the old leader can't get it back.
+        sim.run_for(lease);
+        let Some(Message::Phase1b(_, _, blt, _)) = acc.handle(Message::Phase1a(2, second, 0)) else {
+            panic!("no 1b");
+        };
+        assert_eq!(blt, second);
+        acc.handle(Message::LeaseRequest(1, first, 2));
+        assert!(matches!(acc.lease, Some((1, until)) if until <= acc.io.now()));
+    }
+}
@@ -0,0 +1,76 @@
+//! Client for a Paxos cluster.
+//!
+//! Any replica will take a request, so every try goes to `fanout` of them (all, by default), as in PMMC.
+//! Each try after the first starts from the next replica along, in case the usual ones are down.
+
+use std::{marker::PhantomData, net::SocketAddr};
+
+use serde_json::{from_slice, to_vec};
+
+use crate::{
+    client::{self, ClientError, Options, Outcome, Protocol},
+    config::{ClusterConfig, Role},
+    kv::{KvOp, KvResult},
+    Payload,
+};
+
+use super::{Command, Message};
+
+/// `O` and `R` are the replicated state machine's ops and outputs.
+pub struct Paxos<O = KvOp, R = KvResult> {
+    client_id: usize,
+    replicas: Vec<SocketAddr>,
+    fanout: usize,
+    _payload: PhantomData<fn() -> (O, R)>,
+}
+
+impl<O: Payload, R: Payload> Protocol for Paxos<O, R> {
+    type Op = O;
+    type Output = R;
+
+    fn request(&self, op_id: usize, op: &O) -> Vec<u8> {
+        to_vec(&Message::<O, R>::Request(Command {
+            client_id: self.client_id,
+            op_id,
+            op: op.clone(),
+        }))
+        .unwrap()
+    }
+
+    fn response(&self, buf: &[u8]) -> Option<(usize, Outcome<R>)> {
+        match from_slice::<Message<O, R>>(buf) {
+            Ok(Message::Response(op_id, res)) => Some((op_id, Ok(res))),
+            Ok(Message::Expired(op_id)) => Some((op_id, Err(ClientError::Expired))),
+            _ => None,
+        }
+    }
+
+    fn targets(&mut self, attempt: usize) -> Vec<SocketAddr> {
+        let n = self.replicas.len();
+        (0..self.fanout.min(n))
+            .map(|i| self.replicas[(attempt + i) % n])
+            .collect()
+    }
+}
+
+impl<O, R> Paxos<O, R> {
+    /// Each try goes to `fanout` of the replicas in `cfg`.
+    pub fn new(cfg: &ClusterConfig, client_id: usize, fanout: usize) -> Self {
+        Self {
+            client_id,
+            replicas: cfg.nodes(Role::Replica).map(|n| n.addr).collect(),
+            fanout,
+            _payload: PhantomData,
+        }
+    }
+}
+
+pub type Client = client::Client<Paxos>;
+
+impl Client {
+    /// Client `client_id`, listening on its address from the config.
+    pub fn new(cfg: &ClusterConfig, client_id: usize, opts: Options) -> Self {
+        let proto = Paxos::new(cfg, client_id, opts.fanout);
+        Self::start(client_id, cfg.client_addr(client_id), proto, opts)
+    }
+}
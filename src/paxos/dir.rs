@@ -1,134 +1,124 @@
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    thread::{self, JoinHandle},
+};
 
+use hashbrown::HashMap;
 use message_io::{
     network::{Endpoint, Transport},
     node::{self, NodeHandler, NodeListener},
 };
 
-use crate::LOOPBACK;
-
-use super::{leader::Agent, Ballot};
-
-pub const LEADER_PORT: u16 = 4000;
-pub const SCOUT_PORT: u16 = 4500;
-pub const COMMANDER_PORT: u16 = 5000;
-pub const REPLICA_PORT: u16 = 6000;
-pub const ACCEPTOR_PORT: u16 = 8000;
-pub const CLIENT_PORT: u16 = 9000;
-
-pub const LEADER_COUNT: u8 = 1;
-pub const REPLICA_COUNT: u8 = 3;
-pub const ACCEPTOR_COUNT: u8 = 3;
-
-pub fn scout_init(acceptors: &Vec<Endpoint>) -> (NodeHandler<Ballot>, NodeListener<Ballot>) {
-    let (scout_h, scout_l) = node::split();
-    for a in acceptors.iter() {
-        scout_h.network().connect(Transport::Udp, a.addr()).unwrap();
-    }
-    (scout_h, scout_l)
-}
+use crate::{
+    config::{ClusterConfig, Role},
+    faults::Faults,
+    invariants::Invariants,
+    net::Network,
+    StateMachine,
+};
 
-pub fn commander_init(acceptors: &Vec<Endpoint>) -> (NodeHandler<()>, NodeListener<()>) {
-    let (commander_h, commander_l) = node::split();
-    for a in acceptors.iter() {
-        commander_h
-            .network()
-            .connect(Transport::Udp, a.addr())
-            .unwrap();
-    }
-    (commander_h, commander_l)
-}
+use super::{acceptor, leader, leader::Agent, replica, replica::Tick, storage::MemStorage};
 
 pub fn client_init() -> (NodeHandler<()>, NodeListener<()>) {
-    let out = node::split::<()>();
-    out
+    node::split::<()>()
 }
 
-pub fn replica_init(id: usize) -> (NodeHandler<()>, NodeListener<()>) {
-    let out = node::split::<()>();
+pub fn replica_init(cfg: &ClusterConfig, id: usize) -> (NodeHandler<Tick>, NodeListener<Tick>) {
+    let out = node::split();
     out.0
         .network()
-        .listen(
-            Transport::Udp,
-            SocketAddr::from((LOOPBACK, REPLICA_PORT + id as u16)),
-        )
+        .listen(Transport::Udp, cfg.addr(Role::Replica, id))
         .unwrap();
     out
 }
 
-pub fn leader_init(id: usize) -> (NodeHandler<Agent>, NodeListener<Agent>) {
+pub fn leader_init(cfg: &ClusterConfig, id: usize) -> (NodeHandler<Agent>, NodeListener<Agent>) {
     let out = node::split();
     out.0
         .network()
-        .listen(
-            Transport::Udp,
-            SocketAddr::from((LOOPBACK, LEADER_PORT + id as u16)),
-        )
+        .listen(Transport::Udp, cfg.addr(Role::Leader, id))
         .unwrap();
     out
 }
 
-pub fn acceptor_init(id: usize) -> (NodeHandler<()>, NodeListener<()>) {
+pub fn acceptor_init(cfg: &ClusterConfig, id: usize) -> (NodeHandler<()>, NodeListener<()>) {
     let out = node::split::<()>();
     out.0
         .network()
-        .listen(
-            Transport::Udp,
-            SocketAddr::from((LOOPBACK, ACCEPTOR_PORT + id as u16)),
-        )
+        .listen(Transport::Udp, cfg.addr(Role::Acceptor, id))
         .unwrap();
     out
 }
 
-pub fn get_all_leaders<Y>(handler: NodeHandler<Y>) -> Vec<Endpoint> {
-    (0..LEADER_COUNT)
-        .map(|i| {
-            // let temp = node::split::<()>();
-            let out = handler
-                .network()
-                .connect(
-                    Transport::Udp,
-                    SocketAddr::from((LOOPBACK, LEADER_PORT + i as u16)),
-                )
-                .unwrap();
-            // dbg!(&out);
-            out.0
-        })
-        .collect()
+/// All the acceptors, leaders and replicas in the config (standbys too), each in its own thread, with nothing on disk.
+/// No waiting on each other to come up: leaders resend their 1a's and replicas their proposals till somebody answers.
+/// Everything they send goes past `faults`, and the replicas report to `invariants`, if given.
+pub fn paxos_init<S>(
+    cfg: &ClusterConfig,
+    faults: Option<Faults>,
+    invariants: Option<Invariants<S::Op>>,
+) -> Vec<JoinHandle<()>>
+where
+    S: StateMachine,
+{
+    let mut out = vec![];
+    for i in cfg.ids(Role::Acceptor) {
+        let sock = acceptor_init(cfg, i);
+        let (cfg, faults) = (cfg.clone(), faults.clone());
+        out.push(thread::spawn(move || {
+            acceptor::listen(i, &cfg, sock.1, sock.0, Box::new(MemStorage::new()), faults.as_ref());
+        }));
+    }
+
+    for i in cfg.ids(Role::Leader) {
+        let sock = leader_init(cfg, i);
+        let (cfg, faults) = (cfg.clone(), faults.clone());
+        out.push(thread::spawn(move || {
+            leader::listen(i, &cfg, sock.0, sock.1, faults.as_ref());
+        }));
+    }
 
-    // todo!()
+    for i in cfg.ids(Role::Replica) {
+        let sock = replica_init(cfg, i);
+        let (cfg, faults, invariants) = (cfg.clone(), faults.clone(), invariants.clone());
+        out.push(thread::spawn(move || {
+            replica::listen::<S>(i, &cfg, sock.1, sock.0, faults.as_ref(), invariants.as_ref());
+        }));
+    }
+
+    out
 }
 
-pub fn get_all_replicas<Y>(handler: NodeHandler<Y>) -> Vec<Endpoint> {
-    (0..REPLICA_COUNT)
-        .map(|i| {
-            // SocketAddr::from(([10, 0, 0, i], REPLICA_PORT))
-            // let temp = node::split::<()>();
-            let out = handler
-                .network()
-                .connect(
-                    Transport::Udp,
-                    SocketAddr::from((LOOPBACK, REPLICA_PORT + i as u16)),
-                )
-                .unwrap();
+/// Connect to everything with the given role, bar standbys. That's the initial config.
+///
+/// Anything that can send will do for `net`: a `NodeHandler`, or a node's `Io` under `sim`.
+fn get_all(cfg: &ClusterConfig, role: Role, net: &dyn Network) -> Vec<Endpoint> {
+    cfg.nodes(role)
+        .filter(|n| !n.standby)
+        .map(|n| {
+            let out = net.connect(n.addr);
             // dbg!(&out);
-            out.0
+            out
         })
         .collect()
 }
 
-pub fn get_all_acceptors<Y>(handler: NodeHandler<Y>) -> Vec<Endpoint> {
-    (0..ACCEPTOR_COUNT)
-        .map(|i| {
-            let out = handler
-                .network()
-                .connect(
-                    Transport::Udp,
-                    SocketAddr::from((LOOPBACK, ACCEPTOR_PORT + i as u16)),
-                )
-                .unwrap();
-            // dbg!(&out);
-            out.0
-        })
-        .collect()
+pub fn get_all_leaders(cfg: &ClusterConfig, net: &dyn Network) -> Vec<Endpoint> {
+    get_all(cfg, Role::Leader, net)
+}
+
+pub fn get_all_replicas(cfg: &ClusterConfig, net: &dyn Network) -> Vec<Endpoint> {
+    get_all(cfg, Role::Replica, net)
+}
+
+pub fn get_all_acceptors(cfg: &ClusterConfig, net: &dyn Network) -> Vec<Endpoint> {
+    get_all(cfg, Role::Acceptor, net)
+}
+
+/// Connect to everything with the given role, standbys included, keyed by address.
+///
+/// For when the config can change under us. Do this up front: a UDP connect isn't ready until its `Connected` event,
+/// so connecting in the middle of handling a message drops whatever we send straight after.
+pub fn connect_all(cfg: &ClusterConfig, role: Role, net: &dyn Network) -> HashMap<SocketAddr, Endpoint> {
+    cfg.nodes(role).map(|n| (n.addr, net.connect(n.addr))).collect()
 }
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, sync::Arc, thread, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use message_io::{
     network::{Endpoint, NetEvent},
@@ -7,212 +11,148 @@ use message_io::{
 
 use serde_json::to_vec;
 
-use crate::paxos::dir::commander_init;
+use crate::{
+    config::{ClusterConfig, LeaseConfig, Role},
+    faults::{self, Faults},
+    net::{Io, Network},
+};
 
 use super::{
-    dir::{get_all_acceptors, get_all_replicas, scout_init},
-    Ballot, Message, Proposal,
+    dir::{connect_all, get_all_acceptors, get_all_replicas},
+    Ballot, Raw,
 };
 
-/// 'Return type' of a Scout or Commander thread.
-/// Sent through a channel to the main thread.
+// Leaders never look inside an op or a result, so they're carried along as plain JSON.
+type Message = super::Message<Raw, Raw>;
+type Proposal = super::Proposal<Raw>;
+type Value = super::Value<Raw>;
+
+/// What a scout or commander has to tell the leader.
+/// Sent as a signal on the leader's own event loop, same as the timers.
 #[derive(Debug, Clone)]
 pub enum Agent {
     Committed,
     Adopted(Ballot, HashMap<usize, Vec<Proposal>>),
     Preempted(Ballot),
+    /// Timer. Time to ping whoever preempted us, or take over if they've gone quiet.
+    Watch,
+    /// Timer. Time to renew the lease.
+    Renew,
+    /// Timer. Time to send the 1a's and 2a's nobody's answered again.
+    Resend,
 }
 
-impl Agent {
-    /// Call this in a separate thread
-    pub fn init_commander(
-        prop: Proposal,
-        acceptors: Arc<Vec<Endpoint>>,
-        replicas: Arc<Vec<Endpoint>>,
-        // sock: Arc<UdpSocket>,
-        handler: NodeHandler<()>,
-        listener: NodeListener<()>,
-        other_handler: NodeHandler<Agent>,
-        // agent_tx: Arc<Sender<Agent>>,
-        lid: usize,
-    ) {
-        // dbg!("Commander.");
-        let mut waitfor = (*acceptors).clone();
-        let msg = Message::Phase2a(lid, prop.clone());
-
-        for acc in acceptors.iter() {
-            // sock.send_to(&to_vec(&msg).unwrap(), acc).await.unwrap();
-            handler.network().send(*acc, &to_vec(&msg).unwrap());
-        }
+/// How often to ping the leader we're waiting on.
+const PING_INTERVAL: Duration = Duration::from_millis(50);
+/// Bounds for how long it can go without answering before we take over.
+/// Doubles every time we get preempted, comes down by `TIMEOUT_STEP` every time we get adopted (AIMD).
+const TIMEOUT_MIN: Duration = Duration::from_millis(200);
+const TIMEOUT_MAX: Duration = Duration::from_secs(5);
+const TIMEOUT_STEP: Duration = Duration::from_millis(20);
+/// How long a scout or commander waits to hear back before sending again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Phase 1 for one ballot. Not a thread, just the state: acceptors answer on the leader's socket,
+/// and the leader hands their 1b's over. There's only ever one going, for the leader's current ballot.
+pub struct Scout {
+    ballot: Ballot,
+    /// First slot we want pvalues for.
+    start: usize,
+    /// Acceptors yet to promise.
+    waitfor: HashSet<SocketAddr>,
+    /// Size of the config. A majority of it has to promise.
+    total: usize,
+    /// Everything they've accepted, by slot.
+    pvals: HashMap<usize, Vec<Proposal>>,
+    /// Only tell the leader once, or it starts commanders for everything all over again.
+    adopted: bool,
+}
 
-        // loop {
-        listener.for_each(move |event| {
-            match event.network() {
-                NetEvent::Message(endpoint, message) => {
-                    let msg: Message = serde_json::from_slice(&message).unwrap();
-                    // dbg!(&msg);
-                    match msg {
-                        Message::Phase2b(_back_lid, _acc_id, blt) => {
-                            if blt == prop.ballot {
-                                // Using retain coz remove wants the index.
-                                waitfor.retain(|x| *x != endpoint);
-
-                                if waitfor.len() < acceptors.len() / 2 {
-                                    // Majority
-                                    let rep_msg =
-                                        Message::Decision(prop.slot, prop.command.clone());
-
-                                    for rep in replicas.iter() {
-                                        // sock.send_to(&to_vec(&rep_msg).unwrap(), rep).await.unwrap();
-                                        other_handler
-                                            .network()
-                                            .send(*rep, &to_vec(&rep_msg).unwrap());
-                                    }
-                                    // agent_tx.send(Self::Committed).unwrap();
-                                    other_handler.signals().send(Self::Committed);
-                                    return;
-                                }
-                            } else {
-                                // agent_tx.send(Self::Preempted(blt)).unwrap();
-                                other_handler.signals().send(Self::Preempted(blt));
-                                return;
-                            }
-                        }
-                        _ => {}
-                    }
-                }
-                _ => {}
-            }
-        });
-        /* let mut buf = vec![0; 1024];
-        let (len, addr) = sock.recv_from(&mut buf).await.unwrap();
-        let msg: Message = serde_json::from_slice(&buf[..len]).unwrap();
-        match msg {
-            Message::Phase2b(_back_lid, _acc_id, blt) => {
-                if blt == prop.ballot {
-                    // Using retain coz remove wants the index.
-                    waitfor.retain(|x| *x != addr);
+impl Scout {
+    /// Send out the 1a's. Acceptors only need to say what they've got from slot `start` on.
+    pub fn start(lid: usize, ballot: Ballot, start: usize, acceptors: &[Endpoint], net: &dyn Network) -> Self {
+        let out = Self {
+            ballot,
+            start,
+            waitfor: acceptors.iter().map(|a| a.addr()).collect(),
+            total: acceptors.len(),
+            pvals: HashMap::new(),
+            adopted: false,
+        };
+        out.resend(lid, acceptors, net);
+        out
+    }
 
-                    if waitfor.len() < acceptors.len() / 2 {
-                        // Majority
-                        let rep_msg = Message::Decision(prop.slot, prop.command.clone());
+    /// The 1a again, to whoever hasn't promised yet. Either way round could have been lost.
+    pub fn resend(&self, lid: usize, acceptors: &[Endpoint], net: &dyn Network) {
+        let msg = to_vec(&Message::Phase1a(lid, self.ballot, self.start)).unwrap();
+        for acc in acceptors.iter().filter(|a| self.waitfor.contains(&a.addr())) {
+            net.send(*acc, &msg);
+        }
+    }
 
-                        for rep in replicas.iter() {
-                            sock.send_to(&to_vec(&rep_msg).unwrap(), rep).await.unwrap();
-                        }
-                        agent_tx.send(Self::Committed).unwrap();
-                        return;
-                    }
-                } else {
-                    agent_tx.send(Self::Preempted(blt)).unwrap();
-                    return;
-                }
-            }
-            _ => {}
+    /// A 1b from `from`. Returns what the leader should hear about, if anything.
+    pub fn receive(&mut self, from: SocketAddr, blt: Ballot, accepts: Vec<Proposal>) -> Option<Agent> {
+        if blt != self.ballot {
+            return Some(Agent::Preempted(blt));
+        }
+        if !self.waitfor.remove(&from) {
+            // Not in our config, or heard from already.
+            return None;
+        }
+        for acc in accepts {
+            self.pvals.entry(acc.slot).or_default().push(acc);
         }
-         */
-        // }
+        if 2 * self.waitfor.len() < self.total && !self.adopted {
+            // Majority
+            self.adopted = true;
+            return Some(Agent::Adopted(blt, self.pvals.clone()));
+        }
+        None
+    }
+}
+
+/// Phase 2 for one proposal. Like the scout, state on the leader's event loop, one per slot.
+pub struct Commander {
+    prop: Proposal,
+    /// Acceptors yet to accept.
+    waitfor: HashSet<SocketAddr>,
+    /// Size of the config. A majority of it has to accept.
+    total: usize,
+}
+
+impl Commander {
+    /// Send out the 2a's.
+    pub fn start(lid: usize, prop: Proposal, acceptors: &[Endpoint], net: &dyn Network) -> Self {
+        let out = Self {
+            prop,
+            waitfor: acceptors.iter().map(|a| a.addr()).collect(),
+            total: acceptors.len(),
+        };
+        out.resend(lid, acceptors, net);
+        out
     }
 
-    pub fn init_scout(
-        lid: usize,
-        mut ballot: Ballot,
-        acceptors: Arc<Vec<Endpoint>>,
-        listener: NodeListener<Ballot>,
-        handler: NodeHandler<Ballot>,
-        other_handler: NodeHandler<Agent>, // communicate with leader.
-    ) {
-        let mut waitfor = (*acceptors).clone();
-        // loop {
-        // let mut ballot;
-        let msg = Message::Phase1a(lid, ballot);
-
-        for acc in acceptors.iter() {
-            // sock.send_to(&to_vec(&msg).unwrap(), acc).await.unwrap();
-            handler.network().send(*acc, &to_vec(&msg).unwrap());
+    /// The 2a again, to whoever hasn't accepted yet.
+    pub fn resend(&self, lid: usize, acceptors: &[Endpoint], net: &dyn Network) {
+        let msg = to_vec(&Message::Phase2a(lid, self.prop.clone())).unwrap();
+        for acc in acceptors.iter().filter(|a| self.waitfor.contains(&a.addr())) {
+            net.send(*acc, &msg);
         }
+    }
 
-        let mut pvals = HashMap::<usize, Vec<Proposal>>::new();
-
-        /* loop {
-            let mut buf = vec![0; 1024];
-            let (len, addr) = sock.recv_from(&mut buf).await.unwrap();
-            let msg: Message = serde_json::from_slice(&buf[..len]).unwrap();
-            match msg {
-                Message::Phase1b(_lid, _acc_id, blt, accepts) => {
-                    if blt == ballot {
-                        waitfor.retain(|x| *x != addr);
-                        accepts.iter().for_each(|acc| {
-                            if let Some(p) = pvals.get_mut(&acc.slot) {
-                                p.push(acc.clone());
-                            } else {
-                                pvals.insert(acc.slot, vec![acc.clone()]);
-                            }
-                        });
-
-                        if waitfor.len() < acceptors.len() / 2 {
-                            // Majority
-                            agent_tx.send(Self::Adopted(blt, pvals)).unwrap();
-                            break;
-                        }
-                    } else {
-                        agent_tx.send(Self::Preempted(blt)).unwrap();
-                    }
-                }
-                _ => {}
-            }
-        } */
-        // }
-
-        let _ = listener.for_each_async(move |event| {
-            match event {
-                NodeEvent::Network(u) => match u {
-                    NetEvent::Message(endpoint, message) => {
-                        let msg: Message = serde_json::from_slice(&message).unwrap();
-                        // dbg!(&msg);
-                        match msg {
-                            Message::Phase1b(_lid, _acc_id, blt, accepts) => {
-                                if blt == ballot {
-                                    // dbg!(&endpoint);
-                                    waitfor.retain(|x| x.addr() != endpoint.addr());
-                                    accepts.iter().for_each(|acc| {
-                                        if let Some(p) = pvals.get_mut(&acc.slot) {
-                                            p.push(acc.clone());
-                                        } else {
-                                            pvals.insert(acc.slot, vec![acc.clone()]);
-                                        }
-                                    });
-
-                                    // dbg!(&waitfor, &pvals, &acceptors);
-
-                                    if (waitfor.len() as f64) < acceptors.len() as f64 / 2.0 {
-                                        // Majority
-                                        // dbg!("Majority");
-                                        other_handler
-                                            .signals()
-                                            .send(Self::Adopted(blt, pvals.clone()));
-                                        return;
-                                    }
-                                } else {
-                                    other_handler.signals().send(Self::Preempted(blt));
-                                    return;
-                                }
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                    _ => {}
-                },
-                NodeEvent::Signal(s) => {
-                    ballot = s;
-                    let msg = Message::Phase1a(lid, ballot);
-                    for acc in acceptors.iter() {
-                        // sock.send_to(&to_vec(&msg).unwrap(), acc).await.unwrap();
-                        handler.network().send(*acc, &to_vec(&msg).unwrap());
-                    }
-                }
-            }
-        });
+    /// A 2b from `from`, carrying the acceptor's ballot. `Committed` or `Preempted` mean this commander is done.
+    pub fn receive(&mut self, from: SocketAddr, blt: Ballot) -> Option<Agent> {
+        if blt > self.prop.ballot {
+            return Some(Agent::Preempted(blt));
+        }
+        if blt < self.prop.ballot {
+            // Missed our 1a. The others can still make a majority.
+            return None;
+        }
+        self.waitfor.remove(&from);
+        // Majority
+        (2 * self.waitfor.len() < self.total).then_some(Agent::Committed)
     }
 }
 
@@ -220,42 +160,393 @@ impl Agent {
 pub struct Leader {
     /// Just a lil number. Unique among all leaders.
     id: usize,
-    //// Set of all outstanding proposals.
+    //// Set of all outstanding proposals. Nothing below `floor`.
     proposals: HashMap<usize, Proposal>,
+    /// Slots of `proposals` we've seen decided. The replicas might not all have, so these stick around too.
+    decided: HashSet<usize>,
+    /// Whether a reconfig ever came through us. Sticks, even once the proposal's gone below the floor.
+    reconfigured: bool,
     /// State of the scout.
     active: bool,
     /// Current ballot.
     ballot: Ballot,
+    /// Leader whose ballot preempted ours. We leave it alone while it answers pings.
+    waiting_on: Option<usize>,
+    /// Last time it answered.
+    last_pong: Instant,
+    /// How long it can stay quiet before we take over.
+    timeout: Duration,
+
+    /// Acceptors won't adopt anyone else before this. Reads can skip phase 2 till then.
+    lease: Option<Instant>,
+    /// Lease rounds not yet granted by a majority: when each went out, and who's granted so far.
+    renewals: BTreeMap<usize, (Instant, HashSet<usize>)>,
+    /// Last lease round sent.
+    round: usize,
+    /// Whether the renewal timer is going.
+    renewing: bool,
+    lease_cfg: LeaseConfig,
+
+    /// Phase 1 for the current ballot. None till we've got acceptors to scout (standbys wait for `Activate`).
+    scout: Option<Scout>,
+    /// Phase 2, by slot. At most one per slot, so this doesn't grow with the number of requests.
+    commanders: HashMap<usize, Commander>,
+    /// Whether the resend timer is going.
+    resending: bool,
+
+    /// This is us.
+    io: Box<dyn Io<Agent>>,
+    /// Every acceptor we might ever be handed in an `Activate`, by address.
+    everyone: hashbrown::HashMap<SocketAddr, Endpoint>,
+    /// The acceptors of our config.
+    acceptors: Vec<Endpoint>,
+    replicas: Vec<Endpoint>,
+    /// The other leaders, for pinging whoever preempted us.
+    others: HashMap<usize, Endpoint>,
+
+    /// Latest checkpoint slot each replica has told us about.
+    checkpoints: HashMap<usize, usize>,
+    /// A majority of replicas have checkpointed everything below this. Acceptors can forget it.
+    floor: usize,
 }
 
 impl Leader {
-    pub fn new(id: usize) -> Self {
+    /// Leader `id` of the cluster in `cfg`, connected up but not scouting yet. See `start`.
+    pub fn new(id: usize, cfg: &ClusterConfig, io: Box<dyn Io<Agent>>) -> Self {
+        let others = cfg
+            .nodes(Role::Leader)
+            .filter(|n| n.id != id)
+            .map(|n| (n.id, io.connect(n.addr)))
+            .collect();
+        // Standby leaders belong to some future config, and get their acceptors with `Activate`.
+        let acceptors = match cfg.node(Role::Leader, id).standby {
+            true => vec![],
+            false => get_all_acceptors(cfg, &*io),
+        };
         Self {
             id,
             proposals: HashMap::new(),
+            decided: HashSet::new(),
+            reconfigured: false,
             active: false,
-            ballot: Ballot::new(id, 0),
+            ballot: Ballot::new(0, id),
+            waiting_on: None,
+            last_pong: io.now(),
+            timeout: TIMEOUT_MIN,
+            lease: None,
+            renewals: BTreeMap::new(),
+            round: 0,
+            renewing: false,
+            lease_cfg: cfg.lease,
+            scout: None,
+            commanders: HashMap::new(),
+            resending: false,
+            everyone: connect_all(cfg, Role::Acceptor, &*io),
+            acceptors,
+            replicas: get_all_replicas(cfg, &*io),
+            others,
+            checkpoints: HashMap::new(),
+            floor: 0,
+            io,
+        }
+    }
+
+    /// Whether our ballot's been adopted, so we're the one proposing.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Leaders in the initial config start scouting. Standby leaders sit tight until a replica sends `Activate`.
+    pub fn start(&mut self) {
+        if !self.acceptors.is_empty() {
+            self.scout();
+        }
+    }
+
+    /// Phase 1 again, with our current ballot.
+    fn scout(&mut self) {
+        self.scout = Some(Scout::start(self.id, self.ballot, self.floor, &self.acceptors, &*self.io));
+        if !self.resending {
+            self.resending = true;
+            self.io.timer(Agent::Resend, RESEND_INTERVAL);
+        }
+    }
+
+    fn leased(&self) -> bool {
+        self.active && matches!(self.lease, Some(l) if self.io.now() < l)
+    }
+
+    /// Lost the ballot, so lost the lease.
+    fn deactivate(&mut self) {
+        self.active = false;
+        self.lease = None;
+        self.renewals.clear();
+    }
+
+    /// Next slot nobody's proposed anything for, as far as we know. Everything decided so far is below it.
+    ///
+    /// None if we can't say. Before our first proposal, we may be a new config's leader with the old one still deciding.
+    /// Once a reconfig comes through us, the next config's leaders decide things our acceptors never hear about.
+    fn next_slot(&self) -> Option<usize> {
+        if self.reconfigured {
+            return None;
+        }
+        self.proposals.keys().max().map(|s| s + 1)
+    }
+
+    /// Take a proposal on. The only way into `proposals`, bar `update`.
+    fn insert(&mut self, prop: Proposal) {
+        self.reconfigured |= matches!(prop.value, Value::Reconfig(_));
+        self.proposals.insert(prop.slot, prop);
+    }
+
+    /// Replica `rid` checkpointed at `slot`. If that moves the floor, the acceptors hear about it.
+    fn checkpointed(&mut self, rid: usize, slot: usize) {
+        let cp = self.checkpoints.entry(rid).or_default();
+        *cp = slot.max(*cp);
+        let mut slots = self.checkpoints.values().copied().collect::<Vec<_>>();
+        let majority = self.replicas.len() / 2 + 1;
+        if slots.len() < majority {
+            return;
+        }
+        slots.sort_unstable_by(|a, b| b.cmp(a));
+        if slots[majority - 1] <= self.floor {
+            return;
+        }
+        self.floor = slots[majority - 1];
+        let msg = to_vec(&Message::Trim(self.floor)).unwrap();
+        for acc in self.acceptors.iter() {
+            self.io.send(*acc, &msg);
         }
+        // All settled, and every replica that's missing any of it can get a checkpoint instead.
+        let floor = self.floor;
+        self.proposals.retain(|s, _| *s >= floor);
+        self.commanders.retain(|s, _| *s >= floor);
+        self.decided.retain(|s| *s >= floor);
     }
 
     pub fn update(&mut self, pmax: HashMap<usize, Proposal>) {
-        self.proposals.retain(|s, p| match pmax.get(&s) {
-            Some(val) => val.command == p.command,
+        self.proposals.retain(|s, p| match pmax.get(s) {
+            Some(val) => val.value == p.value,
             None => true,
         });
 
-        self.proposals.extend(pmax.into_iter());
+        for (_, p) in pmax {
+            self.insert(p);
+        }
+    }
+
+    /// What our scouts and commanders have to say, and our timers.
+    pub fn on_signal(&mut self, s: Agent) {
+        let id = self.id;
+        match s {
+            Agent::Adopted(blt, pvals) => {
+                if blt != self.ballot {
+                    // Preempted since. That scout's done for.
+                    return;
+                }
+                let pmax = get_pmax(&pvals);
+                self.update(pmax);
+
+                // Everything not yet decided goes out again under the new ballot. Commanders for the old one can go.
+                self.commanders.clear();
+                for (s, p) in self.proposals.iter_mut().filter(|(s, _)| !self.decided.contains(*s)) {
+                    p.ballot = self.ballot;
+                    self.commanders.insert(*s, Commander::start(id, p.clone(), &self.acceptors, &*self.io));
+                }
+
+                self.active = true;
+                self.timeout = self.timeout.saturating_sub(TIMEOUT_STEP).max(TIMEOUT_MIN);
+                if self.lease_cfg.enabled() && !self.renewing {
+                    self.renewing = true;
+                    self.io.signal(Agent::Renew);
+                }
+            }
+            Agent::Preempted(blt) => {
+                if blt > self.ballot {
+                    self.deactivate();
+                    self.commanders.clear();
+                    self.ballot.num = blt.num + 1;
+                    if blt.leader_id == id {
+                        // Our own ballot from before a restart. Nobody to wait for.
+                        if self.scout.is_some() {
+                            self.scout();
+                        }
+                        return;
+                    }
+                    // Fighting over it. Be more patient next time round.
+                    if self.waiting_on.is_none() {
+                        self.timeout = (self.timeout * 2).min(TIMEOUT_MAX);
+                        self.io.timer(Agent::Watch, PING_INTERVAL);
+                    }
+                    // Pseudocode restarts the scout here. We hold off till the other guy looks dead (PMMC, section 3).
+                    if self.waiting_on != Some(blt.leader_id) {
+                        println!("Leader {id} preempted by {blt:?}, waiting on leader {}", blt.leader_id);
+                    }
+                    self.waiting_on = Some(blt.leader_id);
+                    self.last_pong = self.io.now();
+                } else if self.lease_cfg.enabled()
+                    && blt.leader_id != id
+                    && !self.active
+                    && self.waiting_on.is_none()
+                {
+                    // Turned away with a lower ballot than ours: somebody's lease is still running. Wait on them.
+                    println!("Leader {id} held off by leader {}'s lease", blt.leader_id);
+                    self.waiting_on = Some(blt.leader_id);
+                    self.last_pong = self.io.now();
+                    self.io.timer(Agent::Watch, PING_INTERVAL);
+                }
+            }
+            Agent::Renew => {
+                if !self.active {
+                    self.renewing = false;
+                    return;
+                }
+                self.round += 1;
+                self.renewals.insert(self.round, (self.io.now(), HashSet::new()));
+                let msg = to_vec(&Message::LeaseRequest(id, self.ballot, self.round)).unwrap();
+                for acc in self.acceptors.iter() {
+                    self.io.send(*acc, &msg);
+                }
+                // Renew well before it runs out.
+                self.io.timer(Agent::Renew, self.lease_cfg.guaranteed() / 4);
+            }
+            Agent::Watch => {
+                if let Some(other) = self.waiting_on {
+                    if self.io.now() - self.last_pong > self.timeout {
+                        println!("Leader {id} taking over from {other} with {:?}", self.ballot);
+                        self.waiting_on = None;
+                        if self.scout.is_some() {
+                            self.scout();
+                        }
+                    } else {
+                        self.io.send(self.others[&other], &to_vec(&Message::Ping(id)).unwrap());
+                        self.io.timer(Agent::Watch, PING_INTERVAL);
+                    }
+                }
+            }
+            Agent::Resend => {
+                let fresh = self.waiting_on.is_none();
+                if let Some(s) = self.scout.as_ref().filter(|s| fresh && !s.adopted && s.ballot == self.ballot) {
+                    s.resend(id, &self.acceptors, &*self.io);
+                }
+                for c in self.commanders.values() {
+                    c.resend(id, &self.acceptors, &*self.io);
+                }
+                self.io.timer(Agent::Resend, RESEND_INTERVAL);
+            }
+            Agent::Committed => {} // Commanders' business, handled where their 2b's come in.
+        }
+    }
+
+    pub fn on_message(&mut self, endpoint: Endpoint, buf: &[u8]) {
+        let id = self.id;
+        let Ok(msg) = serde_json::from_slice::<Message>(buf) else {
+            println!("Leader {id} dropped a message it couldn't read from {endpoint}");
+            return;
+        };
+        match msg {
+            // Still going, and not waiting on anyone else.
+            Message::Ping(_) if self.scout.is_some() && self.waiting_on.is_none() => {
+                self.io.send(endpoint, &to_vec(&Message::Pong(id)).unwrap());
+            }
+            Message::Pong(from) if self.waiting_on == Some(from) => {
+                self.last_pong = self.io.now();
+            }
+            Message::Phase1b(_lid, _acc_id, blt, accepts) => {
+                if let Some(a) = self.scout.as_mut().and_then(|s| s.receive(endpoint.addr(), blt, accepts)) {
+                    self.io.signal(a);
+                }
+            }
+            // Which commander it's for goes by slot and the ballot it was proposed with.
+            Message::Phase2b(_lid, _acc_id, blt, slot, pblt) => {
+                let Some(c) = self.commanders.get_mut(&slot).filter(|c| c.prop.ballot == pblt) else {
+                    return;
+                };
+                match c.receive(endpoint.addr(), blt) {
+                    Some(Agent::Committed) => {
+                        let c = self.commanders.remove(&slot).unwrap();
+                        self.decided.insert(slot);
+                        let msg = to_vec(&Message::Decision(slot, c.prop.value)).unwrap();
+                        for rep in self.replicas.iter() {
+                            self.io.send(*rep, &msg);
+                        }
+                    }
+                    Some(a) => {
+                        self.commanders.remove(&slot);
+                        self.io.signal(a);
+                    }
+                    None => {}
+                }
+            }
+            Message::LeaseGrant(acc, blt, round) if blt == self.ballot && self.active => {
+                let majority = self.acceptors.len() / 2 + 1;
+                if let Some((at, granted)) = self.renewals.get_mut(&round) {
+                    granted.insert(acc);
+                    if granted.len() >= majority {
+                        let until = *at + self.lease_cfg.guaranteed();
+                        self.lease = self.lease.max(Some(until));
+                        // Older rounds can only give an earlier expiry.
+                        self.renewals = self.renewals.split_off(&(round + 1));
+                    }
+                }
+            }
+            // Replica wants to read. Tell it how far it has to get first, if we can vouch for that.
+            Message::LeaseRead(rid) if self.active => {
+                let upto = self.leased().then(|| self.next_slot()).flatten();
+                let msg = Message::LeaseReadReply(rid, upto);
+                self.io.send(endpoint, &to_vec(&msg).unwrap());
+            }
+            Message::Propose(slot, value) => {
+                // Replicas pick their slots on their own, so they clash. First come, first served: two values
+                // under one ballot could both get chosen. The replica that lost out proposes again elsewhere.
+                // Below the floor it's been decided already, and the acceptors have forgotten what.
+                if slot < self.floor {
+                    return;
+                }
+                if let Some(p) = self.proposals.get(&slot) {
+                    // Asking again. If it's been decided, the replica must have missed the decision.
+                    if self.decided.contains(&slot) {
+                        let msg = to_vec(&Message::Decision(slot, p.value.clone())).unwrap();
+                        self.io.send(endpoint, &msg);
+                    }
+                    return;
+                }
+
+                let prop = Proposal {
+                    slot,
+                    ballot: self.ballot,
+                    value,
+                };
+                self.insert(prop.clone());
+
+                if self.active {
+                    self.commanders.insert(slot, Commander::start(id, prop, &self.acceptors, &*self.io));
+                }
+            }
+            Message::Checkpointed(rid, slot) => self.checkpointed(rid, slot),
+            // Every replica sends one of these. Only the first does anything.
+            Message::Activate(config) if self.scout.is_none() => {
+                println!("Leader {id} activated with {:?}", config.acceptors);
+                // Replicas only pass on configs they know all of. Skip anybody we don't, just in case.
+                self.acceptors = config.acceptors.iter().filter_map(|a| self.everyone.get(a).copied()).collect();
+                if !self.acceptors.is_empty() {
+                    self.scout();
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 pub fn get_pmax(pvals: &HashMap<usize, Vec<Proposal>>) -> HashMap<usize, Proposal> {
     pvals
-        .into_iter()
+        .iter()
         .map(|(slot, prop)| {
             (
                 *slot,
-                prop.into_iter()
-                    .max_by_key(|p| p.ballot.num)
+                prop.iter()
+                    .max_by_key(|p| p.ballot)
                     .unwrap()
                     .clone(),
             )
@@ -263,112 +554,144 @@ pub fn get_pmax(pvals: &HashMap<usize, Vec<Proposal>>) -> HashMap<usize, Proposa
         .collect::<HashMap<usize, Proposal>>()
 }
 
-/// TODO: Add file read for lists.
-pub fn listen(id: usize, handler: NodeHandler<Agent>, listener: NodeListener<Agent>) {
-    let acceptors = Arc::new(get_all_acceptors(handler.clone()));
-    thread::sleep(Duration::from_secs(2));
-    let replicas = Arc::new(get_all_replicas(handler.clone()));
-
-    let mut leader = Leader::new(id);
+/// This is the main loop for the leader.
+/// Scouts, commanders and timers all run on it, so a leader is just this thread and one socket.
+pub fn listen(
+    id: usize,
+    cfg: &ClusterConfig,
+    handler: NodeHandler<Agent>,
+    listener: NodeListener<Agent>,
+    faults: Option<&Faults>,
+) {
+    let io = faults::wrap(faults, cfg.addr(Role::Leader, id), Box::new(handler));
+    let mut leader = Leader::new(id, cfg, io);
+    // Acceptors that aren't up yet, or 1a's lost down a connection that isn't ready, are the resend timer's problem.
+    leader.start();
     println!("Inited leader {}", id);
 
-    let mut commanders = vec![];
-    let new_acc = acceptors.clone();
-
-    let (scout_h, scout_l) = scout_init(&acceptors);
-    let oh = handler.clone();
-    let sh = scout_h.clone();
-
-    let _scout = thread::spawn(move || {
-        Agent::init_scout(leader.id, leader.ballot, new_acc, scout_l, sh, oh);
-    }); // Sus
-
-    let _ = listener.for_each_async(move |event| {
-        match event {
-            NodeEvent::Signal(s) => {
-                // dbg!(&s);
-                match s {
-                    Agent::Adopted(_blt, pvals) => {
-                        // leader.ballot.num = blt.num + 1;
-                        let pmax = get_pmax(&pvals);
-                        leader.update(pmax);
-
-                        // This is bad. Too many clones. That said, it is Arc, so maybe we can get away with it.
-                        for (_s, p) in leader.proposals.iter() {
-                            // let alt_sock = arc_sock.clone();
-                            let new_acc = acceptors.clone();
-                            let new_rep = replicas.clone();
-                            // let new_tx = agent_tx.clone();
-                            let q = p.clone();
-
-                            let (h, l) = commander_init(&acceptors);
-                            let oh = handler.clone();
-                            commanders.push(thread::spawn(move || {
-                                Agent::init_commander(q, new_acc, new_rep, h, l, oh, leader.id)
-                            }));
-                        }
-
-                        leader.active = true;
-                    }
-                    Agent::Preempted(blt) => {
-                        if blt > leader.ballot {
-                            leader.active = false;
-                            leader.ballot.num = blt.num + 1;
-                            // Pseudocode restarts the thread here. We just update the ballot. Message passing cheaper than spawning.
-                            scout_h.signals().send(leader.ballot);
-                        }
-                    }
-                    Agent::Committed => {} // Not given. WTF.
-                }
+    let _ = listener.for_each_async(move |event| match event {
+        NodeEvent::Signal(s) => leader.on_signal(s),
+        NodeEvent::Network(u) => match u {
+            NetEvent::Message(endpoint, buf) => leader.on_message(endpoint, buf),
+            NetEvent::Accepted(ep, _) => {
+                println!("Leader {id} Accepted {ep}.");
             }
-            NodeEvent::Network(u) => match u {
-                NetEvent::Message(_endpoint, buf) => {
-                    let msg: Message = serde_json::from_slice(&buf).unwrap();
-                    dbg!(&msg);
-                    match msg {
-                        Message::Propose(slot, cmd) => {
-                            // if let Some(_) = leader.proposals.get(&slot) {
-                            //     // Proposal is lost here. Correctness check.
-                            //     return;
-                            // }
-
-                            let prop = Proposal {
-                                slot,
-                                ballot: leader.ballot,
-                                command: cmd,
-                            };
-                            leader.proposals.insert(slot, prop.clone());
-
-                            let new_acc = acceptors.clone();
-                            let new_rep = replicas.clone();
-                            // let new_tx = agent_tx.clone();
-                            let oh = handler.clone();
-                            if leader.active {
-                                let (h, l) = commander_init(&acceptors);
-                                commanders.push(thread::spawn(move || {
-                                    Agent::init_commander(
-                                        prop, new_acc, new_rep, h, l, oh, leader.id,
-                                    )
-                                }))
-                            }
-                        }
-                        Message::Terminate => {
-                            return;
-                        }
-                        _ => {}
-                    }
-                }
-                NetEvent::Accepted(ep, _) => {
-                    println!("Leader {id} Accepted {ep}.");
-                }
-                NetEvent::Connected(ep, _) => {
-                    println!("Leader {id} Connected to {ep}.");
-                }
-                NetEvent::Disconnected(ep) => {
-                    println!("Leader {id} Disconnected from {ep}.");
-                } // _ => {}
-            },
-        }
+            NetEvent::Connected(ep, _) => {
+                println!("Leader {id} Connected to {ep}.");
+            }
+            NetEvent::Disconnected(ep) => {
+                println!("Leader {id} Disconnected from {ep}.");
+            } // _ => {}
+        },
     });
-    // todo!()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use super::*;
+    use crate::{
+        paxos::Command,
+        sim::{Node, Sim},
+    };
+
+    /// Stands in for another leader. Keeps whatever it's sent, never answers.
+    #[derive(Default)]
+    struct Quiet(Vec<Message>);
+
+    impl Node for Quiet {
+        fn on_message(&mut self, _from: Endpoint, buf: &[u8]) {
+            self.0.push(serde_json::from_slice(buf).unwrap());
+        }
+
+        fn on_timer(&mut self, _t: Box<dyn Any + Send>) {}
+    }
+
+    /// `msg`, as if it came from `from`.
+    fn deliver(leader: &mut Leader, from: SocketAddr, msg: Message) {
+        let ep = leader.io.connect(from);
+        leader.on_message(ep, &to_vec(&msg).unwrap());
+    }
+
+    fn pongs(sim: &Sim, at: SocketAddr) -> usize {
+        sim.node::<Quiet>(at).unwrap().0.iter().filter(|m| matches!(m, Message::Pong(_))).count()
+    }
+
+    /// What the replica at `at` has been told about reads, in order.
+    fn read_replies(sim: &Sim, at: SocketAddr) -> Vec<Option<usize>> {
+        let msgs = sim.node::<Quiet>(at).unwrap().0.iter();
+        msgs.filter_map(|m| match m {
+            Message::LeaseReadReply(_, upto) => Some(*upto),
+            _ => None,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn preempted_leader_waits_while_the_other_answers() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let mut sim = Sim::new(1);
+        let (us, them) = (cfg.addr(Role::Leader, 0), cfg.addr(Role::Leader, 1));
+        let mut leader = Leader::new(0, &cfg, sim.io(us));
+        leader.start();
+        leader.on_signal(Agent::Preempted(Ballot::new(5, 1)));
+        assert_eq!(leader.waiting_on, Some(1));
+        assert_eq!(leader.timeout, 2 * TIMEOUT_MIN);
+        assert_eq!(leader.ballot, Ballot::new(6, 0));
+        sim.add(us, leader);
+        sim.add(them, Quiet::default());
+
+        // Well past the timeout, but it keeps answering.
+        for _ in 0..10 {
+            sim.run_for(PING_INTERVAL);
+            deliver(sim.node_mut(us).unwrap(), them, Message::Pong(1));
+        }
+        assert_eq!(sim.node::<Leader>(us).unwrap().waiting_on, Some(1));
+        assert!(sim.node::<Quiet>(them).unwrap().0.iter().any(|m| matches!(m, Message::Ping(0))));
+        // Not the one to talk to while we're waiting.
+        deliver(sim.node_mut(us).unwrap(), them, Message::Ping(1));
+        sim.run_for(PING_INTERVAL);
+        assert_eq!(pongs(&sim, them), 0);
+
+        // Then it goes quiet.
+        sim.run_for(4 * TIMEOUT_MIN);
+        let leader = sim.node_mut::<Leader>(us).unwrap();
+        assert_eq!(leader.waiting_on, None);
+        assert_eq!(leader.scout.as_ref().unwrap().ballot, Ballot::new(6, 0));
+        leader.on_signal(Agent::Adopted(Ballot::new(6, 0), HashMap::new()));
+        assert!(leader.active);
+        assert_eq!(leader.timeout, 2 * TIMEOUT_MIN - TIMEOUT_STEP);
+        deliver(sim.node_mut(us).unwrap(), them, Message::Ping(1));
+        sim.run_for(PING_INTERVAL);
+        assert_eq!(pongs(&sim, them), 1);
+    }
+
+    #[test]
+    fn lease_reads_only_while_a_majority_has_granted() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let mut sim = Sim::new(1);
+        let rep = cfg.addr(Role::Replica, 0);
+        sim.add(rep, Quiet::default());
+        let mut leader = Leader::new(0, &cfg, sim.io(cfg.addr(Role::Leader, 0)));
+        leader.start();
+        leader.on_signal(Agent::Adopted(leader.ballot, HashMap::new()));
+        let value = Value::Command(Command { client_id: 0, op_id: 0, op: Raw::Null });
+        deliver(&mut leader, rep, Message::Propose(0, value));
+
+        // Asked for a lease, but nobody's granted it yet.
+        leader.on_signal(Agent::Renew);
+        deliver(&mut leader, rep, Message::LeaseRead(0));
+        let ballot = leader.ballot;
+        for acc in 0..2 {
+            deliver(&mut leader, cfg.addr(Role::Acceptor, acc), Message::LeaseGrant(acc, ballot, 1));
+        }
+        // Now it can vouch that nothing's been decided past what it's proposed.
+        deliver(&mut leader, rep, Message::LeaseRead(0));
+        // And not once it's run out.
+        sim.run_for(cfg.lease.guaranteed());
+        deliver(&mut leader, rep, Message::LeaseRead(0));
+        sim.run_for(Duration::from_millis(100));
+        assert_eq!(read_replies(&sim, rep), [None, Some(1), None]);
+    }
 }
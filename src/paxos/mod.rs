@@ -1,14 +1,24 @@
 //! Achieve consensus on a sequence of Strings using the Paxos algorithm.
 
 pub mod acceptor;
+pub mod client;
 pub mod dir;
 pub mod leader;
 pub mod replica;
+pub mod storage;
 
-use std::fmt::Debug;
+use std::{collections::BTreeMap, fmt::Debug, net::SocketAddr};
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::{
+    kv::{KvOp, KvResult},
+    session::SessionTable,
+};
+
+/// An op or a result, for whoever doesn't need to look inside: leaders and acceptors just pass them along.
+pub type Raw = serde_json::Value;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Ballot {
     pub num: usize,
@@ -21,29 +31,64 @@ impl Ballot {
     }
 }
 
+/// `O` is the state machine's op. Same for everything below.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct Command {
+pub struct Command<O = KvOp> {
     pub client_id: usize,
     pub op_id: usize,
-    pub op: String, // Small
+    pub op: O,
+}
+
+/// Who runs the show from some slot onwards.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Config {
+    pub leaders: Vec<SocketAddr>,
+    pub acceptors: Vec<SocketAddr>,
+}
+
+/// Whatever gets decided in a slot.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Value<O = KvOp> {
+    Command(Command<O>),
+    /// Decided in slot s, takes effect from slot s + WINDOW.
+    Reconfig(Config),
+    /// Several commands in one slot, applied in order.
+    Batch(Vec<Command<O>>),
+    /// Nothing at all. What a replica stuck on a gap proposes, to find out what went there.
+    Noop,
+}
+
+/// A replica's state as of some slot. Everything decided before `slot` can be forgotten.
+/// `R` is what the state machine answers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint<R = KvResult> {
+    pub slot: usize,
+    /// `StateMachine::snapshot`
+    pub state: Vec<u8>,
+    /// Configs in effect from `slot` onwards.
+    pub configs: BTreeMap<usize, Config>,
+    /// Sessions, by client id.
+    pub sessions: SessionTable<usize, R>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Proposal {
+pub struct Proposal<O = KvOp> {
     pub slot: usize,
     pub ballot: Ballot,
-    pub command: Command,
+    pub value: Value<O>,
 }
 
-impl PartialEq for Proposal {
+impl<O> PartialEq for Proposal<O> {
     fn eq(&self, other: &Self) -> bool {
         self.slot == other.slot && self.ballot == other.ballot
     }
 }
 
-impl Eq for Proposal {}
+impl<O> Eq for Proposal<O> {}
 
-impl PartialOrd for Proposal {
+// Proposals for different slots really are incomparable, so no `Some(self.cmp(other))` here.
+#[allow(clippy::non_canonical_partial_ord_impl)]
+impl<O> PartialOrd for Proposal<O> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         if self.slot == other.slot {
             self.ballot.partial_cmp(&other.ballot)
@@ -53,7 +98,7 @@ impl PartialOrd for Proposal {
     }
 }
 
-impl Ord for Proposal {
+impl<O> Ord for Proposal<O> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         if let Some(c) = self.partial_cmp(other) {
             c
@@ -64,20 +109,45 @@ impl Ord for Proposal {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Message {
+pub enum Message<O = KvOp, R = KvResult> {
     // client <-> replica
-    Request(Command),
-    Response(usize, String, Result<String, String>), // Does the content matter?
+    Request(Command<O>),
+    Response(usize, R), // op id, result
+    Expired(usize),     // op id. Too old to apply, and whether it ever was has been forgotten.
+
+    // admin -> replica
+    Reconfigure(Config),
 
     // replica <-> leader
-    Propose(usize, Command),
-    Decision(usize, Command),
+    Propose(usize, Value<O>),
+    Decision(usize, Value<O>),
+    Activate(Config), // Wakes up the leaders of a new config
+
+    // replica <-> replica
+    StateRequest(usize, usize),    // replica id, slot_out
+    StateTransfer(Checkpoint<R>),
 
     // leader <-> acceptor
-    Phase1a(usize, Ballot),                       // acceptor id
-    Phase1b(usize, usize, Ballot, Vec<Proposal>), // leader id, acceptor id,
-    Phase2a(usize, Proposal),                     // leader id
-    Phase2b(usize, usize, Ballot),                // leader id, acceptor id
+    Phase1a(usize, Ballot, usize),                   // leader id, ballot, first slot it wants pvalues for
+    Phase1b(usize, usize, Ballot, Vec<Proposal<O>>), // leader id, acceptor id,
+    Phase2a(usize, Proposal<O>),                     // leader id
+    Phase2b(usize, usize, Ballot, usize, Ballot),    // leader id, acceptor id, acceptor's ballot, slot, proposal's ballot
+
+    // replica -> leader -> acceptor. Everything below the slot is in a checkpoint (of enough replicas).
+    Checkpointed(usize, usize), // replica id, slot
+    Trim(usize),                // slot
+
+    // leader <-> acceptor, for leases
+    LeaseRequest(usize, Ballot, usize),    // leader id, ballot, round
+    LeaseGrant(usize, Ballot, usize),      // acceptor id, acceptor's ballot, round
+
+    // replica <-> leader, for reads under a lease
+    LeaseRead(usize),                      // read id
+    LeaseReadReply(usize, Option<usize>),  // read id, slot the replica has to get to first. None if no lease.
+
+    // leader <-> leader. Pong only if we're not waiting on someone else ourselves.
+    Ping(usize), // leader id
+    Pong(usize), // leader id
 
     // Special
     Terminate,
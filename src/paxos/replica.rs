@@ -1,108 +1,333 @@
 #![allow(dead_code)]
-use crate::ReplicaState;
+use crate::{
+    config::{ClusterConfig, Role},
+    faults::{self, Faults},
+    invariants::Invariants,
+    session::SessionTable,
+    StateMachine,
+};
 
-use self::dir::get_all_leaders;
+use self::dir::{connect_all, get_all_replicas};
+use crate::net::Io;
 use hashbrown::HashMap;
 use message_io::{
     network::{Endpoint, NetEvent},
-    node::{NodeHandler, NodeListener},
+    node::{NodeEvent, NodeHandler, NodeListener},
 };
 use serde_json::{from_slice, to_vec}; // Might have to change this to bincode or a custom impl.
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
 
 use super::*;
 
+/// How many slots ahead of `slot_out` we may propose. Also how long a reconfiguration takes to kick in.
 const WINDOW: usize = 32;
+/// Take a checkpoint every this many slots.
+const CHECKPOINT_INTERVAL: usize = 128;
+/// Most commands proposed in one slot.
+const MAX_BATCH: usize = 16;
+/// Longest a command waits for others to share a slot with.
+const BATCH_DELAY: Duration = Duration::from_millis(2);
+/// How long a proposal goes unanswered before it's sent again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
 
-/// This can be something as simple as
-/// ```
-/// |q: ReplicaState| (q, Ok(""))
-/// ```
-/// in which case we'd be storing constants and not operations.
-///
-/// The triv() function does just that.
-pub struct Op {
-    /// Change to SocketAddr?
-    client_id: usize,
-    /// Sequence number.
-    op_id: usize,
-    /// The operation to be performed.
-    op: Box<dyn Fn(&ReplicaState) -> (ReplicaState, Result<String, String>) + Send + Sync>,
+/// What the replica's timers carry.
+#[derive(Debug, Clone, Copy)]
+pub enum Tick {
+    /// Whatever's waiting for a batch goes now.
+    Batch,
+    /// Propose everything still undecided again, and fill in any gap we're stuck on.
+    Resend,
 }
 
 /// Node struct.
-pub struct Replica {
+pub struct Replica<S: StateMachine> {
     /// Just a lil number. Unique among all replicas.
     id: usize,
-    /// Eh, just some state.
-    state: ReplicaState,
+    /// Eh, just some state. Whatever we're replicating.
+    state: S,
     /// Things for the algorithm.
     slot_in: usize,
     slot_out: usize,
-    /// Outstanding requests from clients
-    requests: Vec<Command>,
+    /// Outstanding requests from clients (and admins)
+    requests: Vec<Value<S::Op>>,
     /// Outstaning proposals that have been sent out, but not decided upon.
-    proposals: BTreeMap<usize, Command>,
-    /// These are the done deals.
-    decisions: HashMap<usize, Command>,
+    proposals: BTreeMap<usize, Value<S::Op>>,
+    /// These are the done deals. Only those at or after the checkpoint are kept.
+    decisions: HashMap<usize, Value<S::Op>>,
+    /// Who's done what, so nothing is applied twice. Goes into checkpoints along with `state`.
+    sessions: SessionTable<usize, S::Output>,
+    /// Snapshot of everything as of some `slot_out`. Everything decided before that slot has been forgotten.
+    checkpoint: Checkpoint<S::Output>,
 
-    /// These are the guys you gotta talk to.
-    leaders: Vec<Endpoint>,
+    /// Configs, keyed by the first slot they apply to.
+    /// The leaders of the config in effect at a slot are the guys you gotta talk to.
+    configs: BTreeMap<usize, Config>,
+    /// Connections to every leader in the cluster file, by address.
+    leaders: HashMap<SocketAddr, Endpoint>,
+    /// The other replicas. Asked for a checkpoint when we fall behind.
+    replicas: Vec<Endpoint>,
 
     /// This is us.
     // sock: UdpSocket,
     // listener: NodeListener<()>,
-    handler: NodeHandler<()>,
+    io: Box<dyn Io<Tick>>,
 
     /// These are those icky clients that keep bothering us.
     clients: HashMap<usize, Endpoint>,
+
+    /// Every acceptor a config could name.
+    acceptors: Vec<SocketAddr>,
+
+    /// Whether to try reads under a leader's lease first.
+    leases: bool,
+    /// Reads we've asked the leaders about, by read id.
+    lease_reads: HashMap<usize, Command<S::Op>>,
+    next_read: usize,
+    /// `next_read` at the last resend. Reads up to here have had a whole resend interval to get an answer.
+    read_mark: usize,
+    /// Reads a leader has vouched for, waiting for `slot_out` to get to the slot it gave.
+    ready_reads: Vec<(usize, Command<S::Op>)>,
+    /// Reads answered that way so far.
+    leased: usize,
+
+    /// Whether the batch timer is going.
+    batching: bool,
 }
 
-impl Replica {
-    pub fn new(id: usize, leaders: Vec<Endpoint>, handler: NodeHandler<()>) -> Self {
+impl<S> Replica<S>
+where
+    S: StateMachine,
+{
+    pub fn new(
+        id: usize,
+        initial: Config,
+        leaders: HashMap<SocketAddr, Endpoint>,
+        replicas: Vec<Endpoint>,
+        io: Box<dyn Io<Tick>>,
+    ) -> Self {
+        let state = S::default();
+        let initial_acceptors = initial.acceptors.clone();
+        let configs = BTreeMap::from([(0, initial)]);
+        let sessions = SessionTable::default();
+        let checkpoint = Checkpoint {
+            slot: 0,
+            state: state.snapshot(),
+            configs: configs.clone(),
+            sessions: sessions.clone(),
+        };
         Self {
             id,
-            state: ReplicaState::default(),
+            state,
             slot_in: 0,
             slot_out: 0,
             requests: vec![],
             proposals: BTreeMap::new(),
             decisions: HashMap::new(),
+            sessions,
+            checkpoint,
+            configs,
             leaders,
-            handler,
+            replicas,
+            io,
             clients: HashMap::new(),
+            acceptors: initial_acceptors,
+            leases: false,
+            lease_reads: HashMap::new(),
+            next_read: 0,
+            read_mark: 0,
+            ready_reads: vec![],
+            leased: 0,
+            batching: false,
+        }
+    }
+
+    /// Replica `id` of the cluster in `cfg`, connected up and asking around for anything newer.
+    pub fn start(id: usize, cfg: &ClusterConfig, io: Box<dyn Io<Tick>>) -> Self {
+        // Standbys aren't part of the initial config.
+        let initial = Config {
+            leaders: cfg.nodes(Role::Leader).filter(|n| !n.standby).map(|n| n.addr).collect(),
+            acceptors: cfg.nodes(Role::Acceptor).filter(|n| !n.standby).map(|n| n.addr).collect(),
+        };
+        let leaders = connect_all(cfg, Role::Leader, &*io);
+        let replicas = get_all_replicas(cfg, &*io);
+        let mut rep = Self::new(id, initial, leaders, replicas, io);
+        rep.acceptors = cfg.nodes(Role::Acceptor).map(|n| n.addr).collect();
+        rep.leases = cfg.lease.enabled();
+        println!("Inited replica {id}.");
+        // Might be a new or restarted replica. Catch up if anyone's ahead.
+        rep.request_state();
+        rep.io.timer(Tick::Resend, RESEND_INTERVAL);
+        rep
+    }
+
+    /// A client request. Reads try the lease first, unless this is a retry of one that already did.
+    fn request(&mut self, c: Command<S::Op>) {
+        if !self.leases || !S::is_read_only(&c.op) {
+            self.requests.push(Value::Command(c));
+            return;
+        }
+        let before = self.lease_reads.len();
+        self.lease_reads.retain(|_, r| *r != c);
+        if self.lease_reads.len() < before {
+            // Nobody answered last time. The slow way, then.
+            self.requests.push(Value::Command(c));
+            return;
+        }
+        self.next_read += 1;
+        self.lease_reads.insert(self.next_read, c);
+        let buf = Self::encode(&Message::LeaseRead(self.next_read));
+        for l in self.leaders_at(self.slot_in) {
+            self.io.send(l, &buf);
         }
     }
 
+    /// Answer the reads we've caught up for. Nothing goes through the session table, reads can't be applied twice.
+    fn serve_reads(&mut self) {
+        let slot_out = self.slot_out;
+        let (ready, waiting) = std::mem::take(&mut self.ready_reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(upto, _)| *upto <= slot_out);
+        self.ready_reads = waiting;
+        for (_, c) in ready {
+            self.leased += 1;
+            let res = self.state.apply(&c.op);
+            if let Some(addr) = self.clients.get(&c.client_id) {
+                let buf = Self::encode(&Message::Response(c.op_id, res));
+                self.io.send(*addr, &buf);
+            }
+        }
+    }
+
+    fn encode(msg: &Message<S::Op, S::Output>) -> Vec<u8> {
+        to_vec(msg).unwrap()
+    }
+
+    /// Config in effect at `slot`.
+    ///
+    /// Anything decided at `slot - WINDOW` or before has been performed by the time we propose for `slot`, so this is settled.
+    fn config_at(&self, slot: usize) -> &Config {
+        self.configs.range(..=slot).next_back().unwrap().1
+    }
+
+    /// Somebody to lead and a majority to be had, all of them ones we know. Anything else would wedge every slot after it.
+    fn valid(&self, config: &Config) -> bool {
+        !config.leaders.is_empty()
+            && !config.acceptors.is_empty()
+            && config.leaders.iter().all(|a| self.leaders.contains_key(a))
+            && config.acceptors.iter().all(|a| self.acceptors.contains(a))
+    }
+
+    /// Whoever leads at `slot`.
+    fn leaders_at(&self, slot: usize) -> Vec<Endpoint> {
+        self.config_at(slot).leaders.iter().filter_map(|a| self.leaders.get(a).copied()).collect()
+    }
+
+    /// What goes in the next slot. Commands go in batches of `MAX_BATCH`, or whatever's there once `flush` says so
+    /// or nothing else is in flight.
+    /// Anything else (reconfigs, batches coming round again) goes as is.
+    fn next_value(&mut self, flush: bool) -> Option<Value<S::Op>> {
+        if !matches!(self.requests.last(), Some(Value::Command(_))) {
+            return self.requests.pop();
+        }
+        let ready = self.requests.iter().rev().take_while(|v| matches!(v, Value::Command(_))).count();
+        if ready < MAX_BATCH && !flush && !self.proposals.is_empty() {
+            // Slots already in flight, so nobody's waiting on this one yet. Give it a moment, more might turn up.
+            if !self.batching {
+                self.batching = true;
+                self.io.timer(Tick::Batch, BATCH_DELAY);
+            }
+            return None;
+        }
+        let at = self.requests.len() - ready.min(MAX_BATCH);
+        let mut batch = self
+            .requests
+            .split_off(at)
+            .into_iter()
+            .rev()
+            .map(|v| match v {
+                Value::Command(c) => c,
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        Some(if batch.len() == 1 {
+            Value::Command(batch.pop().unwrap())
+        } else {
+            Value::Batch(batch)
+        })
+    }
+
     /// Self explanatory name.
     ///
-    /// Each proposal is removed from `requests`, topped off with a slot, and sent to all leaders.
-    /// This is done for multiple requests, each getting a different slot.
-    fn propose(&mut self) {
+    /// Requests are taken off `requests` a batch at a time, topped off with a slot, and sent to all leaders.
+    /// This is done for multiple batches, each getting a different slot.
+    fn propose(&mut self, flush: bool) {
         while self.slot_in < self.slot_out + WINDOW && !self.requests.is_empty() {
             if self.decisions.get(&self.slot_in).is_none() {
-                let c = self.requests.pop().unwrap(); // do this
+                // reconfig thing, give that leadership
+                let leaders = self.leaders_at(self.slot_in);
+                let Some(c) = self.next_value(flush) else {
+                    break;
+                }; // do this
                 self.proposals.insert(self.slot_in, c.clone()); // and then do that
                 let msg = Message::Propose(self.slot_in, c); // And the this.
 
                 // self.proposals[&self.slot_in] = c;
 
-                let buf = to_vec(&msg).unwrap();
+                let buf = Self::encode(&msg);
 
                 // Now send the bloody thing
-                self.leaders.iter().for_each(|addr| {
-                    self.handler.network().send(*addr, &buf);
+                leaders.iter().for_each(|addr| {
+                    self.io.send(*addr, &buf);
                 });
             }
             self.slot_in += 1;
         }
-        println!("PROPOSE");
+    }
+
+    /// Perform every decision we have, in order, till the first gap.
+    fn perform_decided(&mut self) {
+        while let Some(c1) = self.decisions.get(&self.slot_out) {
+            if let Some(c2) = self.proposals.remove(&self.slot_out) {
+                if c2 != *c1 && c2 != Value::Noop {
+                    self.requests.push(c2);
+                }
+            }
+
+            // Actually do the thing.
+            self.perform(c1.clone()); // GAH, CLONES!
+        }
+    }
+
+    /// Decided values go through here. One slot each, however many commands are in it.
+    fn perform(&mut self, value: Value<S::Op>) {
+        match value {
+            Value::Command(op) => self.perform_command(op),
+            Value::Batch(ops) => {
+                for op in ops {
+                    self.perform_command(op);
+                }
+            }
+            Value::Noop => {}
+            Value::Reconfig(config) => {
+                // Leaders of the new config have been asleep till now.
+                let buf = Self::encode(&Message::Activate(config.clone()));
+                for l in config.leaders.iter().filter_map(|a| self.leaders.get(a)) {
+                    self.io.send(*l, &buf);
+                }
+                println!("Replica {} reconfiguring at slot {}", self.id, self.slot_out + WINDOW);
+                self.configs.insert(self.slot_out + WINDOW, config);
+            }
+        }
+        self.slot_out += 1;
+        if self.slot_out.is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.take_checkpoint();
+        }
     }
 
     /// Simple pipeline.
     /// Gets thing from leader, sends thing to client.
     /// Shimpul.
-    fn perform(&mut self, op: Command) {
+    fn perform_command(&mut self, op: Command<S::Op>) {
         /*
             NOTE:
             - Pseudocode has this particular if block so as to avoid duplicate executions in case one command is decided at multiple slots.
@@ -110,6 +335,7 @@ impl Replica {
             - We contend that a command may mutate some external state, and hence is not idempotent.
             - Thus, this block has been commented out.
             - We *are* keeping this, just in case.
+            - Duplicates (and client retries) are caught by the session table instead. Same idea, but it survives checkpoints.
         */
 
         // if self.decisions.contains(&Some(op)) {
@@ -118,70 +344,448 @@ impl Replica {
         // }
 
         // dbg!(&self.clients, &op);
-        let addr = self.clients.get(&op.client_id);
-        let (state, res) = ReplicaState::triv(op.op)(&self.state);
+        let addr = self.clients.get(&op.client_id).copied();
         // For some reason, this should be atomic, but since we're not using threads, it's fine.
-        {
+        let res = {
             // let _un = self.lock.lock().unwrap();
-            self.state = state;
-            self.slot_out += 1;
-        }
+            let state = &mut self.state;
+            self.sessions
+                .execute(op.client_id, op.op_id, self.slot_out, || state.apply(&op.op))
+        };
         // dbg!("PERFORM");
 
         if let Some(addr) = addr {
-            // TODO: Change the contents of Message::Response, maybe. Don't think String is enough.
-            let msg = Message::Response(op.op_id, "Hello there".to_string(), res);
+            let msg = match res {
+                Some(res) => Message::Response(op.op_id, res),
+                // Better the client hears it's too late than nothing at all.
+                None => Message::Expired(op.op_id),
+            };
 
-            let buf = to_vec(&msg).unwrap();
+            let buf = Self::encode(&msg);
             // self.sock.send_to(&buf, addr).unwrap();
-            self.handler.network().send(*addr, &buf);
+            self.io.send(addr, &buf);
         }
     }
-}
 
-/// This is the main loop for the replica. It listens for messages from the leaders and clients.
-pub fn listen(id: usize, listener: NodeListener<()>, handler: NodeHandler<()>) {
-    let leaders = get_all_leaders(handler.clone());
-    let mut rep = Replica::new(id, leaders, handler);
-    println!("Inited replica {id}.");
-    let _ = listener.for_each_async(move |event| match event.network() {
-        NetEvent::Message(endpoint, buf) => {
-            let msg = from_slice::<Message>(&buf).unwrap();
-            // dbg!(&msg);
-            match msg {
-                Message::Request(c) => {
-                    let c = c.clone();
-                    let _ = rep.clients.try_insert(c.client_id, endpoint);
-                    rep.requests.push(c);
-                    dbg!(&rep.requests);
+    /// Snapshot the state at `slot_out`, and forget every decision before it.
+    fn take_checkpoint(&mut self) {
+        let slot = self.slot_out;
+        // Only the config in effect now, and the ones to come, still matter.
+        let current = *self.configs.range(..=slot).next_back().unwrap().0;
+        self.configs = self.configs.split_off(&current);
+        self.checkpoint = Checkpoint {
+            slot,
+            state: self.state.snapshot(),
+            configs: self.configs.clone(),
+            sessions: self.sessions.clone(),
+        };
+        self.decisions.retain(|s, _| *s >= slot);
+        self.announce_checkpoint();
+    }
+
+    /// Let the leaders know, so the acceptors can forget what's in here once enough of us have it.
+    fn announce_checkpoint(&self) {
+        let buf = Self::encode(&Message::Checkpointed(self.id, self.checkpoint.slot));
+        for l in self.leaders.values() {
+            self.io.send(*l, &buf);
+        }
+    }
+
+    /// Ask the other replicas for something newer than what we have.
+    fn request_state(&self) {
+        let buf = Self::encode(&Message::StateRequest(self.id, self.slot_out));
+        for r in self.replicas.iter() {
+            self.io.send(*r, &buf);
+        }
+    }
+
+    /// Jump straight to a checkpoint from a peer, if it gets us anywhere.
+    fn install(&mut self, cp: Checkpoint<S::Output>) {
+        let slot = cp.slot;
+        if slot <= self.slot_out {
+            return;
+        }
+        self.state.restore(&cp.state);
+        self.configs = cp.configs.clone();
+        self.sessions = cp.sessions.clone();
+        self.slot_out = slot;
+        self.slot_in = self.slot_in.max(slot);
+        self.decisions.retain(|s, _| *s >= slot);
+        // Whatever we proposed below the checkpoint was decided *something*, maybe not ours.
+        // We can't tell which, so try again. Worst case the client sees it twice.
+        let stale = self.proposals.split_off(&slot);
+        let stale = std::mem::replace(&mut self.proposals, stale);
+        self.requests.extend(stale.into_values());
+        self.checkpoint = cp;
+        self.announce_checkpoint();
+        // Anything decided past the checkpoint can go right away, no need to wait for another decision.
+        self.perform_decided();
+    }
+
+    /// Every slot before this has been performed.
+    pub fn slot_out(&self) -> usize {
+        self.slot_out
+    }
+
+    /// How many reads we've answered off a leader's lease, without a slot.
+    pub fn leased(&self) -> usize {
+        self.leased
+    }
+
+    /// What's been decided, from the last checkpoint on.
+    pub fn decisions(&self) -> &HashMap<usize, Value<S::Op>> {
+        &self.decisions
+    }
+
+    /// Show `inv` what we've decided.
+    pub fn observe(&self, inv: &Invariants<S::Op>) {
+        inv.replica(self.id, &self.decisions);
+    }
+
+    /// Proposals and decisions can both get lost. Leaders answer a proposal for a decided slot with the decision.
+    fn resend(&mut self) {
+        // Decided further on, but not here, and we never proposed anything here. Ask about it with a no-op.
+        let stuck = self.decisions.keys().any(|s| *s > self.slot_out);
+        if stuck && !self.proposals.contains_key(&self.slot_out) {
+            self.proposals.insert(self.slot_out, Value::Noop);
+            self.slot_in = self.slot_in.max(self.slot_out + 1);
+        }
+        for (slot, value) in self.proposals.iter() {
+            let buf = Self::encode(&Message::Propose(*slot, value.clone()));
+            for l in self.leaders_at(*slot) {
+                self.io.send(l, &buf);
+            }
+        }
+        // The leaders might have forgotten it already, if the others have checkpointed past it.
+        if stuck {
+            self.request_state();
+        }
+        // Nobody's vouching for these. Maybe the leader's gone, maybe the client has. The slow way, then,
+        // rather than keep them about forever.
+        let mark = self.read_mark;
+        let stale = self.lease_reads.extract_if(|rid, _| *rid <= mark).map(|(_, c)| Value::Command(c));
+        self.requests.extend(stale.collect::<Vec<_>>());
+        self.read_mark = self.next_read;
+    }
+
+    pub fn on_timer(&mut self, t: Tick) {
+        match t {
+            Tick::Batch => {
+                self.batching = false;
+                self.propose(true);
+            }
+            Tick::Resend => {
+                self.resend();
+                self.propose(false);
+                self.io.timer(Tick::Resend, RESEND_INTERVAL);
+            }
+        }
+    }
+
+    pub fn on_message(&mut self, endpoint: Endpoint, buf: &[u8]) {
+        let Ok(msg) = from_slice::<Message<S::Op, S::Output>>(buf) else {
+            println!("Replica {} dropped a message it couldn't read from {endpoint}", self.id);
+            return;
+        };
+        match msg {
+            Message::Request(c) => {
+                let c = c.clone();
+                let _ = self.clients.try_insert(c.client_id, endpoint);
+                self.request(c);
+            }
+            Message::Reconfigure(config) => {
+                if !self.valid(&config) {
+                    println!("Replica {} dropped a reconfig it can't use: {config:?}", self.id);
+                    return;
+                }
+                self.requests.push(Value::Reconfig(config));
+            }
+            Message::Decision(slot, value) => {
+                if slot < self.checkpoint.slot {
+                    // Old news, already in the checkpoint.
+                    return;
                 }
-                Message::Decision(slot, command) => {
-                    // Accept the consensus.
-                    rep.decisions.insert(slot, command);
-                    while let Some(c1) = rep.decisions.get(&rep.slot_out) {
-                        if let Some(c2) = rep.proposals.remove(&rep.slot_out) {
-                            if c2 != *c1 {
-                                rep.requests.push(c2);
-                            }
+                // Accept the consensus.
+                self.decisions.insert(slot, value);
+                self.perform_decided();
+                self.serve_reads();
+                // Decisions are arriving for slots way past us, so we've missed some. Go fetch.
+                if slot >= self.slot_out + WINDOW {
+                    self.request_state();
+                }
+            }
+            Message::StateRequest(_rid, slot) => {
+                if self.checkpoint.slot > slot {
+                    let msg = Message::StateTransfer(self.checkpoint.clone());
+                    self.io.send(endpoint, &Self::encode(&msg));
+                }
+            }
+            Message::StateTransfer(cp) => {
+                self.install(cp);
+                self.serve_reads();
+            }
+            Message::LeaseReadReply(rid, upto) => {
+                // Only the first leader to answer counts.
+                if let Some(c) = self.lease_reads.remove(&rid) {
+                    match upto {
+                        Some(upto) => {
+                            self.ready_reads.push((upto, c));
+                            self.serve_reads();
                         }
-
-                        // Actually do the thing.
-                        rep.perform(c1.clone()); // GAH, CLONES!
+                        None => self.requests.push(Value::Command(c)),
                     }
-                    dbg!(&rep.decisions);
                 }
-                _ => unreachable!(), // It had better be, damn it.
             }
-            rep.propose();
-        }
-        NetEvent::Connected(ep, _) => {
-            println!("Replica {id} Connected to {ep}.");
+            msg => {
+                println!("Replica {} got a message it has no use for: {msg:?}", self.id);
+                return;
+            }
         }
-        NetEvent::Accepted(ep, _) => {
-            println!("Replica {id} Accepted {ep}.");
+        self.propose(false);
+    }
+}
+
+/// This is the main loop for the replica. It listens for messages from the leaders and clients.
+pub fn listen<S>(
+    id: usize,
+    cfg: &ClusterConfig,
+    listener: NodeListener<Tick>,
+    handler: NodeHandler<Tick>,
+    faults: Option<&Faults>,
+    invariants: Option<&Invariants<S::Op>>,
+) where
+    S: StateMachine,
+{
+    let io = faults::wrap(faults, cfg.addr(Role::Replica, id), Box::new(handler));
+    let mut rep = Replica::<S>::start(id, cfg, io);
+    let invariants = invariants.cloned();
+    let _ = listener.for_each_async(move |event| {
+        if let NodeEvent::Signal(t) = event {
+            rep.on_timer(t);
+            return;
         }
-        NetEvent::Disconnected(ep) => {
-            println!("Replica {id} Disconnected from {ep}.");
+        match event.network() {
+            NetEvent::Message(endpoint, buf) => {
+                rep.on_message(endpoint, buf);
+                if let Some(inv) = &invariants {
+                    rep.observe(inv);
+                }
+            }
+            NetEvent::Connected(ep, _) => {
+                println!("Replica {id} Connected to {ep}.");
+            }
+            NetEvent::Accepted(ep, _) => {
+                println!("Replica {id} Accepted {ep}.");
+            }
+            NetEvent::Disconnected(ep) => {
+                println!("Replica {id} Disconnected from {ep}.");
+            }
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv::{KvOp, KvStore},
+        sim::Sim,
+    };
+
+    /// Replica `id` from cluster.json, on the simulated network. Nothing happens till it's added and the sim runs,
+    /// or something's handed to it with `deliver`.
+    fn replica(sim: &Sim, cfg: &ClusterConfig, id: usize) -> Replica<KvStore> {
+        Replica::start(id, cfg, sim.io(cfg.addr(Role::Replica, id)))
+    }
+
+    fn cmd(op_id: usize) -> Command<KvOp> {
+        Command {
+            client_id: 0,
+            op_id,
+            op: KvOp::Put("a".into(), op_id.to_string()),
+        }
+    }
+
+    fn put(op_id: usize) -> Value<KvOp> {
+        Value::Command(cmd(op_id))
+    }
+
+    /// `msg`, as if it came from `from`.
+    fn deliver(rep: &mut Replica<KvStore>, from: SocketAddr, msg: Message) {
+        let ep = rep.io.connect(from);
+        rep.on_message(ep, &to_vec(&msg).unwrap());
+    }
+
+    #[test]
+    fn checkpoint_forgets_old_decisions() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let sim = Sim::new(1);
+        let leader = cfg.addr(Role::Leader, 0);
+        let mut rep = replica(&sim, &cfg, 0);
+        for slot in 0..CHECKPOINT_INTERVAL + 5 {
+            deliver(&mut rep, leader, Message::Decision(slot, put(slot)));
+        }
+        assert_eq!(rep.slot_out, CHECKPOINT_INTERVAL + 5);
+        assert_eq!(rep.checkpoint.slot, CHECKPOINT_INTERVAL);
+        assert!(rep.decisions.keys().all(|s| *s >= CHECKPOINT_INTERVAL));
+
+        // Already in the checkpoint. Nothing to do with it.
+        deliver(&mut rep, leader, Message::Decision(3, put(999)));
+        assert!(!rep.decisions.contains_key(&3));
+    }
+
+    #[test]
+    fn new_replica_catches_up_from_a_peer() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let mut sim = Sim::new(1);
+        let leader = cfg.addr(Role::Leader, 0);
+        let (a, b) = (cfg.addr(Role::Replica, 0), cfg.addr(Role::Replica, 1));
+        let mut ahead = replica(&sim, &cfg, 0);
+        for slot in 0..CHECKPOINT_INTERVAL + 5 {
+            deliver(&mut ahead, leader, Message::Decision(slot, put(slot)));
+        }
+        sim.add(a, ahead);
+
+        // Asks around as it starts.
+        let behind = replica(&sim, &cfg, 1);
+        sim.add(b, behind);
+        sim.run_for(Duration::from_millis(100));
+        let cp = sim.node::<Replica<KvStore>>(a).unwrap().checkpoint.clone();
+        let behind = sim.node_mut::<Replica<KvStore>>(b).unwrap();
+        assert_eq!(behind.slot_out, CHECKPOINT_INTERVAL);
+        assert_eq!(behind.state.snapshot(), cp.state);
+
+        // The rest come as decisions, like for everyone else.
+        for slot in CHECKPOINT_INTERVAL..CHECKPOINT_INTERVAL + 5 {
+            deliver(behind, leader, Message::Decision(slot, put(slot)));
+        }
+        let state = behind.state.snapshot();
+        assert_eq!(state, sim.node::<Replica<KvStore>>(a).unwrap().state.snapshot());
+    }
+
+    #[test]
+    fn install_performs_decisions_buffered_past_the_checkpoint() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let sim = Sim::new(1);
+        let leader = cfg.addr(Role::Leader, 0);
+        let mut ahead = replica(&sim, &cfg, 0);
+        for slot in 0..CHECKPOINT_INTERVAL {
+            deliver(&mut ahead, leader, Message::Decision(slot, put(slot)));
+        }
+        let peer = cfg.addr(Role::Replica, 0);
+
+        // Missed everything up to the checkpoint, but heard the few after it.
+        let mut behind = replica(&sim, &cfg, 1);
+        for slot in CHECKPOINT_INTERVAL..CHECKPOINT_INTERVAL + 5 {
+            deliver(&mut behind, leader, Message::Decision(slot, put(slot)));
+        }
+        assert_eq!(behind.slot_out, 0);
+
+        // Nothing else comes after the checkpoint, so it has to do them itself.
+        deliver(&mut behind, peer, Message::StateTransfer(ahead.checkpoint.clone()));
+        assert_eq!(behind.slot_out, CHECKPOINT_INTERVAL + 5);
+        let last = (CHECKPOINT_INTERVAL + 4).to_string();
+        assert_eq!(behind.state.apply(&KvOp::Get("a".into())), Ok(Some(last)));
+    }
+
+    #[test]
+    fn reconfig_takes_effect_a_window_later() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let sim = Sim::new(1);
+        let leader = cfg.addr(Role::Leader, 0);
+        let mut rep = replica(&sim, &cfg, 0);
+        let old = rep.config_at(0).clone();
+        let new = Config {
+            leaders: vec![cfg.addr(Role::Leader, 2)],
+            acceptors: (3..6).map(|i| cfg.addr(Role::Acceptor, i)).collect(),
+        };
+        deliver(&mut rep, leader, Message::Decision(0, put(0)));
+        deliver(&mut rep, leader, Message::Decision(1, Value::Reconfig(new.clone())));
+        deliver(&mut rep, leader, Message::Decision(2, put(2)));
+
+        // Everything up to the window was maybe proposed under the old config already.
+        assert_eq!(rep.config_at(WINDOW), &old);
+        assert_eq!(rep.config_at(1 + WINDOW), &new);
+        let standby = rep.leaders[&cfg.addr(Role::Leader, 2)];
+        assert_eq!(rep.leaders_at(1 + WINDOW), [standby]);
+    }
+
+    #[test]
+    fn unanswered_lease_read_goes_through_the_log() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let sim = Sim::new(1);
+        let client = cfg.client_addr(0);
+        let mut rep = replica(&sim, &cfg, 0);
+        let get = Command {
+            op: KvOp::Get("a".into()),
+            ..cmd(1)
+        };
+        deliver(&mut rep, client, Message::Request(get.clone()));
+        assert_eq!(rep.lease_reads.len(), 1);
+
+        // Asked just before the timer, so it gets a whole interval more.
+        rep.on_timer(Tick::Resend);
+        assert_eq!(rep.lease_reads.len(), 1);
+        // No leader ever answered.
+        rep.on_timer(Tick::Resend);
+        assert!(rep.lease_reads.is_empty());
+        assert_eq!(rep.proposals.values().collect::<Vec<_>>(), [&Value::Command(get)]);
+    }
+
+    #[test]
+    fn reconfig_to_unknown_nodes_is_dropped() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let sim = Sim::new(1);
+        let (leader, admin) = (cfg.addr(Role::Leader, 0), cfg.client_addr(99));
+        let mut rep = replica(&sim, &cfg, 0);
+        let nowhere: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let acceptors = cfg.nodes(Role::Acceptor).map(|n| n.addr).collect::<Vec<_>>();
+        for config in [
+            Config { leaders: vec![nowhere], acceptors: acceptors.clone() },
+            Config { leaders: vec![leader], acceptors: vec![nowhere] },
+            Config { leaders: vec![], acceptors: acceptors.clone() },
+            Config { leaders: vec![leader], acceptors: vec![] },
+        ] {
+            deliver(&mut rep, admin, Message::Reconfigure(config));
+        }
+        assert!(rep.proposals.is_empty());
+
+        // Decided anyway, from before anybody checked. Nobody to activate there, but no reason to fall over.
+        let bad = Config { leaders: vec![nowhere, leader], acceptors };
+        deliver(&mut rep, leader, Message::Decision(0, Value::Reconfig(bad)));
+        assert_eq!(rep.slot_out, 1);
+        assert_eq!(rep.leaders_at(WINDOW), [rep.leaders[&leader]]);
+    }
+
+    #[test]
+    fn requests_wait_for_a_batch_while_a_slot_is_in_flight() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let sim = Sim::new(1);
+        let (leader, client) = (cfg.addr(Role::Leader, 0), cfg.client_addr(0));
+        let mut rep = replica(&sim, &cfg, 0);
+
+        // Nothing in flight. Nobody to wait for.
+        deliver(&mut rep, client, Message::Request(cmd(1)));
+        assert_eq!(rep.proposals[&0], put(1));
+        // These wait for the timer, then go together.
+        for op_id in 2..5 {
+            deliver(&mut rep, client, Message::Request(cmd(op_id)));
+        }
+        assert_eq!(rep.proposals.len(), 1);
+        rep.on_timer(Tick::Batch);
+        // Newest first, same order they'd come off `requests` one at a time.
+        let batch = Value::Batch((2..5).rev().map(cmd).collect());
+        assert_eq!(rep.proposals[&1], batch);
+        // A full batch doesn't wait.
+        for op_id in 5..5 + MAX_BATCH {
+            deliver(&mut rep, client, Message::Request(cmd(op_id)));
+        }
+        assert_eq!(rep.proposals[&2], Value::Batch((5..5 + MAX_BATCH).rev().map(cmd).collect()));
+
+        // All of a batch goes in its one slot.
+        deliver(&mut rep, leader, Message::Decision(0, put(1)));
+        deliver(&mut rep, leader, Message::Decision(1, batch));
+        assert_eq!(rep.slot_out, 2);
+        assert_eq!(rep.state.apply(&KvOp::Get("a".into())), Ok(Some("2".into())));
+    }
+}
@@ -0,0 +1,308 @@
+//! Stable storage for acceptors: the promised ballot and the accepted pvalues.
+//!
+//! An acceptor that forgets either can break a promise after a restart, so both go to disk before any reply.
+//! So does the slot below which it keeps nothing: forgetting that would let it accept something there again.
+//! `FileStorage` is a write-ahead file of JSON records, fsync'd after every write, and rewritten on compaction.
+//! `MemStorage` forgets everything, for the threaded harness.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Ballot, Raw};
+
+/// Acceptors never look inside an op.
+type Proposal = super::Proposal<Raw>;
+
+pub trait Storage: Send {
+    /// Whatever was stored before the restart: ballot, floor and pvalues.
+    fn load(&mut self) -> (Ballot, usize, Vec<Proposal>);
+    /// A new ballot was promised.
+    fn promise(&mut self, ballot: Ballot);
+    /// A pvalue was accepted.
+    fn accept(&mut self, prop: &Proposal);
+    /// Nothing below slot `floor` matters any more. Pvalues below it go with the next compaction.
+    fn trim(&mut self, floor: usize);
+    /// Replace everything with just this. `accepted` had better be all that still matters.
+    fn compact(&mut self, ballot: Ballot, floor: usize, accepted: &[Proposal]);
+    /// Number of records written since the last compaction.
+    fn records(&self) -> usize;
+}
+
+/// One line of the write-ahead file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Record {
+    Promise(Ballot),
+    Accept(Proposal),
+    Trim(usize),
+}
+
+/// Keeps everything in memory. Nothing survives a restart.
+#[derive(Debug)]
+pub struct MemStorage {
+    ballot: Ballot,
+    floor: usize,
+    accepted: Vec<Proposal>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self {
+            ballot: Ballot::new(0, 0),
+            floor: 0,
+            accepted: vec![],
+        }
+    }
+}
+
+impl Default for MemStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemStorage {
+    fn load(&mut self) -> (Ballot, usize, Vec<Proposal>) {
+        (self.ballot, self.floor, self.accepted.clone())
+    }
+
+    fn promise(&mut self, ballot: Ballot) {
+        self.ballot = ballot;
+    }
+
+    fn accept(&mut self, prop: &Proposal) {
+        self.accepted.push(prop.clone());
+    }
+
+    fn trim(&mut self, floor: usize) {
+        self.floor = floor;
+    }
+
+    fn compact(&mut self, ballot: Ballot, floor: usize, accepted: &[Proposal]) {
+        self.ballot = ballot;
+        self.floor = floor;
+        self.accepted = accepted.to_vec();
+    }
+
+    fn records(&self) -> usize {
+        self.accepted.len()
+    }
+}
+
+/// Write-ahead file of JSON records, one per line.
+///
+/// Compaction writes a fresh file next to the old one and renames it over, so a crash mid-compaction loses nothing.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    records: usize,
+}
+
+impl FileStorage {
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::append_to(&path);
+        Self {
+            path,
+            file,
+            records: 0,
+        }
+    }
+
+    fn append_to(path: &Path) -> File {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap()
+    }
+
+    fn encode(rec: &Record) -> Vec<u8> {
+        let mut buf = serde_json::to_vec(rec).unwrap();
+        buf.push(b'\n');
+        buf
+    }
+
+    fn write(&mut self, rec: &Record) {
+        self.file.write_all(&Self::encode(rec)).unwrap();
+        self.file.sync_data().unwrap();
+        self.records += 1;
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> (Ballot, usize, Vec<Proposal>) {
+        let mut ballot = Ballot::new(0, 0);
+        let mut floor = 0;
+        let mut accepted = vec![];
+        let mut reader = BufReader::new(File::open(&self.path).unwrap());
+        let mut good = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            match serde_json::from_str::<Record>(&line) {
+                Ok(Record::Promise(b)) => ballot = ballot.max(b),
+                Ok(Record::Accept(p)) => accepted.push(p),
+                Ok(Record::Trim(f)) => floor = floor.max(f),
+                // Torn write. Everything after this never made it.
+                Err(_) => break,
+            }
+            good += line.len() as u64;
+            self.records += 1;
+            line.clear();
+        }
+        // Chop off the torn bit, so new records don't get glued onto it.
+        self.file.set_len(good).unwrap();
+        accepted.retain(|p: &Proposal| p.slot >= floor);
+        (ballot, floor, accepted)
+    }
+
+    fn promise(&mut self, ballot: Ballot) {
+        self.write(&Record::Promise(ballot));
+    }
+
+    fn accept(&mut self, prop: &Proposal) {
+        self.write(&Record::Accept(prop.clone()));
+    }
+
+    fn trim(&mut self, floor: usize) {
+        self.write(&Record::Trim(floor));
+    }
+
+    fn compact(&mut self, ballot: Ballot, floor: usize, accepted: &[Proposal]) {
+        let tmp = self.path.with_extension("compact");
+        {
+            let mut out = File::create(&tmp).unwrap();
+            out.write_all(&Self::encode(&Record::Promise(ballot)))
+                .unwrap();
+            out.write_all(&Self::encode(&Record::Trim(floor)))
+                .unwrap();
+            for p in accepted {
+                out.write_all(&Self::encode(&Record::Accept(p.clone())))
+                    .unwrap();
+            }
+            out.sync_all().unwrap();
+        }
+        fs::rename(&tmp, &self.path).unwrap();
+        // The rename is only durable once the directory is.
+        let dir = self.path.parent().filter(|d| !d.as_os_str().is_empty());
+        File::open(dir.unwrap_or(Path::new("."))).unwrap().sync_all().unwrap();
+        self.file = Self::append_to(&self.path);
+        self.records = accepted.len() + 2;
+    }
+
+    fn records(&self) -> usize {
+        self.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::paxos::{Command, Value};
+
+    /// A fresh directory for one test, gone once it's done.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("paxos-storage-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn wal(&self) -> PathBuf {
+            self.0.join("wal")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn pvalue(ballot: Ballot, slot: usize) -> Proposal {
+        let op = Raw::from(format!("op {slot}"));
+        let value = Value::Command(Command { client_id: 0, op_id: slot, op });
+        Proposal { slot, ballot, value }
+    }
+
+    #[test]
+    fn everything_survives_a_restart() {
+        let dir = TempDir::new("restart");
+        let (old, new) = (Ballot::new(1, 1), Ballot::new(2, 0));
+        let mut s = FileStorage::open(dir.wal());
+        s.load();
+        s.promise(old);
+        s.accept(&pvalue(old, 0));
+        s.accept(&pvalue(old, 1));
+        s.promise(new);
+        s.accept(&pvalue(new, 2));
+        s.trim(1);
+        drop(s);
+
+        let mut s = FileStorage::open(dir.wal());
+        let (ballot, floor, accepted) = s.load();
+        assert_eq!((ballot, floor), (new, 1));
+        assert_eq!(accepted, [pvalue(old, 1), pvalue(new, 2)]);
+        assert_eq!(s.records(), 6);
+    }
+
+    #[test]
+    fn torn_last_record_is_dropped() {
+        let dir = TempDir::new("torn");
+        let ballot = Ballot::new(1, 1);
+        let mut s = FileStorage::open(dir.wal());
+        s.load();
+        s.promise(ballot);
+        s.accept(&pvalue(ballot, 0));
+        drop(s);
+        // Dies halfway through the next record.
+        let mut f = OpenOptions::new().append(true).open(dir.wal()).unwrap();
+        let rec = FileStorage::encode(&Record::Accept(pvalue(ballot, 1)));
+        f.write_all(&rec[..rec.len() / 2]).unwrap();
+        drop(f);
+
+        let mut s = FileStorage::open(dir.wal());
+        let (_, _, accepted) = s.load();
+        assert_eq!(accepted, [pvalue(ballot, 0)]);
+
+        // What comes after isn't glued onto the torn bit.
+        s.accept(&pvalue(ballot, 2));
+        drop(s);
+        let (_, _, accepted) = FileStorage::open(dir.wal()).load();
+        assert_eq!(accepted, [pvalue(ballot, 0), pvalue(ballot, 2)]);
+    }
+
+    #[test]
+    fn compaction_keeps_only_what_it_is_given() {
+        let dir = TempDir::new("compact");
+        let (old, new) = (Ballot::new(1, 1), Ballot::new(2, 0));
+        let mut s = FileStorage::open(dir.wal());
+        s.load();
+        s.promise(old);
+        for slot in 0..4 {
+            s.accept(&pvalue(old, slot));
+        }
+        s.promise(new);
+        s.compact(new, 2, &[pvalue(old, 2), pvalue(old, 3)]);
+        assert_eq!(s.records(), 4);
+        assert!(!dir.wal().with_extension("compact").exists());
+        // Appends go to the new file.
+        s.accept(&pvalue(new, 3));
+        drop(s);
+
+        let mut s = FileStorage::open(dir.wal());
+        let (ballot, floor, accepted) = s.load();
+        assert_eq!((ballot, floor), (new, 2));
+        assert_eq!(accepted, [pvalue(old, 2), pvalue(old, 3), pvalue(new, 3)]);
+        assert_eq!(s.records(), 5);
+    }
+}
@@ -0,0 +1,100 @@
+//! Client for a Raft cluster.
+//!
+//! Only the leader can take a request. Followers pass requests on if they know who it is,
+//! but the answer always comes back from the leader, so whoever answers is who we talk to next time.
+//! A try that times out goes to the next server along instead.
+
+use std::{marker::PhantomData, net::SocketAddr};
+
+use serde_json::{from_slice, to_vec};
+
+use crate::{
+    client::{self, ClientError, Options, Outcome, Protocol},
+    config::{ClusterConfig, Role},
+    kv::{KvOp, KvResult},
+    Payload,
+};
+
+use super::{Command, Message};
+
+/// `O` and `R` are the replicated state machine's ops and outputs.
+pub struct Raft<O = KvOp, R = KvResult> {
+    /// Where responses go. Part of every command.
+    addr: SocketAddr,
+    servers: Vec<SocketAddr>,
+    /// Best guess at the leader, as an index into `servers`.
+    leader: usize,
+    /// Try that last moved `leader` along, so a fan of resends moves it once.
+    moved_at: usize,
+    _payload: PhantomData<fn() -> (O, R)>,
+}
+
+impl<O: Payload, R: Payload> Protocol for Raft<O, R> {
+    type Op = O;
+    type Output = R;
+
+    fn request(&self, op_id: usize, op: &O) -> Vec<u8> {
+        to_vec(&Message::<O, R>::Request(Command {
+            client: self.addr,
+            op_id,
+            op: op.clone(),
+        }))
+        .unwrap()
+    }
+
+    fn response(&self, buf: &[u8]) -> Option<(usize, Outcome<R>)> {
+        match from_slice::<Message<O, R>>(buf) {
+            Ok(Message::Response(op_id, res)) => Some((op_id, Ok(res))),
+            Ok(Message::Expired(op_id)) => Some((op_id, Err(ClientError::Expired))),
+            _ => None,
+        }
+    }
+
+    fn targets(&mut self, attempt: usize) -> Vec<SocketAddr> {
+        if attempt > self.moved_at {
+            self.leader = (self.leader + 1) % self.servers.len();
+        }
+        self.moved_at = attempt;
+        vec![self.servers[self.leader]]
+    }
+
+    fn heard_from(&mut self, from: SocketAddr) {
+        if let Some(i) = self.servers.iter().position(|s| *s == from) {
+            self.leader = i;
+            self.moved_at = 0;
+        }
+    }
+}
+
+impl<O, R> Raft<O, R> {
+    /// Client `client_id` of the cluster in `cfg`, starting off guessing `leader`. Any number will do, it wraps around.
+    pub fn new(cfg: &ClusterConfig, client_id: usize, leader: usize) -> Self {
+        let servers = cfg
+            .nodes(Role::Raft)
+            .filter(|n| !n.standby)
+            .map(|n| n.addr)
+            .collect::<Vec<_>>();
+        Self::with_servers(cfg.client_addr(client_id), servers, leader)
+    }
+
+    /// The client listening on `addr`, for a cluster of `servers`, starting off guessing `leader`.
+    pub fn with_servers(addr: SocketAddr, servers: Vec<SocketAddr>, leader: usize) -> Self {
+        Self {
+            addr,
+            leader: leader % servers.len(),
+            servers,
+            moved_at: 0,
+            _payload: PhantomData,
+        }
+    }
+}
+
+pub type Client = client::Client<Raft>;
+
+impl Client {
+    /// Client `client_id`, listening on its address from the config. Starts off guessing a random server is the leader.
+    pub fn new(cfg: &ClusterConfig, client_id: usize, opts: Options) -> Self {
+        let proto = Raft::new(cfg, client_id, rand::random());
+        Self::start(client_id, cfg.client_addr(client_id), proto, opts)
+    }
+}
@@ -1,60 +1,53 @@
 #![allow(dead_code)]
-use std::{
-    net::SocketAddr,
-    thread::{self, JoinHandle},
-};
+use std::thread::{self, JoinHandle};
 
 use hashbrown::HashMap;
-use message_io::{
-    network::{Endpoint, Transport},
-    node::NodeHandler,
-};
+use message_io::network::Endpoint;
 
-use crate::LOOPBACK;
-
-use super::server;
+use crate::{
+    config::{ClusterConfig, Role},
+    faults::Faults,
+    invariants::Invariants,
+    net::Network,
+    StateMachine,
+};
 
-pub const RAFT_PORT: u16 = 9000;
-pub const RAFT_COUNT: usize = 5;
+use super::{server, storage::MemStorage, Options};
 
-pub fn get_peers<Y>(id: usize, handler: NodeHandler<Y>) -> HashMap<usize, Endpoint> {
-    (0..RAFT_COUNT)
-        .filter(|&i| i != id)
-        .map(|i| {
-            let out = handler
-                .network()
-                .connect(
-                    Transport::Udp,
-                    SocketAddr::from((LOOPBACK, RAFT_PORT + i as u16)),
-                )
-                .unwrap();
+pub fn get_peers(cfg: &ClusterConfig, id: usize, net: &dyn Network) -> HashMap<usize, Endpoint> {
+    cfg.nodes(Role::Raft)
+        .filter(|n| n.id != id)
+        .map(|n| {
+            let out = net.connect(n.addr);
             // dbg!(&out);
-            (i, out.0)
+            (n.id, out)
         })
         .collect()
 }
 
-/* pub fn get_all_servers(handler: &NodeHandler<()>) -> Vec<Endpoint> {
-    (0..RAFT_COUNT)
-        .map(|i| {
-            let out = handler
-                .network()
-                .connect(
-                    Transport::Udp,
-                    SocketAddr::from((LOOPBACK, RAFT_PORT + i as u16)),
-                )
-                .unwrap();
-            // dbg!(&out);
-            out.0
-        })
-        .collect()
-}
- */
-pub fn raft_init() -> Vec<JoinHandle<()>> {
+/// All the servers in the config, each in its own thread, with nothing on disk.
+/// Everything they send goes past `faults`, and they report to `invariants`, if given.
+pub fn raft_init<S>(
+    cfg: &ClusterConfig,
+    faults: Option<Faults>,
+    invariants: Option<Invariants<S::Op>>,
+) -> Vec<JoinHandle<()>>
+where
+    S: StateMachine,
+{
     let mut out = vec![];
-    for i in 0..RAFT_COUNT {
+    for i in cfg.ids(Role::Raft) {
+        let cfg = cfg.clone();
+        let (faults, invariants) = (faults.clone(), invariants.clone());
         out.push(thread::spawn(move || {
-            server::run(i, SocketAddr::from((LOOPBACK, RAFT_PORT + i as u16)));
+            server::run::<S>(
+                i,
+                &cfg,
+                Box::new(MemStorage::new()),
+                Options::default(),
+                faults.as_ref(),
+                invariants.as_ref(),
+            );
         }));
     }
 
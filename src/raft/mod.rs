@@ -1,26 +1,45 @@
 #![allow(dead_code)]
-use std::net::SocketAddr;
+use std::{collections::BTreeMap, net::SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
-// use crate::paxos::Command;
+use crate::{
+    kv::{KvOp, KvResult},
+    session::SessionTable,
+};
 
 // use self::server::{Campaign, Replicate};
 
+pub mod client;
 pub mod dir;
 pub mod server;
+pub mod storage;
 
+/// `O` is whatever the state machine takes (`StateMachine::Op`). Everything here is generic over it, and over what
+/// the state machine answers, with the key-value store's as the default.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct Command {
+pub struct Command<O = KvOp> {
     pub client: SocketAddr,
     pub op_id: usize,
-    pub op: String, // Small
+    pub op: O,
 }
 
+/// The voting members of the cluster, and where to find them.
+pub type Membership = BTreeMap<usize, SocketAddr>;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct Log {
+pub struct Log<O = KvOp> {
     term: usize,
-    command: Option<Command>,
+    command: Option<Command<O>>,
+    /// A configuration change. The new set of voters takes effect as soon as this is in the log.
+    #[serde(default)]
+    config: Option<Membership>,
+}
+
+impl<O> Log<O> {
+    pub fn term(&self) -> usize {
+        self.term
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -28,20 +47,122 @@ pub struct Reply {
     pub from: usize,
     pub success: bool,
     pub term: usize,
+    /// Round of the heartbeat being answered. 0 for anything else.
+    #[serde(default)]
+    pub seq: usize,
+    /// Last index known to match the leader's log, as of this reply. Only means anything on a successful append.
+    #[serde(default)]
+    pub match_index: usize,
+    /// Failed append only. Where the leader should try next: the first index of `conflict_term` in our log, or one
+    /// past our last entry if `conflict_term` is None. 0 if there's no hint, and the leader steps back by one.
+    #[serde(default)]
+    pub conflict_index: usize,
+    /// The term we have at the leader's `prev_log_index`, if we have anything there at all.
+    #[serde(default)]
+    pub conflict_term: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Message {
-    Request(Command),
-    Response(Command),
-    Heartbeat(Replicate),
+pub enum Message<O = KvOp, R = KvResult> {
+    Request(Command<O>),
+    Response(usize, R), // op id, result
+    Expired(usize),     // op id. Too old to apply, and whether it ever was has been forgotten.
+    Heartbeat(Replicate<O>),
     Campaign(Campaign),
     ServerReply(Reply),
+    /// Would you vote for me? `term` is the one we'd run in. Nobody changes term over it.
+    PreVote(Campaign),
+    PreVoteReply(Reply),
+    InstallSnapshot(InstallSnapshot<R>),
+
+    // Admin. Sent to any server, they end up at the leader.
+    /// Add a server. It catches up as a non-voting learner first.
+    AddServer(usize, SocketAddr),
+    RemoveServer(usize),
+    /// Hand leadership over to this server, e.g. before taking the leader down.
+    TransferLeadership(usize),
+
+    /// Leader to the server it's handing over to: campaign now, don't wait for the timeout. Contains the leader's term.
+    TimeoutNow(usize),
+}
+
+/// State machine as of some log index. Everything up to and including that index can be thrown away.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Snapshot<R = KvResult> {
+    last_included_index: usize,
+    last_included_term: usize,
+    data: Vec<u8>,
+    /// Voters as of `last_included_index`.
+    #[serde(default)]
+    config: Membership,
+    /// Sessions as of `last_included_index`. Not plain `default`: serde would want R: Default.
+    #[serde(default = "SessionTable::default")]
+    sessions: SessionTable<SocketAddr, R>,
+}
+
+// Not derived: that would want R: Default.
+impl<R> Default for Snapshot<R> {
+    fn default() -> Self {
+        Self {
+            last_included_index: 0,
+            last_included_term: 0,
+            data: vec![],
+            config: Membership::new(),
+            sessions: SessionTable::default(),
+        }
+    }
+}
+
+/// Sent instead of entries when a follower needs something we've already compacted away.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshot<R = KvResult> {
+    term: usize,
+    leader_id: usize,
+    snapshot: Snapshot<R>,
+}
+
+/// How read-only ops (`StateMachine::is_read_only`) are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Through the log, like everything else.
+    Log,
+    /// ReadIndex: note the commit index, check we're still leader with a round of heartbeats, answer once applied.
+    ReadIndex,
+    /// Skip the heartbeat round while a majority has heard from us within the election timeout.
+    /// Only safe if clocks don't drift more than `Options::max_drift`.
+    Lease,
+}
+
+/// Knobs for a Raft server.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Take a snapshot once this many applied entries have piled up in the log.
+    pub snapshot_threshold: usize,
+    pub read_mode: ReadMode,
+    /// Worst relative clock drift between servers that `ReadMode::Lease` puts up with. The lease is this much shorter.
+    pub max_drift: f64,
+    /// Most entries in one AppendEntries.
+    pub max_entries: usize,
+    /// Most unacknowledged AppendEntries to one follower. Past that it only gets bare heartbeats till it catches up.
+    pub max_inflight: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            snapshot_threshold: 1000,
+            read_mode: ReadMode::ReadIndex,
+            max_drift: 0.1,
+            max_entries: 64,
+            max_inflight: 4,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum ServerState {
     Follower,
+    PreCandidate(usize), // Checking we could win before bumping the term. Contains number of pre-votes
     Candidate(usize), // Contains number of votes
     Leader,
 }
@@ -53,12 +174,15 @@ pub struct Heartbeat {
     prev_log_index: usize,
     prev_log_term: usize,
     leader_commit: usize,
+    /// Heartbeat round, for confirming leadership. Goes up by one with every heartbeat the leader sends out.
+    #[serde(default)]
+    seq: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Replicate {
+pub struct Replicate<O = KvOp> {
     hb: Heartbeat,
-    entries: Vec<(usize, Log)>,
+    entries: Vec<(usize, Log<O>)>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,6 +191,9 @@ pub struct Campaign {
     candidate_id: usize,
     last_log_index: usize,
     last_log_term: usize,
+    /// The leader asked for this election (TimeoutNow), so don't hold out for it.
+    #[serde(default)]
+    transfer: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
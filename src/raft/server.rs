@@ -1,448 +1,1933 @@
 #![allow(dead_code)]
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use message_io::{
-    events::TimerId,
-    network::{Endpoint, NetEvent, Transport},
-    node::{self, NodeEvent, NodeHandler},
+    network::{Endpoint, NetEvent, ResourceId, Transport},
+    node::{self, NodeEvent},
 };
-use rand::distributions::{Distribution, Uniform};
 use serde_json::{from_slice, to_vec};
 
-use crate::ReplicaState;
+use crate::{
+    config::{ClusterConfig, Role},
+    faults::{self, Faults},
+    invariants::{Invariants, RaftView},
+    net::{Io, TimerId},
+    session::SessionTable,
+    StateMachine,
+};
 
 use super::{
-    dir::get_peers, Campaign, Heartbeat, Log, Message, Replicate, Reply, ServerState,
-    Timer,
+    dir::get_peers,
+    storage::{HardState, Storage},
+    Campaign, Command, Heartbeat, InstallSnapshot, Log, Membership, Message, Options, ReadMode, Replicate,
+    Reply, ServerState, Snapshot, Timer,
 };
 
-pub struct Server {
+/// Election timeouts are picked uniformly from this range, in ms.
+const ELECTION_MIN: f64 = 150.0;
+const ELECTION_MAX: f64 = 300.0;
+/// How often the leader sends heartbeats.
+const HEARTBEAT: Duration = Duration::from_millis(50);
+/// Appends not acked in this long are taken as lost, and sent again.
+const RESEND_AFTER: Duration = Duration::from_millis(200);
+
+/// A read-only op, waiting to be answered without going through the log.
+#[derive(Debug)]
+struct Read<O> {
+    /// Heartbeat round that has to come back from a majority first. 0 if the lease already covers it.
+    round: usize,
+    /// Commit index when it came in. Has to be applied first.
+    index: usize,
+    cmd: Command<O>,
+}
+
+pub struct Server<S: StateMachine> {
     id: usize,
     state: ServerState,                 // Look at enum variants
-    rst: ReplicaState,                  // State of the replica
+    rst: S,                             // State of the replica
+    sessions: SessionTable<SocketAddr, S::Output>, // Who's done what. Replicated along with rst.
     current_term: usize,                
-    voted_for: Option<usize>,           // Leader election.
-    log: Vec<Log>,                      // Replica<index, Log<term, ACTUAL SHIT>>
+    voted_for: Option<usize>,           // Leader election. Who got our vote this term, for the whole term.
+    leader: Option<usize>,              // Who leads this term, if we've heard. Requests go there.
+    log: Vec<Log<S::Op>>,               // Replica<index, Log<term, ACTUAL SHIT>>, starting at the snapshot
+    snapshot: Snapshot<S::Output>,      // Everything up to here is compacted away. log[0] stands in for it.
     commit_index: usize,                // index of highest committed entry
     last_applied: usize,                // index of highest applied entry
     next_index: HashMap<usize, usize>,  // index of next log entry to send to each server
     match_index: HashMap<usize, usize>, // index of highest log entry known to be replicated on server
+    inflight: HashMap<usize, VecDeque<(usize, usize, Instant)>>, // Leader only. Unacked appends to each server: last index, round, when sent.
+    voters: Membership,                 // Who gets a vote. Latest config in the log, committed or not.
+    config_index: usize,                // Where that config came from
+    learners: Membership,               // Leader only. Being caught up before they get a vote.
+    removals: Vec<usize>,               // Leader only. Waiting for the previous change to commit.
+    seq: usize,                         // Leader only. Last heartbeat round sent.
+    acked: HashMap<usize, usize>,       // Leader only. Latest round each server has answered this term.
+    sent_at: VecDeque<(usize, Instant)>, // Leader only. When rounds not yet acked by a majority went out.
+    lease: Option<Instant>,             // Leader only. Nobody else can be leader before this.
+    reads: Vec<Read<S::Op>>,            // Leader only. Reads waiting on a heartbeat round or on apply.
+    leased: usize,                      // Reads answered off the lease, no heartbeat round needed. Just a count.
+    heard_at: Option<Instant>,          // Last time a leader's heartbeat got through to us.
+    contact: HashMap<usize, Instant>,   // Leader only. Last time each server answered us, for CheckQuorum.
+    transfer: Option<(usize, Instant)>, // Leader only. Handing over to this server, since then. No new requests meanwhile.
+    votes: HashSet<usize>,              // Candidate only. Who's said yes this time round. Replies can come in twice.
 
-    u: rand::distributions::Uniform<f64>,
-    handler: NodeHandler<Timer>,
+    io: Box<dyn Io<Timer>>,
     peers: HashMap<usize, Endpoint>,
     clients: HashMap<SocketAddr, Endpoint>,
+    /// Our listening socket, on a real network. Clients only know us by its address, so answers go out of it.
+    listener: Option<ResourceId>,
     current_timer: Option<TimerId>,
-    pending: Vec<Message>,
+    pending: Vec<Message<S::Op, S::Output>>,
+
+    storage: Box<dyn Storage<S::Op, S::Output>>, // Stable storage for term, vote and log
+    hard: HardState,                    // What storage last saw
+    opts: Options,
 }
 
-impl Server {
-    fn new(id: usize, peers: HashMap<usize, Endpoint>, handler: NodeHandler<Timer>) -> Self {
+impl<S> Server<S>
+where
+    S: StateMachine,
+{
+    fn new(
+        id: usize,
+        peers: HashMap<usize, Endpoint>,
+        io: Box<dyn Io<Timer>>,
+        mut storage: Box<dyn Storage<S::Op, S::Output>>,
+        opts: Options,
+        initial: Membership,
+    ) -> Self {
+        // Whatever we had before the crash, if anything.
+        let (hard, mut snapshot, entries) = storage.load();
+        if snapshot.config.is_empty() {
+            // Never snapshotted, so still on the config file's idea of who's in.
+            snapshot.config = initial;
+        }
+        let mut log = vec![Log {
+            term: snapshot.last_included_term,
+            command: None,
+            config: None,
+        }];
+        log.extend(entries);
+        let mut rst = S::default();
+        if snapshot.last_included_index > 0 {
+            rst.restore(&snapshot.data);
+        }
+        let sessions = snapshot.sessions.clone();
+
         let mut out = Self {
             id,
             state: ServerState::Follower,
-            rst: ReplicaState::default(),
-            current_term: 0,
-            voted_for: None,
-            log: vec![Log {
-                term: 0,
-                command: None,
-            }],
-            commit_index: 0,
-            last_applied: 0,
-            next_index: peers.iter().map(|(a, _b)| (*a, 1)).collect::<HashMap<_, _>>(),
-            match_index: peers.iter().map(|(a, _b)| (*a, 0)).collect::<HashMap<_, _>>(),
-            u: Uniform::new(150.0, 300.0),
-            handler,
+            rst,
+            sessions,
+            current_term: hard.term,
+            voted_for: hard.voted_for,
+            leader: None,
+            log,
+            // Snapshots only ever cover committed entries.
+            commit_index: snapshot.last_included_index,
+            last_applied: snapshot.last_included_index,
+            snapshot,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            inflight: HashMap::new(),
+            voters: Membership::new(),
+            config_index: 0,
+            learners: Membership::new(),
+            removals: vec![],
+            seq: 0,
+            acked: HashMap::new(),
+            sent_at: VecDeque::new(),
+            lease: None,
+            reads: vec![],
+            leased: 0,
+            heard_at: None,
+            contact: HashMap::new(),
+            transfer: None,
+            votes: HashSet::new(),
+            io,
             peers,
             clients: HashMap::new(),
+            listener: None,
             current_timer: None,
             pending: vec![],
+            storage,
+            hard,
+            opts,
         };
 
+        out.refresh_config();
+
         // Start the timeouts.
         out.reset_timeout();
         out.reset_heartbeat();
         out
     }
 
-    fn empty_decree(&self) {
-        let hb = Heartbeat {
+    /// Server `id` of the cluster in `cfg`. Standbys start outside it, and wait to be added.
+    pub fn start(id: usize, cfg: &ClusterConfig, io: Box<dyn Io<Timer>>, storage: Box<dyn Storage<S::Op, S::Output>>, opts: Options) -> Self {
+        let peers = get_peers(cfg, id, &*io);
+        let initial = cfg
+            .nodes(Role::Raft)
+            .filter(|n| !n.standby)
+            .map(|n| (n.id, n.addr))
+            .collect();
+        Self::new(id, peers, io, storage, opts, initial)
+    }
+
+    /// Answer clients out of `listener`. Otherwise they'd come from some other socket, and a client would never
+    /// know a forwarded request was answered by the leader.
+    pub fn reply_from(&mut self, listener: ResourceId) {
+        self.listener = Some(listener);
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state == ServerState::Leader
+    }
+
+    /// Who gets a vote, as of the latest config in our log.
+    pub fn voters(&self) -> &Membership {
+        &self.voters
+    }
+
+    /// Whether that config has been committed yet.
+    pub fn config_committed(&self) -> bool {
+        self.config_index <= self.commit_index
+    }
+
+    /// Index of the highest committed entry.
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    /// How many reads we've answered off the lease, without waiting on a heartbeat round.
+    pub fn leased(&self) -> usize {
+        self.leased
+    }
+
+    /// Show `inv` where we're at.
+    pub fn observe(&self, inv: &Invariants<S::Op>) {
+        let view = RaftView {
             term: self.current_term,
-            leader_id: self.id,
-            prev_log_index: self.log.len() - 1,
-            prev_log_term: self.log.last().unwrap().term,
-            leader_commit: self.commit_index,
+            leader: self.is_leader(),
+            commit_index: self.commit_index,
+            first: self.snapshot.last_included_index,
+            log: &self.log,
         };
+        inv.raft(self.id, view);
+    }
+
+    /// Index of the last entry in the log.
+    fn last_index(&self) -> usize {
+        self.snapshot.last_included_index + self.log.len() - 1
+    }
+
+    /// Where log index `i` lives in `self.log`.
+    fn pos(&self, i: usize) -> usize {
+        i - self.snapshot.last_included_index
+    }
+
+    fn term_at(&self, i: usize) -> usize {
+        self.log[self.pos(i)].term
+    }
+
+    /// The config in effect at log index `i`, and the index it came from.
+    fn config_at(&self, i: usize) -> (usize, Membership) {
+        let base = self.snapshot.last_included_index;
+        self.log[1..=self.pos(i)]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(p, l)| l.config.clone().map(|c| (base + p + 1, c)))
+            .unwrap_or_else(|| (base, self.snapshot.config.clone()))
+    }
+
+    /// Pick up the latest config in the log. Call whenever a config entry might have come or gone.
+    fn refresh_config(&mut self) {
+        (self.config_index, self.voters) = self.config_at(self.last_index());
+        // Connect to anyone new.
+        for (id, addr) in self.voters.iter().chain(self.learners.iter()) {
+            if *id != self.id && !self.peers.contains_key(id) {
+                let ep = self.io.connect(*addr);
+                self.peers.insert(*id, ep);
+            }
+        }
+        self.sync_targets();
+    }
 
-        for p in self.peers.iter() {
-            self.handler.network().send(
-                *p.1,
-                &to_vec(&Message::Heartbeat(Replicate {
-                    hb,
-                    entries: vec![],
-                }))
-                .unwrap(),
-            );
+    /// Replicate to exactly the voters and learners.
+    fn sync_targets(&mut self) {
+        let targets = self
+            .voters
+            .keys()
+            .chain(self.learners.keys())
+            .filter(|i| **i != self.id)
+            .copied()
+            .collect::<Vec<_>>();
+        let next = self.last_index() + 1;
+        self.next_index.retain(|i, _| targets.contains(i));
+        self.match_index.retain(|i, _| targets.contains(i));
+        self.inflight.retain(|i, _| targets.contains(i));
+        for t in targets {
+            self.next_index.entry(t).or_insert(next);
+            self.match_index.entry(t).or_insert(0);
         }
-        self.reset_heartbeat();
     }
 
-    fn decree(&self) {
-        let mut hb = Heartbeat {
+    /// Votes (or acks) needed to get anything done.
+    fn quorum(&self) -> usize {
+        self.voters.len() / 2 + 1
+    }
+
+    /// Leader side. One membership change at a time: the next one only starts once the last is committed.
+    fn reconfigure(&mut self) {
+        if self.state != ServerState::Leader || self.config_index > self.commit_index {
+            return;
+        }
+        let mut voters = self.voters.clone();
+        // Learners get a vote once they've caught up with everything committed.
+        let ready = self
+            .learners
+            .iter()
+            .find(|(i, _)| self.match_index[*i] >= self.commit_index)
+            .map(|(i, a)| (*i, *a));
+        if let Some((id, addr)) = ready {
+            self.learners.remove(&id);
+            voters.insert(id, addr);
+        } else if let Some(id) = self.removals.pop() {
+            voters.remove(&id);
+        } else {
+            return;
+        }
+        println!("Raft server {} reconfiguring to {:?}", self.id, voters.keys());
+        self.push(Log {
+            term: self.current_term,
+            command: None,
+            config: Some(voters),
+        });
+        self.decree();
+    }
+
+    fn send(&self, to: Endpoint, msg: &Message<S::Op, S::Output>) {
+        self.io.send(to, &to_vec(msg).unwrap());
+    }
+
+    /// Not the leader. Pass it on if we know who is, otherwise hold on to it.
+    fn forward(&mut self, msg: Message<S::Op, S::Output>, buf: &[u8]) {
+        let leader = self.leader.and_then(|l| self.peers.get(&l));
+        match (self.state, leader) {
+            (ServerState::Follower, Some(leader)) => {
+                self.io.send(*leader, buf);
+            }
+            _ => self.pending.push(msg),
+        }
+    }
+
+    /// Start a new heartbeat round.
+    fn next_round(&mut self) -> usize {
+        self.seq += 1;
+        self.sent_at.push_back((self.seq, self.io.now()));
+        self.seq
+    }
+
+    /// Our last index with term `t`, if any of it is still in the log.
+    fn last_of_term(&self, t: usize) -> Option<usize> {
+        (self.snapshot.last_included_index + 1..=self.last_index())
+            .rev()
+            .take_while(|i| self.term_at(*i) >= t)
+            .find(|i| self.term_at(*i) == t)
+    }
+
+    /// A round of AppendEntries to everyone, which is also the heartbeat. Each gets whatever it's due, see `replicate`.
+    fn decree(&mut self) {
+        let seq = self.next_round();
+        let targets = self.next_index.keys().copied().collect::<Vec<_>>();
+        for p in targets {
+            self.replicate(p, seq);
+        }
+    }
+
+    /// Send `p` what it's due without flooding it. Up to `max_entries` from `next_index` if it has room in its
+    /// pipeline, a bare heartbeat if it doesn't (or is caught up). `next_index` moves on as soon as entries go out.
+    fn replicate(&mut self, p: usize, seq: usize) {
+        let ep = self.peers[&p];
+        let next = self.next_index[&p];
+        let queued = self.inflight.get(&p).map_or(0, |q| q.len());
+
+        // Too far behind, what they need is gone. Send the snapshot instead, one at a time.
+        if next <= self.snapshot.last_included_index {
+            if queued == 0 {
+                let upto = self.snapshot.last_included_index;
+                self.inflight.entry(p).or_default().push_back((upto, seq, self.io.now()));
+                self.next_index.insert(p, upto + 1);
+                self.send(
+                    ep,
+                    &Message::InstallSnapshot(InstallSnapshot {
+                        term: self.current_term,
+                        leader_id: self.id,
+                        snapshot: self.snapshot.clone(),
+                    }),
+                );
+            }
+            return;
+        }
+
+        let (prev, entries) = if queued < self.opts.max_inflight && next <= self.last_index() {
+            let upto = self.last_index().min(next + self.opts.max_entries.max(1) - 1);
+            let entries = (next..=upto).map(|i| (i, self.log[self.pos(i)].clone())).collect::<Vec<_>>();
+            self.inflight.entry(p).or_default().push_back((upto, seq, self.io.now()));
+            self.next_index.insert(p, upto + 1);
+            (next - 1, entries)
+        } else if queued == 0 {
+            (next - 1, vec![])
+        } else {
+            // Whatever's in flight might not have landed yet. Only vouch for what we know they have.
+            (self.match_index[&p], vec![])
+        };
+        if prev < self.snapshot.last_included_index {
+            // Don't know the term any more. The resend timeout gets them a snapshot.
+            return;
+        }
+
+        let hb = Heartbeat {
             term: self.current_term,
             leader_id: self.id,
-            prev_log_index: 0,
-            prev_log_term: 0,
+            prev_log_index: prev,
+            prev_log_term: self.term_at(prev),
             leader_commit: self.commit_index,
+            seq,
         };
+        self.send(ep, &Message::Heartbeat(Replicate { hb, entries }));
+    }
 
-        for p in self.peers.iter() {
-            let entries = self.log
-                .iter()
-                .enumerate().skip(self.next_index[p.0] - 1)
-                .map(|x| (x.0, x.1.clone()))
-                .collect::<Vec<_>>();
-            hb.prev_log_index = self.next_index[p.0] - 1;
-            hb.prev_log_term = self.log[hb.prev_log_index].term;
-            self.handler.network().send(
-                *p.1,
-                &to_vec(&Message::Heartbeat(Replicate { hb, entries })).unwrap(),
-            );
+    /// Appends that haven't been acked in `RESEND_AFTER` got lost (it's UDP). Start over from what we know they have.
+    fn expire_inflight(&mut self) {
+        let now = self.io.now();
+        for (p, q) in self.inflight.iter_mut() {
+            if q.front().is_some_and(|(_, _, at)| now - *at > RESEND_AFTER) {
+                q.clear();
+                self.next_index.insert(*p, self.match_index.get(p).copied().unwrap_or(0) + 1);
+            }
         }
-        self.reset_heartbeat();
     }
 
-    fn campaign(&mut self) {
+    /// Our last entry, as a candidate would describe it.
+    fn candidacy(&self, term: usize) -> Campaign {
+        Campaign {
+            term,
+            candidate_id: self.id,
+            last_log_index: self.last_index(),
+            last_log_term: self.log.last().unwrap().term,
+            transfer: false,
+        }
+    }
+
+    /// Is `c`'s log at least as up to date as ours?
+    fn log_ok(&self, c: &Campaign) -> bool {
+        (c.last_log_term, c.last_log_index) >= (self.log.last().unwrap().term, self.last_index())
+    }
+
+    /// Heard from a leader recently enough that it's probably still around.
+    fn leader_alive(&self) -> bool {
+        matches!(self.heard_at, Some(t) if (self.io.now() - t) < Duration::from_millis(ELECTION_MIN as u64))
+    }
+
+    /// PreVote. Ask around before bumping the term, so a server that can't win (cut off, or behind) doesn't disrupt anyone.
+    fn pre_campaign(&mut self) {
+        if self.quorum() <= 1 {
+            self.campaign(false);
+            return;
+        }
+        self.state = ServerState::PreCandidate(1);
+        self.votes.clear();
+        let cp = self.candidacy(self.current_term + 1);
+        for p in self.voters.keys().filter(|i| **i != self.id) {
+            self.send(self.peers[p], &Message::PreVote(cp.clone()));
+        }
+        self.reset_timeout();
+    }
+
+    /// CheckQuorum. A leader that can't reach a majority steps down, instead of hanging on to clients it can't serve.
+    fn check_quorum(&mut self) {
+        let window = Duration::from_millis(ELECTION_MAX as u64);
+        let heard = self
+            .voters
+            .keys()
+            .filter(|v| **v == self.id || matches!(self.contact.get(*v), Some(t) if (self.io.now() - *t) < window))
+            .count();
+        self.abandon_transfer();
+        if heard < self.quorum() {
+            println!("Raft server {} lost quorum in term {}, stepping down", self.id, self.current_term);
+            // Still our vote this term, though. Giving it to someone else now would make two leaders possible.
+            self.state = ServerState::Follower;
+            self.leader = None;
+            self.reads.clear();
+        }
+        self.reset_timeout();
+    }
+
+    /// Move up to `term`, if it's newer. Nobody has our vote in it yet, and we don't know who leads it.
+    fn advance(&mut self, term: usize) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+    }
+
+    /// `transfer` if the leader told us to (TimeoutNow).
+    fn campaign(&mut self, transfer: bool) {
         self.current_term += 1;
         self.voted_for = Some(self.id);
+        self.leader = None;
         self.state = ServerState::Candidate(1);
-        
+        self.votes.clear();
+
         let cp = Campaign {
-            term: self.current_term,
-            candidate_id: self.id,
-            last_log_index: self.log.len() - 1,
-            last_log_term: self.log.last().unwrap().term,
+            transfer,
+            ..self.candidacy(self.current_term)
         };
-        
-        for p in self.peers.iter() {
-            self.handler.network().send(*p.1, &to_vec(&Message::Campaign(cp.clone())).unwrap());
+        self.persist();
+
+        for p in self.voters.keys().filter(|i| **i != self.id) {
+            self.send(self.peers[p], &Message::Campaign(cp.clone()));
         }
         self.reset_timeout();
     }
 
     fn crown(&mut self) {
         self.state = ServerState::Leader;
-        self.voted_for = None;
-        // println!("Crowned {}", self.id);
-        self.empty_decree();
+        self.leader = Some(self.id);
+        // Whatever the last leader was in the middle of is lost.
+        self.learners.clear();
+        self.removals.clear();
+        self.acked.clear();
+        self.sent_at.clear();
+        self.lease = None;
+        self.reads.clear();
+        self.transfer = None;
+        // Everyone gets the benefit of the doubt for the first election timeout.
+        let now = self.io.now();
+        self.contact = self.voters.keys().map(|v| (*v, now)).collect();
+        self.sync_targets();
+        // Whatever we knew from last time we led may be out of date. Probe from the end of our log.
+        let next = self.last_index() + 1;
         for (_a, b) in self.next_index.iter_mut() {
-            *b = self.log.len();
+            *b = next;
+        }
+        for (_a, b) in self.match_index.iter_mut() {
+            *b = 0;
         }
+        self.inflight.clear();
+        // println!("Crowned {}", self.id);
+        self.decree();
     }
 
+    /// Replaces whatever election timer was going. Left running, they'd pile up: every one that fires starts another.
     fn reset_timeout(&mut self) {
-        let rng = &mut rand::thread_rng();
-        self.current_timer = Some(self.handler.signals().send_with_timer(
-            Timer::Election,
-            Duration::from_millis(self.u.sample(rng) as u64),
-        ));
+        if let Some(t) = self.current_timer.take() {
+            self.io.cancel(t);
+        }
+        let ms = ELECTION_MIN + self.io.random() * (ELECTION_MAX - ELECTION_MIN);
+        self.current_timer = Some(self.io.timer(Timer::Election, Duration::from_millis(ms as u64)));
     }
 
+    /// Only ever one of these going, started in `new`.
     fn reset_heartbeat(&self) {
-        self.handler.signals().send_with_timer(Timer::Heartbeat, Duration::from_millis(50));
+        self.io.timer(Timer::Heartbeat, HEARTBEAT);
     }
 
-    fn reject(&mut self, ep: Endpoint) {
+    fn reject(&mut self, ep: Endpoint, seq: usize) {
+        self.persist();
         let rep = &Message::ServerReply(Reply {
             from: self.id,
             success: false,
             term: self.current_term,
+            seq,
+            match_index: 0,
+            conflict_index: 0,
+            conflict_term: None,
         });
-        self.handler.network().send(ep, &to_vec(rep).unwrap());
-        self.voted_for = None;
+        self.send(ep, rep);
+    }
+
+    /// Turn down an append that doesn't fit our log, saying how far back the leader should go.
+    /// A whole term at a time when it's a conflict, straight to the end of our log when we're just short.
+    fn mismatch(&mut self, ep: Endpoint, hb: &Heartbeat) {
+        let (conflict_index, conflict_term) = if self.last_index() < hb.prev_log_index {
+            (self.last_index() + 1, None)
+        } else {
+            let t = self.term_at(hb.prev_log_index);
+            let mut i = hb.prev_log_index;
+            while i > self.snapshot.last_included_index + 1 && self.term_at(i - 1) == t {
+                i -= 1;
+            }
+            (i, Some(t))
+        };
+        self.persist();
+        let rep = &Message::ServerReply(Reply {
+            from: self.id,
+            success: false,
+            term: self.current_term,
+            seq: hb.seq,
+            match_index: 0,
+            conflict_index,
+            conflict_term,
+        });
+        self.send(ep, rep);
     }
 
     fn vote(&mut self, ep: Endpoint, c: Campaign) {
         if let Some(t) = self.current_timer {
-            self.handler.signals().cancel_timer(t);
+            self.io.cancel(t);
         }
         self.current_term = c.term;
         self.voted_for = Some(c.candidate_id);
         self.state = ServerState::Follower;
+        // Never hand out a vote we might forget.
+        self.persist();
         let rep = &Message::ServerReply(Reply {
             from: self.id,
             success: true,
             term: self.current_term,
+            seq: 0,
+            match_index: 0,
+            conflict_index: 0,
+            conflict_term: None,
         });
-        self.handler.network().send(ep, &to_vec(rep).unwrap());
+        self.send(ep, rep);
         self.reset_timeout();
     }
 
-    fn accept(&self, ep: Endpoint) {
+    /// We have everything up to `match_index` that the leader does.
+    fn accept(&mut self, ep: Endpoint, seq: usize, match_index: usize) {
+        self.persist();
         let rep = &Message::ServerReply(Reply {
             from: self.id,
             success: true,
             term: self.current_term,
+            seq,
+            match_index,
+            conflict_index: 0,
+            conflict_term: None,
         });
-        self.handler.network().send(ep, &to_vec(rep).unwrap());
+        self.send(ep, rep);
+    }
+
+    /// Write term and vote to stable storage, if they changed. Call before replying to anyone.
+    fn persist(&mut self) {
+        let hard = HardState {
+            term: self.current_term,
+            voted_for: self.voted_for,
+        };
+        if hard != self.hard {
+            self.storage.set_hard_state(hard);
+            self.hard = hard;
+        }
+    }
+
+    /// Leader side. Add a fresh entry to the log, durably.
+    fn push(&mut self, entry: Log<S::Op>) {
+        self.storage.append(self.last_index() + 1, std::slice::from_ref(&entry));
+        let config = entry.config.is_some();
+        self.log.push(entry);
+        if config {
+            self.refresh_config();
+        }
+    }
+
+    /// Follower side. Merge entries from the leader. A conflicting entry wipes out everything after it.
+    fn merge(&mut self, entries: Vec<(usize, Log<S::Op>)>) {
+        let mut first = None;
+        let mut config = false;
+        for (i, l) in entries {
+            if i <= self.snapshot.last_included_index {
+                // Already in the snapshot.
+                continue;
+            }
+            if i <= self.last_index() {
+                if self.term_at(i) == l.term {
+                    // Already have it.
+                    continue;
+                }
+                // Override. Might take a config change with it.
+                let p = self.pos(i);
+                self.log.truncate(p);
+                config = true;
+            }
+            config |= l.config.is_some();
+            first.get_or_insert(i);
+            // New
+            self.log.push(l);
+        }
+        if let Some(i) = first {
+            self.storage.append(i, &self.log[self.pos(i)..]);
+        }
+        if config {
+            self.refresh_config();
+        }
     }
 
     fn perform(&mut self) {
         for q in self.last_applied + 1..=self.commit_index {
             // perform
-            let cmd = self.log[q].command.clone();
+            let cmd = self.log[self.pos(q)].command.clone();
             if cmd.is_none() {
                 continue;
             }
             let cmd = cmd.unwrap();
-            let (state, _res) = ReplicaState::triv(cmd.op.clone())(&self.rst);
-            self.rst = state;
+            // Retried commands can be in the log more than once. Only the first counts.
+            let rst = &mut self.rst;
+            let res = self.sessions.execute(cmd.client, cmd.op_id, q, || rst.apply(&cmd.op));
             if self.state == ServerState::Leader {
-                let sock = cmd.client;
-                if let Some(ep) = self.clients.get(&sock) {
-                    self.handler
-                        .network()
-                        .send(*ep, &to_vec(&Message::Response(cmd.clone())).unwrap());
-                } else {
-                    let ep = self
-                        .handler
-                        .network()
-                        .connect(Transport::Udp, sock)
-                        .unwrap()
-                        .0;
-                    self.clients.insert(sock, ep);
-                    self.handler
-                        .network()
-                        .send(ep, &to_vec(&Message::Response(cmd.clone())).unwrap());
-                };
+                match res {
+                    Some(res) => self.respond(&cmd, res),
+                    // Better the client hears it's too late than nothing at all.
+                    None => self.reply(cmd.client, &Message::Expired(cmd.op_id)),
+                }
             }
         }
         self.last_applied = self.commit_index;
+        self.compact();
+        self.serve_reads();
     }
-}
 
-pub fn run(id: usize, addr: SocketAddr) {
-    let (handler, listener) = node::split::<Timer>();
-    handler.network().listen(Transport::Udp, addr).unwrap();
-    let peers = get_peers(id, handler.clone());
+    fn respond(&mut self, cmd: &Command<S::Op>, res: S::Output) {
+        self.reply(cmd.client, &Message::Response(cmd.op_id, res));
+    }
 
-    let mut server = Server::new(id, peers, handler);
-    println!("Server {id} up.");
-    let _ = listener.for_each_async(move |event| {
-        match event {
-            NodeEvent::Network(e) => {
-                match e {
-                    NetEvent::Connected(ep, _) => {
-                        println!("Raft server {id} Connected to {ep}.");
+    fn reply(&mut self, sock: SocketAddr, msg: &Message<S::Op, S::Output>) {
+        if let Some(ep) = self.clients.get(&sock) {
+            self.send(*ep, msg);
+        } else {
+            let ep = match self.listener {
+                Some(res) => Endpoint::from_listener(res, sock),
+                None => self.io.connect(sock),
+            };
+            self.clients.insert(sock, ep);
+            self.send(ep, msg);
+        };
+    }
+
+    /// Leader side. A client command, through the log or as a read.
+    fn submit(&mut self, cmd: Command<S::Op>) {
+        if self.opts.read_mode != ReadMode::Log && S::is_read_only(&cmd.op) {
+            self.read(cmd);
+            return;
+        }
+        self.push(Log {
+            term: self.current_term,
+            command: Some(cmd),
+            config: None,
+        });
+        self.decree();
+    }
+
+    /// Leader side. Tell the transfer target to go ahead, once it has everything we have.
+    fn try_transfer(&mut self) {
+        if let Some((to, _)) = self.transfer {
+            if self.match_index.get(&to).copied().unwrap_or(0) >= self.last_index() {
+                println!("Raft server {} handing over to {to}", self.id);
+                let msg = Message::TimeoutNow(self.current_term);
+                self.send(self.peers[&to], &msg);
+            }
+        }
+    }
+
+    /// Leader side. Give up on a transfer that's been going for an election timeout, and take requests again.
+    fn abandon_transfer(&mut self) {
+        match self.transfer {
+            Some((to, since)) if (self.io.now() - since) >= Duration::from_millis(ELECTION_MAX as u64) => {
+                println!("Raft server {} gave up handing over to {to}", self.id);
+                self.transfer = None;
+                // Rounds from during the transfer don't count towards a lease.
+                self.sent_at.clear();
+                for msg in std::mem::take(&mut self.pending) {
+                    if let Message::Request(cmd) = msg {
+                        self.submit(cmd);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Leader side. A read-only op, answered without touching the log.
+    fn read(&mut self, cmd: Command<S::Op>) {
+        // Until something from this term commits, we can't tell how far the commit index really goes.
+        // No writes to do that for us yet, so put in a no-op.
+        if self.log.last().unwrap().term != self.current_term {
+            self.push(Log {
+                term: self.current_term,
+                command: None,
+                config: None,
+            });
+            self.decree();
+        }
+        // Everything from this term may be compacted already, and so committed.
+        let term_start = (self.snapshot.last_included_index + 1..=self.last_index())
+            .rev()
+            .take_while(|i| self.term_at(*i) == self.current_term)
+            .last()
+            .unwrap_or(self.snapshot.last_included_index);
+        let lease = matches!(self.lease, Some(l) if self.io.now() < l);
+        let round = if self.opts.read_mode == ReadMode::Lease && lease {
+            0
+        } else {
+            self.seq + 1
+        };
+        self.reads.push(Read {
+            round,
+            index: self.commit_index.max(term_start),
+            cmd,
+        });
+        // Nothing in flight to piggyback on, so start a round now rather than wait for the next heartbeat.
+        if round > self.seq && self.confirmed() == self.seq {
+            self.decree();
+        }
+        self.serve_reads();
+    }
+
+    /// Latest heartbeat round a majority of voters (us included) has answered.
+    fn confirmed(&self) -> usize {
+        let mut acks = self
+            .voters
+            .keys()
+            .map(|v| match *v == self.id {
+                true => self.seq,
+                false => self.acked.get(v).copied().unwrap_or(0),
+            })
+            .collect::<Vec<_>>();
+        acks.sort_unstable_by(|a, b| b.cmp(a));
+        acks.get(self.quorum() - 1).copied().unwrap_or(0)
+    }
+
+    /// Answer whichever reads can be answered, and extend the lease.
+    fn serve_reads(&mut self) {
+        if self.state != ServerState::Leader {
+            // Clients will retry with whoever is leader now.
+            self.reads.clear();
+            return;
+        }
+        let confirmed = self.confirmed();
+        // A majority heard from us after this went out, and none of them will vote for anyone else for ELECTION_MIN.
+        while let Some(&(s, at)) = self.sent_at.front() {
+            if s > confirmed {
+                break;
+            }
+            // Not while handing over, voters don't wait out the lease for the server we picked.
+            if self.transfer.is_none() {
+                let ms = ELECTION_MIN * (1.0 - self.opts.max_drift);
+                self.lease = Some(at + Duration::from_millis(ms as u64));
+            }
+            self.sent_at.pop_front();
+        }
+
+        let applied = self.last_applied;
+        let (ready, waiting) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|r| r.round <= confirmed && r.index <= applied);
+        self.reads = waiting;
+        for r in ready {
+            self.leased += (r.round == 0) as usize;
+            let res = self.rst.apply(&r.cmd.op);
+            self.respond(&r.cmd, res);
+        }
+
+        if self.reads.iter().any(|r| r.round > self.seq) && confirmed == self.seq {
+            self.decree();
+        }
+    }
+
+    /// Snapshot the state machine at `last_applied` and drop the log up to there, if enough has piled up.
+    fn compact(&mut self) {
+        if self.last_applied - self.snapshot.last_included_index < self.opts.snapshot_threshold {
+            return;
+        }
+        let p = self.pos(self.last_applied);
+        self.snapshot = Snapshot {
+            last_included_index: self.last_applied,
+            last_included_term: self.log[p].term,
+            data: self.rst.snapshot(),
+            config: self.config_at(self.last_applied).1,
+            sessions: self.sessions.clone(),
+        };
+        self.storage.save_snapshot(&self.snapshot);
+        // The entry at the snapshot point becomes the new sentinel.
+        self.log.drain(..p);
+        self.log[0].command = None;
+    }
+
+    /// Follower side. The leader thinks we're hopelessly behind.
+    fn install(&mut self, snap: Snapshot<S::Output>) {
+        let idx = snap.last_included_index;
+        if idx <= self.commit_index {
+            // Already have all of it.
+            return;
+        }
+        if idx <= self.last_index() && self.term_at(idx) == snap.last_included_term {
+            // Keep whatever follows the snapshot.
+            let p = self.pos(idx);
+            self.log.drain(..p);
+        } else {
+            self.log.truncate(1);
+            self.storage.append(self.last_index() + 1, &[]);
+        }
+        self.log[0] = Log {
+            term: snap.last_included_term,
+            command: None,
+            config: None,
+        };
+        self.rst.restore(&snap.data);
+        self.sessions = snap.sessions.clone();
+        self.commit_index = idx;
+        self.last_applied = idx;
+        self.storage.save_snapshot(&snap);
+        self.snapshot = snap;
+        self.refresh_config();
+    }
+
+    /// Whatever came in off the network. Junk is ignored.
+    pub fn on_message(&mut self, ep: Endpoint, buf: &[u8]) {
+        if let Ok(msg) = from_slice::<Message<S::Op, S::Output>>(buf) {
+            self.handle(ep, msg, buf);
+        }
+    }
+
+    fn handle(&mut self, ep: Endpoint, msg: Message<S::Op, S::Output>, buf: &[u8]) {
+        match msg {
+            // If leader, decree. Else, redirect to leader.
+            Message::Request(ref cmd) => {
+                if self.state == ServerState::Leader {
+                    // Straight from the client's own socket, so answer on this endpoint. Then the client knows who the leader is.
+                    if ep.addr() == cmd.client {
+                        self.clients.insert(cmd.client, ep);
                     }
-                    NetEvent::Accepted(ep, _) => {
-                        println!("Raft server {id} Accepted {ep}.");
+                    if self.transfer.is_some() {
+                        // Goes to whoever's leader next, or back to us if the transfer falls through.
+                        self.pending.push(msg);
+                        return;
                     }
-                    NetEvent::Disconnected(ep) => {
-                        println!("Raft server {id} Disconnected from {ep}.");
+                    self.submit(cmd.clone());
+                } else {
+                    self.forward(msg, buf);
+                }
+            }
+
+            // A server should never receive a response.
+            // The leader responds to the client directly.
+            // The client socket address is contained in the command.
+            Message::Response(..) | Message::Expired(_) => {
+                println!("Raft server {} got a message it has no use for: {msg:?}", self.id);
+            }
+            // Add to log
+            Message::Heartbeat(rep) => {
+                // println!("HB, {}", rep.entries.len());
+                // Old leader
+                if rep.hb.term < self.current_term {
+                    // println!("{}@{} Rejected {}@{}", id, self.current_term, rep.hb.leader_id, rep.hb.term);
+                    self.reject(ep, rep.hb.seq);
+                    return;
+                }
+                if self.last_index() < rep.hb.prev_log_index // Old log, send previous stuff also
+                || (rep.hb.prev_log_index >= self.snapshot.last_included_index // Compacted entries are committed, so they match
+                    && self.term_at(rep.hb.prev_log_index) != rep.hb.prev_log_term) // Log conflict, send previous stuff also
+                {
+                    // So that pending messages are not lost.
+                    self.advance(rep.hb.term);
+                    self.state = ServerState::Follower;
+                    self.leader = Some(rep.hb.leader_id);
+                    // It's still the leader, and it'll count this reply towards its lease. Hold off voting as if it were a match.
+                    self.heard_at = Some(self.io.now());
+                    // println!("{} unmerge {}", id, rep.hb.leader_id);
+                    self.mismatch(ep, &rep.hb);
+                    self.reset_timeout();
+                    return;
+                }
+                // The leader only vouches for what it sent. Anything past that in our log may yet be overwritten.
+                let matched = rep.hb.prev_log_index + rep.entries.len();
+                // println!("{} Accepted {}", id, rep.hb.leader_id);
+                self.advance(rep.hb.term);
+                if let Some(t) = self.current_timer {
+                    self.io.cancel(t);
+                }
+                self.state = ServerState::Follower;
+                self.leader = Some(rep.hb.leader_id);
+                self.heard_at = Some(self.io.now());
+                self.merge(rep.entries);
+
+                if let Some(leader) = self.peers.get(&rep.hb.leader_id).copied() {
+                    while let Some(msg) = self.pending.pop() {
+                        self.send(leader, &msg);
                     }
-                    NetEvent::Message(ep, buf) => {
-                        let msg = from_slice::<Message>(&buf);
-                        if let Ok(msg) = msg {
-                            match msg {
-                                // If leader, decree. Else, redirect to leader.
-                                Message::Request(ref cmd) => {
-                                    dbg!(&msg);
-                                    match server.state {
-                                        ServerState::Follower => {
-                                            if let Some(l) = server.voted_for {
-                                                // dbg!(id, l);
-                                                let leader = server.peers[&l];
-                                                server.handler.network().send(leader, &buf);
-                                            } else {
-                                                server.pending.push(msg);
-                                            }
-                                        },
-                                        ServerState::Candidate(_) => {
-                                            server.pending.push(msg);
-                                        },
-                                        ServerState::Leader => {
-                                            server.log.push(Log {
-                                                term: server.current_term,
-                                                command: Some(cmd.clone()),
-                                            });
-                                            server.decree();
-                                        },
-                                    }
-                                }
+                }
 
-                                // A server can never receive a response.
-                                // The leader responds to the client directly.
-                                // The client socket address is contained in the command.
-                                Message::Response(_) => unreachable!(),
-                                // Add to log
-                                Message::Heartbeat(rep) => {
-                                    // println!("HB, {}", rep.entries.len());
-                                    // Old leader
-                                    if rep.hb.term < server.current_term {
-                                        // println!("{}@{} Rejected {}@{}", id, server.current_term, rep.hb.leader_id, rep.hb.term);
-                                        server.reject(ep);
-                                    } else if server.log.len() - 1 < rep.hb.prev_log_index // Old log, send previous stuff also
-                                    || server.log[rep.hb.prev_log_index].term != rep.hb.prev_log_term // Log conflict, send previous stuff also
-                                    {
-                                        // So that pending messages are not lost.
-                                        server.current_term = rep.hb.term;
-                                        server.voted_for = Some(rep.hb.leader_id);
-                                        // println!("{} unmerge {}", id, rep.hb.leader_id);
-                                        server.reject(ep);
-                                    } else {
-                                        // println!("{} Accepted {}", id, rep.hb.leader_id);
-                                        server.current_term = rep.hb.term;
-                                        if let Some(t) = server.current_timer {
-                                            server.handler.signals().cancel_timer(t);
-                                        }
-                                        server.state = ServerState::Follower;
-                                        server.voted_for = Some(rep.hb.leader_id);
-                                        for (i, l) in rep.entries.iter() {
-                                            if *i < server.log.len() {
-                                                // Override
-                                                server.log[*i] = l.clone();
-                                            } else {
-                                                // New
-                                                server.log.push(l.clone());
-                                            }
-                                        }
-    
-                                        
-                                        while !server.pending.is_empty() {
-                                            let msg = server.pending.pop().unwrap();
-                                            let leader = server.peers[&server.voted_for.unwrap()];
-                                            server
-                                                .handler
-                                                .network()
-                                                .send(leader, &to_vec(&msg).unwrap());
-                                        }
-                                    }
+                // The whole log, every append. Swamps everything else on a long run.
+                // if !rep.entries.is_empty() {
+                //     dbg!(&self.log);
+                // }
 
-                                    if rep.entries.len() > 0 {
-                                        dbg!(&server.log);
-                                    }
-    
-                                    if rep.hb.leader_commit > server.commit_index {
-                                        server.commit_index =
-                                            rep.hb.leader_commit.min(server.log.len() - 1);
-                                        if server.commit_index > server.last_applied {
-                                            // perform
-                                            server.perform();
-                                        }
-                                    }
-    
-                                    server.accept(ep);
-                                    server.reset_timeout();
+                let commit = rep.hb.leader_commit.min(matched);
+                if commit > self.commit_index {
+                    self.commit_index = commit;
+                    if self.commit_index > self.last_applied {
+                        // perform
+                        self.perform();
+                    }
+                }
+
+                self.accept(ep, rep.hb.seq, matched);
+                self.reset_timeout();
+            }
+            // Candidacy
+            Message::Campaign(c) => {
+                // Somehow, we don't need to check ServerState?
+
+                // The leader's lease counts on us not voting till an election timeout after we last heard from it.
+                // Unless the leader itself is the one asking.
+                if self.opts.read_mode == ReadMode::Lease
+                    && !c.transfer
+                    && matches!(self.heard_at, Some(t) if (self.io.now() - t) < Duration::from_millis(ELECTION_MIN as u64))
+                {
+                    return;
+                }
+
+                // If we at newer term, reply false.
+                if c.term < self.current_term {
+                    self.reject(ep, 0);
+                    return;
+                }
+                if c.term > self.current_term {
+                    // New term. Whoever we voted for (or followed) before doesn't count any more.
+                    self.advance(c.term);
+                    self.state = ServerState::Follower;
+                }
+                let free = self.voted_for.is_none() || self.voted_for == Some(c.candidate_id);
+                if free && self.log_ok(&c) {
+                    self.vote(ep, c);
+                } else {
+                    self.reject(ep, 0);
+                }
+            }
+            // Same questions as a campaign, but nothing changes on our side.
+            Message::PreVote(c) => {
+                let grant = c.term > self.current_term
+                    && self.state != ServerState::Leader
+                    && !self.leader_alive()
+                    && self.log_ok(&c);
+                let rep = Message::PreVoteReply(Reply {
+                    from: self.id,
+                    success: grant,
+                    term: self.current_term,
+                    seq: 0,
+                    match_index: 0,
+                    conflict_index: 0,
+                    conflict_term: None,
+                });
+                self.send(ep, &rep);
+            }
+            Message::PreVoteReply(res) => {
+                if let ServerState::PreCandidate(v) = self.state {
+                    if res.success && self.voters.contains_key(&res.from) && self.votes.insert(res.from) {
+                        if v + 1 >= self.quorum() {
+                            self.campaign(false);
+                        } else {
+                            self.state = ServerState::PreCandidate(v + 1);
+                        }
+                    }
+                }
+            }
+
+            Message::ServerReply(res) => {
+                match self.state {
+                    ServerState::Follower => {
+                        // println!("BAD.");
+                    }
+                    // Left over from an earlier term. Pre-votes come back as PreVoteReply.
+                    ServerState::PreCandidate(_) => {}
+                    // Votes
+                    ServerState::Candidate(v) => {
+                        if res.success {
+                            // Only voters count, once each, and only for this term. An old yes could be from back when our log was good enough.
+                            if res.term == self.current_term && self.voters.contains_key(&res.from) && self.votes.insert(res.from) {
+                                // Majority
+                                if v + 1 >= self.quorum() {
+                                    self.crown();
+                                } else {
+                                    self.state = ServerState::Candidate(v + 1);
                                 }
-                                // Candidacy
-                                Message::Campaign(c) => {
-                                    // Somehow, we don't need to check ServerState?
-    
-                                    // If we at newer term, reply false.
-                                    if c.term < server.current_term {
-                                        server.reject(ep);
-                                    }
-                                    // Ambiguity here. Should we abstain?
-                                    else if c.term > server.current_term
-                                        || c.last_log_index >= server.log.len() - 1
-                                    {
-                                        // Can be optimised, not doing it.
-                                        if let Some(s) = server.voted_for {
-                                            if s == c.candidate_id {
-                                                server.vote(ep, c);
-                                            }
-                                        } else if server.voted_for.is_none() {
-                                            server.vote(ep, c);
-                                        }
-                                    }
+                            }
+                        } else if res.term > self.current_term {
+                            self.advance(res.term);
+                            self.state = ServerState::Follower;
+                        }
+                    }
+                    // Rejects
+                    ServerState::Leader => {
+                        // Answering this term's heartbeat at all, success or not, means they still follow us.
+                        if res.term == self.current_term {
+                            self.contact.insert(res.from, self.io.now());
+                            let a = self.acked.entry(res.from).or_insert(0);
+                            *a = (*a).max(res.seq);
+                            self.serve_reads();
+                        }
+                        if res.term > self.current_term {
+                            self.advance(res.term);
+                            self.state = ServerState::Follower;
+                            // todo!()
+                        } else if res.success {
+                            // Replies can come back out of order. Only ever move forward.
+                            let m = res.match_index.min(self.last_index());
+                            let m = *self.match_index.entry(res.from).and_modify(|x| *x = (*x).max(m)).or_insert(m);
+                            let n = self.next_index.entry(res.from).or_insert(m + 1);
+                            *n = (*n).max(m + 1);
+                            if let Some(q) = self.inflight.get_mut(&res.from) {
+                                while q.front().is_some_and(|(i, _, _)| *i <= m) {
+                                    q.pop_front();
                                 }
-    
-                                Message::ServerReply(res) => {
-                                    match server.state {
-                                        ServerState::Follower => {
-                                            // println!("BAD.");
-                                        }
-                                        // Votes
-                                        ServerState::Candidate(v) => {
-                                            if res.success {
-                                                // Majority
-                                                if v > (server.peers.len() + 1) / 2 {
-                                                    server.crown();
-                                                } else {
-                                                    server.state = ServerState::Candidate(v + 1);
-                                                }
-                                            } else {
-                                                server.current_term = res.term;
-                                                server.state = ServerState::Follower;
-                                                server.voted_for = None
-                                            }
-                                        }
-                                        // Rejects
-                                        ServerState::Leader => {
-                                            if res.term > server.current_term {
-                                                server.current_term = res.term;
-                                                server.state = ServerState::Follower;
-                                                server.voted_for = None;
-                                                // todo!()
-                                            } else {
-                                                if res.success {
-                                                    // TODO: Verify.
-                                                    server
-                                                        .match_index
-                                                        .insert(res.from, server.log.len() - 1);
-                                                    server
-                                                        .next_index
-                                                        .insert(res.from, server.log.len());
-    
-                                                    for i in (server.commit_index + 1..server.log.len())
-                                                        .rev()
-                                                    {
-                                                        let mut count = 1;
-                                                        for (_a, b) in server.match_index.iter() {
-                                                            if *b >= i {
-                                                                count += 1;
-                                                            }
-                                                        }
-                                                        if count > (server.peers.len() + 1) / 2 {
-                                                            server.commit_index = i;
-                                                            break;
-                                                        }
-                                                    }
-    
-                                                    if server.commit_index > server.last_applied {
-                                                        server.perform();
-                                                    }
-                                                } else {
-                                                    // Term matches, log does not.
-                                                    let u = server.next_index.get_mut(&res.from).unwrap();
-                                                    if *u > 0 {
-                                                        *u -= 1;
-                                                    }
-                                                    server.decree();
-                                                }
-                                            }
-                                        }
+                                // They've answered a later round without getting it, so it's most likely lost. Don't wait out
+                                // `RESEND_AFTER`, go again from what they have. Costs a resend at worst.
+                                if q.front().is_some_and(|(_, round, _)| *round < res.seq) {
+                                    q.clear();
+                                    self.next_index.insert(res.from, m + 1);
+                                }
+                            }
+
+                            // Only entries from our own term get committed by counting (Raft, 5.4.2). Earlier ones come along with them.
+                            for i in (self.commit_index + 1..=self.last_index()).rev() {
+                                if self.term_at(i) != self.current_term {
+                                    break;
+                                }
+                                // Only voters count, and we might not be one any more.
+                                let mut count = self.voters.contains_key(&self.id) as usize;
+                                for (a, b) in self.match_index.iter() {
+                                    if *b >= i && self.voters.contains_key(a) {
+                                        count += 1;
                                     }
                                 }
+                                if count >= self.quorum() {
+                                    self.commit_index = i;
+                                    break;
+                                }
                             }
+
+                            if self.commit_index > self.last_applied {
+                                self.perform();
+                            }
+
+                            if !self.voters.contains_key(&self.id) && self.config_index <= self.commit_index {
+                                // Removed ourselves, and that's committed now. Bow out.
+                                self.state = ServerState::Follower;
+                                self.leader = None;
+                            } else {
+                                self.reconfigure();
+                                self.try_transfer();
+                            }
+                            // Keep the pipeline going, if there's more for them.
+                            if self.state == ServerState::Leader
+                                && self.next_index.get(&res.from).is_some_and(|n| *n <= self.last_index())
+                            {
+                                self.replicate(res.from, self.seq);
+                            }
+                        } else if let Some(&n) = self.next_index.get(&res.from) {
+                            // Term matches, log does not. Whatever's in flight was built on the same bad guess.
+                            let probing = self.inflight.get(&res.from).is_none_or(|q| q.is_empty());
+                            self.inflight.remove(&res.from);
+                            let m = self.match_index[&res.from];
+                            let n = match (res.conflict_index, res.conflict_term) {
+                                // Step back one at a time, or straight back to what we know once things are in flight.
+                                (0, _) if probing => n.saturating_sub(1),
+                                (0, _) => 0,
+                                // They're short. Carry on from the end of their log.
+                                (c, None) => c,
+                                // Skip their whole term. If we have it too, the entries up to the end of ours match.
+                                (c, Some(t)) => self.last_of_term(t).map_or(c, |i| i + 1),
+                            };
+                            self.next_index.insert(res.from, n.max(m + 1));
+                            self.replicate(res.from, self.seq);
                         }
                     }
                 }
+                self.persist();
+            }
+            // Same deal as a heartbeat, but with the whole state machine.
+            Message::InstallSnapshot(is) => {
+                if is.term < self.current_term {
+                    self.reject(ep, 0);
+                    return;
+                }
+                self.advance(is.term);
+                if let Some(t) = self.current_timer {
+                    self.io.cancel(t);
+                }
+                self.state = ServerState::Follower;
+                self.leader = Some(is.leader_id);
+                self.heard_at = Some(self.io.now());
+                let idx = is.snapshot.last_included_index;
+                self.install(is.snapshot);
+                self.accept(ep, 0, idx);
+                self.reset_timeout();
             }
-            NodeEvent::Signal(t) => match t {
-                Timer::Heartbeat => {
-                    if server.state == ServerState::Leader {
-                        server.empty_decree();
+            Message::AddServer(sid, addr) => {
+                if self.state != ServerState::Leader {
+                    self.forward(msg, buf);
+                } else if sid != self.id && !self.voters.contains_key(&sid) {
+                    // No vote until it's caught up.
+                    self.learners.insert(sid, addr);
+                    self.refresh_config();
+                    self.decree();
+                }
+            }
+            Message::RemoveServer(sid) => {
+                if self.state != ServerState::Leader {
+                    self.forward(msg, buf);
+                } else {
+                    self.learners.remove(&sid);
+                    if self.voters.contains_key(&sid) && !self.removals.contains(&sid) {
+                        self.removals.push(sid);
                     }
+                    self.sync_targets();
+                    self.reconfigure();
                 }
-                Timer::Election => {
+            }
+            Message::TransferLeadership(sid) => {
+                if self.state != ServerState::Leader {
+                    self.forward(msg, buf);
+                } else if sid != self.id && self.voters.contains_key(&sid) && self.transfer.is_none() {
+                    println!("Raft server {} transferring leadership to {sid}", self.id);
+                    self.transfer = Some((sid, self.io.now()));
+                    // Stop serving lease reads too. The next leader may be up before the lease runs out.
+                    self.lease = None;
+                    // Catch it up. try_transfer goes on from its reply.
+                    self.decree();
+                    self.try_transfer();
+                }
+            }
+            Message::TimeoutNow(term) => {
+                if term == self.current_term && self.voters.contains_key(&self.id) && self.state != ServerState::Leader {
+                    if let Some(t) = self.current_timer {
+                        self.io.cancel(t);
+                    }
+                    self.campaign(true);
+                }
+            }
+        }
+    }
+
+    pub fn on_timer(&mut self, t: Timer) {
+        match t {
+            Timer::Heartbeat => {
+                if self.state == ServerState::Leader {
+                    self.expire_inflight();
+                    self.decree();
+                }
+                self.reset_heartbeat();
+            }
+            Timer::Election => match self.state {
+                ServerState::Leader => self.check_quorum(),
+                // Learners, standbys and removed servers don't get to run.
+                _ if self.voters.contains_key(&self.id) => {
                     // println!("Campaign {id}");
-                    server.campaign();
+                    self.pre_campaign();
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+/// Server `id` on its own socket, till the process ends. Everything it sends goes past `faults`, and it shows
+/// `invariants` where it's at after everything it handles, if given.
+pub fn run<S>(
+    id: usize,
+    cfg: &ClusterConfig,
+    storage: Box<dyn Storage<S::Op, S::Output>>,
+    opts: Options,
+    faults: Option<&Faults>,
+    invariants: Option<&Invariants<S::Op>>,
+) where
+    S: StateMachine,
+{
+    let (handler, listener) = node::split::<Timer>();
+    let (res, _) = handler
+        .network()
+        .listen(Transport::Udp, cfg.addr(Role::Raft, id))
+        .unwrap();
+    let io = faults::wrap(faults, cfg.addr(Role::Raft, id), Box::new(handler));
+    let mut server = Server::<S>::start(id, cfg, io, storage, opts);
+    server.reply_from(res);
+    println!("Server {id} up.");
+    let invariants = invariants.cloned();
+    let _ = listener.for_each_async(move |event| {
+        match event {
+            NodeEvent::Network(e) => match e {
+                NetEvent::Connected(ep, _) => {
+                    println!("Raft server {id} Connected to {ep}.");
                 }
+                NetEvent::Accepted(ep, _) => {
+                    println!("Raft server {id} Accepted {ep}.");
+                }
+                NetEvent::Disconnected(ep) => {
+                    println!("Raft server {id} Disconnected from {ep}.");
+                }
+                NetEvent::Message(ep, buf) => server.on_message(ep, buf),
             },
+            NodeEvent::Signal(t) => server.on_timer(t),
+        }
+        if let Some(inv) = &invariants {
+            server.observe(inv);
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use message_io::{
+        events::EventReceiver,
+        node::{NodeHandler, NodeTask, StoredNetEvent, StoredNodeEvent},
+    };
+
+    use super::*;
+    use crate::{
+        client,
+        faults::Faults,
+        invariants::Violation,
+        kv::{KvOp, KvStore},
+        raft::{client::Raft, storage::MemStorage},
+        sim::{self, Sim},
+    };
+
+    /// A server we drive by hand. Its own timers are ignored, on_timer gets called when the test says so.
+    struct Node<S: StateMachine = KvStore> {
+        server: Server<S>,
+        handler: NodeHandler<Timer>,
+        rx: EventReceiver<StoredNodeEvent<Timer>>,
+        /// Every socket it sends from, to tell its messages apart.
+        sends_from: HashSet<SocketAddr>,
+        _task: NodeTask,
+    }
+
+    impl<S: StateMachine> Drop for Node<S> {
+        fn drop(&mut self) {
+            // Or dropping the task waits forever.
+            self.handler.stop();
+        }
+    }
+
+    fn cluster(n: usize) -> Vec<Node> {
+        cluster_with(n, Options::default())
+    }
+
+    fn cluster_with(n: usize, opts: Options) -> Vec<Node> {
+        cluster_of(n, opts)
+    }
+
+    fn cluster_of<S: StateMachine>(n: usize, opts: Options) -> Vec<Node<S>> {
+        let mut nodes = vec![];
+        for _ in 0..n {
+            let (handler, listener) = node::split::<Timer>();
+            let (res, addr) = handler.network().listen(Transport::Udp, "127.0.0.1:0").unwrap();
+            let (task, rx) = listener.enqueue();
+            nodes.push((handler, addr, res, task, rx));
+        }
+        let membership: Membership = nodes.iter().enumerate().map(|(i, n)| (i, n.1)).collect();
+        nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, (handler, _, res, task, rx))| {
+                let mut sends_from = HashSet::new();
+                let mut peers = HashMap::new();
+                for (j, addr) in membership.iter().filter(|(j, _)| **j != i) {
+                    let (ep, local) = handler.network().connect_sync(Transport::Udp, *addr).unwrap();
+                    sends_from.insert(local);
+                    peers.insert(*j, ep);
+                }
+                let mut server = Server::new(
+                    i,
+                    peers,
+                    Box::new(handler.clone()),
+                    Box::new(MemStorage::new()),
+                    opts,
+                    membership.clone(),
+                );
+                server.reply_from(res);
+                Node {
+                    server,
+                    handler,
+                    rx,
+                    sends_from,
+                    _task: task,
+                }
+            })
+            .collect()
+    }
+
+    /// Deliver messages till things go quiet. Nothing gets to or from the nodes in `cut`.
+    fn pump<S: StateMachine>(nodes: &mut [Node<S>], cut: &[usize]) {
+        let cut_from: HashSet<SocketAddr> = cut.iter().flat_map(|i| nodes[*i].sends_from.clone()).collect();
+        let mut quiet = 0;
+        while quiet < 5 {
+            let mut any = false;
+            for (i, n) in nodes.iter_mut().enumerate() {
+                while let Some(e) = n.rx.receive_timeout(Duration::from_millis(2)) {
+                    if let StoredNodeEvent::Network(StoredNetEvent::Message(ep, buf)) = e {
+                        any = true;
+                        if cut.contains(&i) || cut_from.contains(&ep.addr()) {
+                            continue;
+                        }
+                        if let Ok(msg) = from_slice::<Message<S::Op, S::Output>>(&buf) {
+                            n.server.handle(ep, msg, &buf);
+                        }
+                    }
+                }
+            }
+            quiet = if any { 0 } else { quiet + 1 };
+        }
+    }
+
+    /// Deliver whatever turns up at node `i` right now, and nothing else.
+    fn step<S: StateMachine>(nodes: &mut [Node<S>], i: usize) {
+        while let Some(e) = nodes[i].rx.receive_timeout(Duration::from_millis(20)) {
+            if let StoredNodeEvent::Network(StoredNetEvent::Message(ep, buf)) = e {
+                if let Ok(msg) = from_slice::<Message<S::Op, S::Output>>(&buf) {
+                    nodes[i].server.handle(ep, msg, &buf);
+                }
+            }
+        }
+    }
+
+    fn put(op_id: usize) -> Command {
+        Command {
+            client: "127.0.0.1:1".parse().unwrap(),
+            op_id,
+            op: KvOp::Put("a".into(), op_id.to_string()),
+        }
+    }
+
+    fn elect<S: StateMachine>(nodes: &mut [Node<S>], id: usize) {
+        nodes[id].server.on_timer(Timer::Election);
+        pump(nodes, &[]);
+        assert_eq!(nodes[id].server.state, ServerState::Leader);
+        heartbeat(nodes, id, &[]);
+    }
+
+    fn heartbeat<S: StateMachine>(nodes: &mut [Node<S>], id: usize, cut: &[usize]) {
+        nodes[id].server.on_timer(Timer::Heartbeat);
+        pump(nodes, cut);
+    }
+
+    /// The whole cluster from cluster.json on the simulated network, for anything that needs time to go by.
+    /// Only the voters' addresses come back. Nothing wrong with the network till the test says so.
+    fn sim_cluster(seed: u64, opts: Options) -> (Sim, Faults, Vec<SocketAddr>) {
+        let cfg = ClusterConfig::load("cluster.json");
+        let mut sim = Sim::new(seed);
+        let faults = Faults::new(seed);
+        sim.set_faults(faults.clone());
+        sim.set_invariants(Invariants::new());
+        sim::raft_cluster::<KvStore>(&mut sim, &cfg, opts);
+        let addrs = cfg.nodes(Role::Raft).filter(|n| !n.standby).map(|n| n.addr).collect();
+        (sim, faults, addrs)
+    }
+
+    fn server(sim: &Sim, addr: SocketAddr) -> &Server<KvStore> {
+        sim.node(addr).unwrap()
+    }
+
+    fn sim_leader(sim: &Sim, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        addrs.iter().copied().find(|a| server(sim, *a).is_leader())
+    }
+
+    /// `msg` straight to the server at `addr`, as if from an admin tool.
+    fn admin(sim: &mut Sim, addr: SocketAddr, msg: Message) {
+        let s = sim.node_mut::<Server<KvStore>>(addr).unwrap();
+        let ep = s.io.connect("127.0.0.1:1".parse().unwrap());
+        s.on_message(ep, &to_vec(&msg).unwrap());
+    }
+
+    /// A running total. Every op is a number to add, and gets back the new total. Adding 0 only looks.
+    #[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+    struct Adder(usize);
+
+    impl StateMachine for Adder {
+        type Op = usize;
+        type Output = usize;
+
+        fn apply(&mut self, op: &usize) -> usize {
+            self.0 += op;
+            self.0
+        }
+
+        fn is_read_only(op: &usize) -> bool {
+            *op == 0
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            to_vec(self).unwrap()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) {
+            *self = from_slice(snapshot).unwrap();
+        }
+    }
+
+    #[test]
+    fn replicates_any_state_machine() {
+        // Nothing KV about it, just numbers to add up.
+        let mut nodes = cluster_of::<Adder>(3, Options::default());
+        elect(&mut nodes, 0);
+        for (op_id, op) in [(1, 2), (2, 3)] {
+            nodes[0].server.submit(Command {
+                client: "127.0.0.1:1".parse().unwrap(),
+                op_id,
+                op,
+            });
+        }
+        pump(&mut nodes, &[]);
+        heartbeat(&mut nodes, 0, &[]);
+        for n in nodes.iter() {
+            assert_eq!(n.server.rst.clone().apply(&0), 5);
+        }
+    }
+
+    #[test]
+    fn client_learns_the_leader_from_a_forwarded_answer() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes, 0);
+        let servers = nodes[0].server.voters.values().copied().collect::<Vec<_>>();
+        // Somewhere free to listen.
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let opts = client::Options {
+            retries: 0,
+            ..client::Options::default()
+        };
+        let client = client::Client::start(0, addr, <Raft>::with_servers(addr, servers, 1), opts);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let wait = Duration::from_secs(2);
+
+        // Goes to follower 1, which passes it on. The answer comes from the leader's own address.
+        let done = tx.clone();
+        client.submit_with(KvOp::Put("a".into(), "1".into()), move |res| done.send(res).unwrap());
+        pump(&mut nodes, &[]);
+        heartbeat(&mut nodes, 0, &[]);
+        assert!(matches!(rx.recv_timeout(wait).unwrap(), Ok(Ok(_))));
+
+        // Straight to the leader now. With server 1 cut off and no retries, that's the only way it gets answered.
+        client.submit_with(KvOp::Put("a".into(), "2".into()), move |res| tx.send(res).unwrap());
+        pump(&mut nodes, &[1]);
+        heartbeat(&mut nodes, 0, &[1]);
+        assert!(matches!(rx.recv_timeout(wait).unwrap(), Ok(Ok(_))));
+    }
+
+    #[test]
+    fn flapping_node_does_not_disrupt() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes, 0);
+        let term = nodes[0].server.current_term;
+
+        // Node 2 loses the network and keeps timing out.
+        for _ in 0..5 {
+            nodes[2].server.on_timer(Timer::Election);
+            pump(&mut nodes, &[2]);
+            heartbeat(&mut nodes, 0, &[2]);
+        }
+        // Without PreVote it would be 5 terms ahead by now.
+        assert_eq!(nodes[2].server.current_term, term);
+        assert!(matches!(nodes[2].server.state, ServerState::PreCandidate(_)));
+
+        // Back again, and timing out once more before the leader gets a word in.
+        nodes[2].server.on_timer(Timer::Election);
+        pump(&mut nodes, &[]);
+        heartbeat(&mut nodes, 0, &[]);
+
+        assert_eq!(nodes[0].server.state, ServerState::Leader);
+        for n in &nodes {
+            assert_eq!(n.server.current_term, term);
+        }
+        assert_eq!(nodes[2].server.state, ServerState::Follower);
+        assert_eq!(nodes[2].server.leader, Some(0));
+    }
+
+    #[test]
+    fn duplicate_vote_counts_once() {
+        let mut nodes = cluster(5);
+        nodes[0].server.campaign(false);
+        step(&mut nodes, 1);
+        // Node 1's vote, twice over. That and our own would look like 3 of 5.
+        while let Some(e) = nodes[0].rx.receive_timeout(Duration::from_millis(20)) {
+            if let StoredNodeEvent::Network(StoredNetEvent::Message(ep, buf)) = e {
+                for _ in 0..2 {
+                    nodes[0].server.handle(ep, from_slice(&buf).unwrap(), &buf);
+                }
+            }
+        }
+        assert_eq!(nodes[0].server.state, ServerState::Candidate(2));
+
+        step(&mut nodes, 2);
+        step(&mut nodes, 0);
+        assert_eq!(nodes[0].server.state, ServerState::Leader);
+    }
+
+    #[test]
+    fn leader_keeps_its_vote() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes, 0);
+        let term = nodes[0].server.current_term;
+
+        // Node 1 asks for the vote node 0 already gave itself this term. Its log is just as good.
+        let msg = <Message>::Campaign(nodes[1].server.candidacy(term));
+        let buf = to_vec(&msg).unwrap();
+        let ep = nodes[0].server.peers[&1];
+        nodes[0].server.handle(ep, msg, &buf);
+
+        assert_eq!(nodes[0].server.state, ServerState::Leader);
+        assert_eq!(nodes[0].server.voted_for, Some(0));
+        assert_eq!(nodes[0].server.hard.voted_for, Some(0));
+    }
+
+    #[test]
+    fn behind_node_cannot_win() {
+        let (mut sim, faults, addrs) = sim_cluster(1, Options::default());
+        assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &addrs).is_some()));
+        let leader = sim_leader(&sim, &addrs).unwrap();
+        let behind = addrs.iter().copied().find(|a| *a != leader).unwrap();
+
+        // It misses a write.
+        faults.isolate(behind);
+        sim.node_mut::<Server<KvStore>>(leader).unwrap().submit(put(1));
+        sim.run_for(Duration::from_millis(500));
+        let index = server(&sim, leader).commit_index;
+        assert!(server(&sim, behind).last_index() < index);
+
+        // The leader goes away. Everyone else has the write, so it can't get a majority, however often it times out.
+        faults.heal();
+        faults.isolate(leader);
+        let won = sim.run_until(Duration::from_secs(3), |sim| server(sim, behind).is_leader());
+        assert!(!won);
+        let new = sim_leader(&sim, &addrs).unwrap();
+        assert_ne!(new, leader);
+        assert!(server(&sim, new).last_index() >= index);
+        assert!(sim.invariants().unwrap().report());
+    }
+
+    #[test]
+    fn lagging_follower_gets_a_snapshot() {
+        let opts = Options {
+            snapshot_threshold: 10,
+            ..Options::default()
+        };
+        let (mut sim, faults, addrs) = sim_cluster(3, opts);
+        assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &addrs).is_some()));
+        let leader = sim_leader(&sim, &addrs).unwrap();
+        let behind = addrs.iter().copied().find(|a| *a != leader).unwrap();
+
+        // Cut off while the log goes past a few snapshots.
+        faults.isolate(behind);
+        for op_id in 1..=50 {
+            sim.node_mut::<Server<KvStore>>(leader).unwrap().submit(put(op_id));
+            sim.run_for(Duration::from_millis(20));
+        }
+        let snap = server(&sim, leader).snapshot.last_included_index;
+        assert!(snap > server(&sim, behind).last_index());
+        assert!(server(&sim, leader).log.len() <= opts.snapshot_threshold);
+
+        // What it's missing is gone from the leader's log. Only the snapshot can catch it up.
+        faults.heal();
+        sim.run_for(Duration::from_secs(1));
+        let (l, b) = (server(&sim, leader), server(&sim, behind));
+        assert!(b.snapshot.last_included_index >= snap);
+        assert_eq!(b.last_applied, l.last_applied);
+        assert_eq!(b.rst.snapshot(), l.rst.snapshot());
+        assert!(sim.invariants().unwrap().report());
+    }
+
+    #[test]
+    fn learner_catches_up_before_it_votes() {
+        let (mut sim, _faults, addrs) = sim_cluster(4, Options::default());
+        assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &addrs).is_some()));
+        let leader = sim_leader(&sim, &addrs).unwrap();
+        for op_id in 1..=20 {
+            sim.node_mut::<Server<KvStore>>(leader).unwrap().submit(put(op_id));
+            sim.run_for(Duration::from_millis(10));
+        }
+
+        // The standby from cluster.json. It only gets a vote once it has everything committed.
+        let new = ClusterConfig::load("cluster.json").addr(Role::Raft, 5);
+        admin(&mut sim, leader, <Message>::AddServer(5, new));
+        assert!(server(&sim, leader).learners.contains_key(&5));
+        assert_eq!(server(&sim, leader).quorum(), 3);
+        let promoted = |sim: &Sim| server(sim, leader).voters.contains_key(&5);
+        assert!(sim.run_until(Duration::from_secs(2), promoted));
+        assert!(server(&sim, new).last_index() >= 20);
+        assert_eq!(server(&sim, leader).quorum(), 4);
+        sim.run_for(Duration::from_millis(500));
+        assert!(server(&sim, new).voters.contains_key(&5));
+        assert_eq!(server(&sim, new).last_applied, server(&sim, leader).last_applied);
+
+        // And out again.
+        admin(&mut sim, leader, <Message>::RemoveServer(5));
+        let removed = |sim: &Sim| !server(sim, leader).voters.contains_key(&5);
+        assert!(sim.run_until(Duration::from_secs(2), removed));
+        assert_eq!(server(&sim, leader).quorum(), 3);
+        assert!(sim.invariants().unwrap().report());
+    }
+
+    #[test]
+    fn tampered_log_is_reported() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes, 0);
+        let term = nodes[0].server.current_term;
+        for i in 1..=3 {
+            nodes[0].server.submit(put(i));
+        }
+        pump(&mut nodes, &[]);
+        heartbeat(&mut nodes, 0, &[]);
+        let inv = Invariants::new();
+        for n in nodes.iter() {
+            n.server.observe(&inv);
+        }
+        assert!(inv.violations().is_empty());
+
+        // Node 2's entry at index 2 gets swapped for another one from the same term.
+        let p = nodes[2].server.pos(2);
+        nodes[2].server.log[p].command = Some(put(9));
+        nodes[2].server.observe(&inv);
+        match inv.violations().as_slice() {
+            [Violation::LogMatching { index: 2, term: t, servers: (0, 2), .. }] => assert_eq!(*t, term),
+            vs => panic!("{vs:?}"),
+        }
+    }
+
+    #[test]
+    fn transfer_hands_over() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes, 0);
+        let term = nodes[0].server.current_term;
+
+        // Node 2 is behind when the transfer starts, so it gets caught up first.
+        let put = |op_id| Command {
+            client: "127.0.0.1:1".parse().unwrap(),
+            op_id,
+            op: KvOp::Put("a".into(), op_id.to_string()),
+        };
+        nodes[0].server.submit(put(1));
+        pump(&mut nodes, &[2]);
+        let msg = <Message>::TransferLeadership(2);
+        let buf = to_vec(&msg).unwrap();
+        let ep = nodes[0].server.peers[&1];
+        nodes[0].server.handle(ep, msg, &buf);
+        // Held back till the new leader is in.
+        let msg = <Message>::Request(put(2));
+        let buf = to_vec(&msg).unwrap();
+        nodes[0].server.handle(ep, msg, &buf);
+        assert_eq!(nodes[0].server.last_index(), 1);
+        pump(&mut nodes, &[]);
+
+        assert_eq!(nodes[2].server.state, ServerState::Leader);
+        assert_eq!(nodes[2].server.current_term, term + 1);
+        assert_eq!(nodes[0].server.state, ServerState::Follower);
+        // The held back request went on to the new leader once it was in.
+        heartbeat(&mut nodes, 2, &[]);
+        heartbeat(&mut nodes, 2, &[]);
+        for n in &nodes {
+            assert_eq!(n.server.last_index(), 2);
+        }
+    }
+
+    #[test]
+    fn no_new_leader_while_lease_holds() {
+        let opts = Options {
+            read_mode: ReadMode::Lease,
+            ..Options::default()
+        };
+        let (mut sim, faults, addrs) = sim_cluster(6, opts);
+        assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &addrs).is_some()));
+        let old = sim_leader(&sim, &addrs).unwrap();
+        let others = addrs.iter().copied().filter(|a| *a != old).collect::<Vec<_>>();
+        let (behind, leader) = (others[0], others[1]);
+
+        // One server misses a write and the handover, so the new leader's heartbeats don't match its log.
+        faults.isolate(behind);
+        sim.node_mut::<Server<KvStore>>(old).unwrap().submit(put(1));
+        sim.run_for(Duration::from_millis(300));
+        let id = server(&sim, leader).id;
+        admin(&mut sim, old, Message::TransferLeadership(id));
+        assert!(sim.run_until(Duration::from_secs(1), |sim| server(sim, leader).is_leader()));
+
+        // The other side of a split hasn't heard from the leader in a while either.
+        let split = vec![vec![old, leader, behind], others[2..].to_vec()];
+        faults.partition(split.clone());
+        sim.run_for(Duration::from_millis(300));
+
+        // It comes back on the leader's side, and turns down a heartbeat.
+        // Then it's cut off again before the leader can catch it up.
+        let seen = sim.delivered(behind, "Heartbeat");
+        faults.heal();
+        faults.partition(split);
+        assert!(sim.run_until(Duration::from_secs(1), |sim| sim.delivered(behind, "Heartbeat") > seen));
+        assert!(server(&sim, behind).last_index() < server(&sim, leader).last_index());
+        faults.isolate(behind);
+
+        // The mismatch is what makes a majority for the next lease.
+        let before = server(&sim, leader).lease;
+        assert!(sim.run_until(Duration::from_millis(50), |sim| server(sim, leader).lease > before));
+        let lease = server(&sim, leader).lease.unwrap();
+
+        // The leader goes away, along with the old one. The other side and the server that was behind are a majority,
+        // but none of them may win till the lease is up, or the leader would have served stale reads.
+        faults.heal();
+        faults.isolate(leader);
+        faults.isolate(old);
+        sim.node_mut::<Server<KvStore>>(others[2]).unwrap().on_timer(Timer::Election);
+        let rest = [behind, others[2], others[3]];
+        assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &rest).is_some()));
+        let new = sim_leader(&sim, &rest).unwrap();
+        assert!(server(&sim, new).io.now() >= lease);
+        assert!(sim.invariants().unwrap().report());
+    }
+
+    #[test]
+    fn reads_skip_the_log() {
+        for read_mode in [ReadMode::ReadIndex, ReadMode::Lease] {
+            let opts = Options {
+                read_mode,
+                ..Options::default()
+            };
+            let (mut sim, faults, addrs) = sim_cluster(5, opts);
+            assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &addrs).is_some()));
+            let leader = sim_leader(&sim, &addrs).unwrap();
+            sim.node_mut::<Server<KvStore>>(leader).unwrap().submit(put(1));
+            sim.run_for(Duration::from_millis(200));
+            let last = server(&sim, leader).last_index();
+
+            // Cut off, so no heartbeat round can come back.
+            faults.isolate(leader);
+            let get = Command {
+                op: KvOp::Get("a".into()),
+                ..put(2)
+            };
+            sim.node_mut::<Server<KvStore>>(leader).unwrap().submit(get);
+            let s = server(&sim, leader);
+            assert_eq!(s.last_index(), last);
+            match read_mode {
+                // Has to hear from a majority first.
+                ReadMode::ReadIndex => assert_eq!(s.reads.len(), 1),
+                // Nobody else can be leader yet. Answered straight off.
+                _ => assert!(s.reads.is_empty()),
+            }
+            faults.heal();
+            sim.run_for(Duration::from_millis(200));
+            assert!(server(&sim, leader).reads.is_empty());
+            assert_eq!(server(&sim, leader).last_index(), last);
+        }
+    }
+
+    #[test]
+    fn slow_follower_is_not_flooded() {
+        let opts = Options {
+            max_entries: 3,
+            max_inflight: 2,
+            ..Options::default()
+        };
+        let mut nodes = cluster_with(3, opts);
+        elect(&mut nodes, 0);
+
+        // Node 2 stops answering while the writes pile up.
+        for op_id in 1..=20 {
+            nodes[0].server.submit(Command {
+                client: "127.0.0.1:1".parse().unwrap(),
+                op_id,
+                op: KvOp::Put("a".into(), op_id.to_string()),
+            });
+            pump(&mut nodes, &[2]);
+        }
+        assert_eq!(nodes[1].server.last_index(), 20);
+        assert_eq!(nodes[0].server.commit_index, 20);
+        // Never more than two appends of three out to it.
+        assert!(nodes[0].server.inflight[&2].len() <= 2);
+        assert!(nodes[0].server.next_index[&2] <= 7);
+
+        // Back, and the first heartbeat it answers gets it going again.
+        heartbeat(&mut nodes, 0, &[]);
+        heartbeat(&mut nodes, 0, &[]);
+        assert_eq!(nodes[2].server.last_index(), 20);
+        assert_eq!(nodes[2].server.commit_index, 20);
+        assert_eq!(nodes[0].server.match_index[&2], 20);
+        assert!(nodes[0].server.inflight[&2].is_empty());
+    }
+
+    #[test]
+    fn diverged_follower_backs_up_a_term_at_a_time() {
+        let mut nodes = cluster(3);
+        elect(&mut nodes, 0);
+        for op_id in 1..=10 {
+            nodes[0].server.submit(put(op_id));
+        }
+        pump(&mut nodes, &[]);
+
+        // Node 0 gets 20 more that nobody else sees.
+        for op_id in 11..=30 {
+            nodes[0].server.submit(put(op_id));
+        }
+        pump(&mut nodes, &[1, 2]);
+        let old = nodes[0].server.current_term;
+
+        // Meanwhile the others stop hearing from it, move on without it, and write something else in those slots.
+        for n in nodes[1..].iter_mut() {
+            n.server.heard_at = None;
+        }
+        nodes[1].server.on_timer(Timer::Election);
+        pump(&mut nodes, &[0]);
+        assert_eq!(nodes[1].server.state, ServerState::Leader);
+        for op_id in 31..=50 {
+            nodes[1].server.submit(put(op_id));
+        }
+        pump(&mut nodes, &[0]);
+        let new = nodes[1].server.current_term;
+
+        // Node 2 takes over, so the leader starts out with no idea where node 0 is.
+        let msg = <Message>::TransferLeadership(2);
+        let buf = to_vec(&msg).unwrap();
+        let ep = nodes[1].server.peers[&2];
+        nodes[1].server.handle(ep, msg, &buf);
+        pump(&mut nodes, &[0]);
+        assert_eq!(nodes[2].server.state, ServerState::Leader);
+        assert_eq!(nodes[2].server.next_index[&0], 31);
+
+        // Node 0 is back. It turns down the first heartbeat, and says its term started at 1.
+        nodes[2].server.on_timer(Timer::Heartbeat);
+        step(&mut nodes, 0);
+        step(&mut nodes, 1);
+        // So the leader skips all 20 at once, and sends them over.
+        step(&mut nodes, 2);
+        step(&mut nodes, 0);
+        assert_eq!(nodes[0].server.last_index(), 30);
+        assert_eq!(nodes[0].server.term_at(10), old);
+        assert_eq!(nodes[0].server.term_at(11), new);
+        assert_eq!(nodes[0].server.term_at(30), new);
+        pump(&mut nodes, &[]);
+        assert_eq!(nodes[2].server.match_index[&0], 30);
+    }
+
+    #[test]
+    fn leader_without_quorum_steps_down() {
+        let (mut sim, faults, addrs) = sim_cluster(2, Options::default());
+        assert!(sim.run_until(Duration::from_secs(5), |sim| sim_leader(sim, &addrs).is_some()));
+        let leader = sim_leader(&sim, &addrs).unwrap();
+
+        // Still hearing from everyone, so it stays.
+        sim.run_for(Duration::from_secs(1));
+        assert!(server(&sim, leader).is_leader());
+
+        // Cut off from every follower. Nobody can depose it, it has to notice on its own.
+        faults.isolate(leader);
+        let limit = Duration::from_millis(2 * ELECTION_MAX as u64);
+        assert!(sim.run_until(limit, |sim| !server(sim, leader).is_leader()));
+        assert_eq!(server(&sim, leader).state, ServerState::Follower);
+    }
+}
@@ -0,0 +1,329 @@
+//! Stable storage for Raft: `current_term`, `voted_for`, the log and the latest snapshot.
+//!
+//! Raft is only safe if these survive a crash, and if they hit the disk before the server answers anyone.
+//! `FileStorage` is an append-only, fsync'd record file. `MemStorage` forgets everything, for tests and the threaded harness.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Log, Snapshot};
+use crate::{
+    kv::{KvOp, KvResult},
+    Payload,
+};
+
+/// The bits of server state that must be persisted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: usize,
+    pub voted_for: Option<usize>,
+}
+
+/// `O` is what goes in the log, `R` what the sessions in a snapshot remember.
+pub trait Storage<O = KvOp, R = KvResult>: Send {
+    /// Whatever was stored before the restart.
+    /// The entries start right after the snapshot (at index 1 if there is none).
+    fn load(&mut self) -> (HardState, Snapshot<R>, Vec<Log<O>>);
+    /// Overwrite the term and vote.
+    fn set_hard_state(&mut self, hs: HardState);
+    /// Store `entries` starting at log index `index`, discarding anything already stored from `index` onwards.
+    fn append(&mut self, index: usize, entries: &[Log<O>]);
+    /// Store a snapshot, and drop every entry it covers.
+    fn save_snapshot(&mut self, snapshot: &Snapshot<R>);
+}
+
+/// One line of the log file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Record<O> {
+    HardState(HardState),
+    Append(usize, Vec<Log<O>>),
+}
+
+/// Keeps everything in memory. Nothing survives a restart.
+#[derive(Debug)]
+pub struct MemStorage<O = KvOp, R = KvResult> {
+    hs: HardState,
+    snapshot: Snapshot<R>,
+    log: Vec<Log<O>>,
+}
+
+impl<O, R> Default for MemStorage<O, R> {
+    fn default() -> Self {
+        Self {
+            hs: HardState::default(),
+            snapshot: Snapshot::default(),
+            log: vec![],
+        }
+    }
+}
+
+impl<O, R> MemStorage<O, R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<O: Payload, R: Payload> Storage<O, R> for MemStorage<O, R> {
+    fn load(&mut self) -> (HardState, Snapshot<R>, Vec<Log<O>>) {
+        (self.hs, self.snapshot.clone(), self.log.clone())
+    }
+
+    fn set_hard_state(&mut self, hs: HardState) {
+        self.hs = hs;
+    }
+
+    fn append(&mut self, index: usize, entries: &[Log<O>]) {
+        self.log
+            .truncate(index - self.snapshot.last_included_index - 1);
+        self.log.extend_from_slice(entries);
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<R>) {
+        let drop = snapshot.last_included_index - self.snapshot.last_included_index;
+        self.log.drain(..drop.min(self.log.len()));
+        self.snapshot = snapshot.clone();
+    }
+}
+
+/// Append-only file of JSON records, one per line, fsync'd after every write.
+///
+/// Truncations are records too, so nothing is ever rewritten in place, except on a snapshot:
+/// the snapshot goes to `(path).snap`, and the log file is rewritten without the entries it covers.
+/// A torn last line (crash mid-write) is ignored on load.
+pub struct FileStorage<O = KvOp, R = KvResult> {
+    path: PathBuf,
+    file: File,
+    /// Only here so that a snapshot can rewrite the log file.
+    hs: HardState,
+    snapshot: Snapshot<R>,
+    log: Vec<Log<O>>,
+}
+
+impl<O: Payload, R: Payload> FileStorage<O, R> {
+    pub fn open(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::append_to(&path);
+        Self {
+            path,
+            file,
+            hs: HardState::default(),
+            snapshot: Snapshot::default(),
+            log: vec![],
+        }
+    }
+
+    fn append_to(path: &Path) -> File {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap()
+    }
+
+    fn snap_path(&self) -> PathBuf {
+        self.path.with_extension("snap")
+    }
+
+    fn encode(rec: &Record<O>) -> Vec<u8> {
+        let mut buf = serde_json::to_vec(rec).unwrap();
+        buf.push(b'\n');
+        buf
+    }
+
+    fn write(&mut self, rec: &Record<O>) {
+        self.file.write_all(&Self::encode(rec)).unwrap();
+        self.file.sync_data().unwrap();
+    }
+
+    /// Write `buf` to `path` such that a crash leaves either the old file or the new one.
+    fn replace(path: &Path, buf: &[u8]) {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut out = File::create(&tmp).unwrap();
+            out.write_all(buf).unwrap();
+            out.sync_all().unwrap();
+        }
+        fs::rename(&tmp, path).unwrap();
+        // The rename is only durable once the directory is.
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+        File::open(dir.unwrap_or(Path::new("."))).unwrap().sync_all().unwrap();
+    }
+}
+
+impl<O: Payload, R: Payload> Storage<O, R> for FileStorage<O, R> {
+    fn load(&mut self) -> (HardState, Snapshot<R>, Vec<Log<O>>) {
+        if let Ok(buf) = fs::read(self.snap_path()) {
+            self.snapshot = serde_json::from_slice(&buf).unwrap();
+        }
+        let start = self.snapshot.last_included_index + 1;
+
+        let mut reader = BufReader::new(File::open(&self.path).unwrap());
+        let mut good = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            match serde_json::from_str::<Record<O>>(&line) {
+                Ok(Record::HardState(h)) => self.hs = h,
+                Ok(Record::Append(index, entries)) => {
+                    // Stale records from before the snapshot are skipped.
+                    let skip = start.saturating_sub(index);
+                    self.log.truncate(index.max(start) - start);
+                    self.log.extend(entries.into_iter().skip(skip));
+                }
+                // Torn write. Everything after this never made it.
+                Err(_) => break,
+            }
+            good += line.len() as u64;
+            line.clear();
+        }
+        // Chop off the torn bit, so new records don't get glued onto it.
+        self.file.set_len(good).unwrap();
+        (self.hs, self.snapshot.clone(), self.log.clone())
+    }
+
+    fn set_hard_state(&mut self, hs: HardState) {
+        self.hs = hs;
+        self.write(&Record::HardState(hs));
+    }
+
+    fn append(&mut self, index: usize, entries: &[Log<O>]) {
+        self.log
+            .truncate(index - self.snapshot.last_included_index - 1);
+        self.log.extend_from_slice(entries);
+        self.write(&Record::Append(index, entries.to_vec()));
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<R>) {
+        let drop = snapshot.last_included_index - self.snapshot.last_included_index;
+        self.log.drain(..drop.min(self.log.len()));
+        self.snapshot = snapshot.clone();
+
+        // Snapshot first. If we crash before the log is rewritten, load() skips the stale records.
+        Self::replace(&self.snap_path(), &serde_json::to_vec(snapshot).unwrap());
+
+        let mut buf = Self::encode(&Record::HardState(self.hs));
+        if !self.log.is_empty() {
+            buf.extend(Self::encode(&Record::Append(
+                snapshot.last_included_index + 1,
+                self.log.clone(),
+            )));
+        }
+        Self::replace(&self.path, &buf);
+        self.file = Self::append_to(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::{kv::KvOp, raft::Command};
+
+    /// A fresh directory for one test, gone once it's done.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("raft-storage-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn log(&self) -> PathBuf {
+            self.0.join("log")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry(term: usize, op_id: usize) -> Log {
+        Log {
+            term,
+            command: Some(Command {
+                client: "127.0.0.1:1".parse().unwrap(),
+                op_id,
+                op: KvOp::Put("a".into(), op_id.to_string()),
+            }),
+            config: None,
+        }
+    }
+
+    /// Reopen, the way a restarted server would.
+    fn reload(path: &Path) -> (HardState, Snapshot, Vec<Log>) {
+        <FileStorage>::open(path).load()
+    }
+
+    #[test]
+    fn hard_state_survives_a_restart() {
+        let dir = TempDir::new("hard-state");
+        let mut s = <FileStorage>::open(dir.log());
+        s.load();
+        s.set_hard_state(HardState { term: 3, voted_for: Some(1) });
+        s.set_hard_state(HardState { term: 4, voted_for: None });
+        drop(s);
+
+        let (hs, _, log) = reload(&dir.log());
+        assert_eq!(hs, HardState { term: 4, voted_for: None });
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn torn_last_record_is_dropped() {
+        let dir = TempDir::new("torn");
+        let mut s = <FileStorage>::open(dir.log());
+        s.load();
+        s.set_hard_state(HardState { term: 1, voted_for: Some(0) });
+        s.append(1, &[entry(1, 1), entry(1, 2)]);
+        // Overwrites the second one, then dies halfway through the next record.
+        s.append(2, &[entry(1, 3)]);
+        drop(s);
+        let mut f = OpenOptions::new().append(true).open(dir.log()).unwrap();
+        let rec = <FileStorage>::encode(&Record::Append(3, vec![entry(1, 4)]));
+        f.write_all(&rec[..rec.len() / 2]).unwrap();
+        drop(f);
+
+        let mut s = <FileStorage>::open(dir.log());
+        let (hs, _, log) = s.load();
+        assert_eq!(hs, HardState { term: 1, voted_for: Some(0) });
+        assert_eq!(log, vec![entry(1, 1), entry(1, 3)]);
+
+        // What comes after isn't glued onto the torn bit.
+        s.append(3, &[entry(2, 5)]);
+        drop(s);
+        let (_, _, log) = reload(&dir.log());
+        assert_eq!(log, vec![entry(1, 1), entry(1, 3), entry(2, 5)]);
+    }
+
+    #[test]
+    fn snapshot_survives_a_restart() {
+        let dir = TempDir::new("snapshot");
+        let mut s = <FileStorage>::open(dir.log());
+        s.load();
+        s.set_hard_state(HardState { term: 2, voted_for: Some(2) });
+        s.append(1, &(1..=5).map(|i| entry(1, i)).collect::<Vec<_>>());
+        let snap = Snapshot {
+            last_included_index: 3,
+            last_included_term: 1,
+            data: b"state".to_vec(),
+            ..Snapshot::default()
+        };
+        s.save_snapshot(&snap);
+        s.append(6, &[entry(2, 6)]);
+        drop(s);
+
+        let (hs, loaded, log) = reload(&dir.log());
+        assert_eq!(hs, HardState { term: 2, voted_for: Some(2) });
+        assert_eq!(loaded, snap);
+        assert_eq!(log, vec![entry(1, 4), entry(1, 5), entry(2, 6)]);
+    }
+}
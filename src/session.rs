@@ -0,0 +1,194 @@
+//! Session tables, for exactly-once commands.
+//!
+//! Clients retry, and Paxos replicas may get the same command decided in more than one slot,
+//! so the same `(client, op_id)` can come out of consensus several times. Only the first one gets applied.
+//! The rest get the cached response of the first.
+//!
+//! The table is part of the replicated state: it changes only as decided commands are applied, and goes into
+//! checkpoints and snapshots with the state machine. Expiry runs off the slot/log index, not the clock, so every
+//! replica expires the same sessions at the same point.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv::KvResult;
+
+/// Sessions untouched for this many slots/entries are dropped.
+pub const SESSION_TTL: usize = 10_000;
+/// Responses remembered per client. Older ones are forgotten, but never re-applied.
+/// A client with more than this many ops in flight may have the stragglers turned away as expired.
+const MAX_CACHED: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Session<R> {
+    /// Every op below this has been applied, and its response forgotten.
+    floor: usize,
+    /// Responses to recent ops, by `op_id`.
+    responses: BTreeMap<usize, R>,
+    /// Slot/index of the last command from this client.
+    last_seen: usize,
+}
+
+// Not derived: that would want R: Default.
+impl<R> Default for Session<R> {
+    fn default() -> Self {
+        Self {
+            floor: 0,
+            responses: BTreeMap::new(),
+            last_seen: 0,
+        }
+    }
+}
+
+/// Per-client record of what has already been applied, keyed by however the protocol names clients.
+/// `R` is what the state machine answers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionTable<K: Ord, R = KvResult> {
+    sessions: BTreeMap<K, Session<R>>,
+    /// Floors of expired sessions. A retry from one of those must not be applied again.
+    expired: BTreeMap<K, usize>,
+    ttl: usize,
+    /// Where expiry last ran.
+    swept: usize,
+}
+
+impl<K: Ord, R> Default for SessionTable<K, R> {
+    fn default() -> Self {
+        Self::new(SESSION_TTL)
+    }
+}
+
+impl<K: Ord, R> SessionTable<K, R> {
+    pub fn new(ttl: usize) -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+            expired: BTreeMap::new(),
+            ttl,
+            swept: 0,
+        }
+    }
+}
+
+impl<K: Ord, R: Clone> SessionTable<K, R> {
+    /// Apply `op_id` from `client` through `apply`, decided at slot/index `at`, unless it's been done before.
+    ///
+    /// Returns the response to send back: fresh, cached, or `None` if it's so old we've forgotten the answer.
+    pub fn execute(&mut self, client: K, op_id: usize, at: usize, apply: impl FnOnce() -> R) -> Option<R> {
+        self.expire(at);
+        // Coming back after expiry picks up where it left off. Only one of these is ever there.
+        let floor = self.expired.remove(&client).unwrap_or(0);
+        let s = self.sessions.entry(client).or_insert_with(|| Session { floor, ..Session::default() });
+        s.last_seen = at;
+        if op_id < s.floor {
+            return None;
+        }
+        if let Some(res) = s.responses.get(&op_id) {
+            return Some(res.clone());
+        }
+
+        let res = apply();
+        s.responses.insert(op_id, res.clone());
+        if s.responses.len() > MAX_CACHED {
+            let (oldest, _) = s.responses.pop_first().unwrap();
+            s.floor = oldest + 1;
+        }
+        Some(res)
+    }
+
+    /// Drop idle sessions' responses. Only every `ttl` slots, it's a full scan.
+    ///
+    /// Just the floor stays, past everything the client got applied, so a late retry is turned away instead of
+    /// being applied again.
+    fn expire(&mut self, at: usize) {
+        if at < self.swept + self.ttl {
+            return;
+        }
+        let ttl = self.ttl;
+        for (client, s) in std::mem::take(&mut self.sessions) {
+            if s.last_seen + ttl >= at {
+                self.sessions.insert(client, s);
+                continue;
+            }
+            let floor = s.responses.last_key_value().map_or(s.floor, |(op_id, _)| op_id + 1);
+            self.expired.insert(client, floor);
+        }
+        self.swept = at;
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// `execute` with an op that counts how often it's applied. Its response is the op id.
+    fn run(t: &mut SessionTable<usize, usize>, applied: &Cell<usize>, client: usize, op_id: usize, at: usize) -> Option<usize> {
+        t.execute(client, op_id, at, || {
+            applied.set(applied.get() + 1);
+            op_id
+        })
+    }
+
+    #[test]
+    fn retry_gets_the_cached_response() {
+        let (mut t, applied) = (SessionTable::default(), Cell::new(0));
+        assert_eq!(run(&mut t, &applied, 0, 7, 1), Some(7));
+        assert_eq!(run(&mut t, &applied, 0, 7, 2), Some(7));
+        // Same op id from someone else is a different op.
+        assert_eq!(run(&mut t, &applied, 1, 7, 3), Some(7));
+        assert_eq!(applied.get(), 2);
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let (mut t, applied) = (SessionTable::new(10), Cell::new(0));
+        run(&mut t, &applied, 0, 1, 0);
+        run(&mut t, &applied, 1, 1, 5);
+        // Client 1 kept its session going, client 0 hasn't been seen in over 10 slots.
+        run(&mut t, &applied, 1, 2, 12);
+        assert_eq!(t.len(), 1);
+        assert_eq!(run(&mut t, &applied, 1, 1, 13), Some(1));
+        assert_eq!(applied.get(), 3);
+    }
+
+    #[test]
+    fn retry_after_expiry_is_not_applied_again() {
+        let (mut t, applied) = (SessionTable::new(10), Cell::new(0));
+        run(&mut t, &applied, 0, 1, 0);
+        run(&mut t, &applied, 0, 2, 1);
+        // Long gone by the time op 2 comes round again.
+        run(&mut t, &applied, 1, 1, 20);
+        assert_eq!(t.len(), 1);
+        assert_eq!(run(&mut t, &applied, 0, 2, 21), None);
+        assert_eq!(run(&mut t, &applied, 0, 1, 22), None);
+        assert_eq!(applied.get(), 3);
+        // Anything new from it still goes through.
+        assert_eq!(run(&mut t, &applied, 0, 3, 23), Some(3));
+        assert_eq!(applied.get(), 4);
+    }
+
+    #[test]
+    fn straggler_past_the_cache_is_turned_away() {
+        let (mut t, applied) = (SessionTable::default(), Cell::new(0));
+        // Op 0 is held up while the next MAX_CACHED + 1 go through, pushing op 1 out of the cache.
+        for op_id in 1..=MAX_CACHED + 1 {
+            run(&mut t, &applied, 0, op_id, op_id);
+        }
+        let applied_before = applied.get();
+        assert_eq!(run(&mut t, &applied, 0, 0, 200), None);
+        assert_eq!(run(&mut t, &applied, 0, 1, 201), None);
+        assert_eq!(applied.get(), applied_before);
+        // Still cached.
+        assert_eq!(run(&mut t, &applied, 0, 2, 202), Some(2));
+    }
+}
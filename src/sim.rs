@@ -0,0 +1,934 @@
+//! A whole cluster in one thread, on a simulated network and a virtual clock.
+//!
+//! Every node gets a `SimIo` instead of a message-io handler. Sends and timers become events in one queue,
+//! ordered by virtual time, and `Sim` hands them out one at a time. Nothing sleeps, nothing races,
+//! and a run is a function of its seed: if something goes wrong, the same seed does it again.
+//!
+//! Message delays come from the seed and the message itself, not from how many random numbers were drawn before,
+//! so nodes iterating their `HashMap`s in a different order each run doesn't change what happens.
+//!
+//! ```no_run
+//! use dc_project::{config::ClusterConfig, kv::KvStore, raft::Options, sim::{self, Sim}};
+//! use std::time::Duration;
+//!
+//! let cfg = ClusterConfig::from_env();
+//! let mut sim = Sim::new(42);
+//! sim::raft_cluster::<KvStore>(&mut sim, &cfg, Options::default());
+//! let client = sim::raft_client(&mut sim, &cfg, 0, 100);
+//! sim.run_for(Duration::from_secs(10));
+//! ```
+
+use std::{
+    any::Any,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use message_io::network::{Endpoint, ResourceId, Transport};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    client::{ClientError, Options, Outcome, Protocol},
+    history::Call,
+    config::{ClusterConfig, Role},
+    faults::{self, Faults},
+    invariants::Invariants,
+    kv::{KvOp, KvResult},
+    net::{Clock, Io, Network, TimerId},
+    paxos::{self, acceptor::Acceptor, leader::Leader, replica::Replica},
+    raft::{self, server::Server},
+    StateMachine,
+};
+
+/// Default bounds on how long a message takes to get anywhere.
+pub const MIN_LATENCY: Duration = Duration::from_millis(1);
+pub const MAX_LATENCY: Duration = Duration::from_millis(10);
+
+/// Anything that can sit on the simulated network.
+pub trait Node: Any {
+    fn on_message(&mut self, from: Endpoint, buf: &[u8]);
+    /// One of its own timers (or signals) going off. Carries whatever it was set with.
+    fn on_timer(&mut self, t: Box<dyn Any + Send>);
+    /// Report to `inv`, after each event. Nodes with nothing to check don't.
+    fn observe(&self, _inv: &Invariants) {}
+}
+
+enum Event {
+    Message { from: SocketAddr, to: SocketAddr, buf: Vec<u8> },
+    Timer { node: SocketAddr, t: Box<dyn Any + Send> },
+}
+
+/// Everything nodes can see of the simulation, through their `SimIo`.
+struct World {
+    seed: u64,
+    /// Virtual time zero.
+    start: Instant,
+    now: Duration,
+    /// Ordered by time, then by a key that only depends on the seed and the event itself.
+    events: BTreeMap<(Duration, u64), Event>,
+    /// When each pending timer goes off, by key. For cancelling.
+    timers: HashMap<u64, Duration>,
+    /// Timers set so far by each node.
+    timer_count: HashMap<SocketAddr, u64>,
+    /// Times each (sender, receiver, message) has been sent, so resends get delays of their own.
+    sent: HashMap<u64, u64>,
+    /// For each node's `random`.
+    rngs: HashMap<SocketAddr, StdRng>,
+    latency: (Duration, Duration),
+    /// Running hash of every event handed out.
+    trace: u64,
+    delivered: usize,
+    dropped: usize,
+    /// Messages delivered to each node, by kind. See `kind`.
+    kinds: HashMap<SocketAddr, HashMap<String, usize>>,
+}
+
+impl World {
+    fn hash(&self, x: impl Hash) -> u64 {
+        let mut h = DefaultHasher::new();
+        (self.seed, x).hash(&mut h);
+        h.finish()
+    }
+
+    /// Queue `e` at `at`. Keys only collide by accident, so just move along.
+    fn push(&mut self, at: Duration, mut key: u64, e: Event) -> u64 {
+        while self.events.contains_key(&(at, key)) {
+            key = key.wrapping_add(1);
+        }
+        self.events.insert((at, key), e);
+        key
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, buf: &[u8], after: Duration) {
+        let id = self.hash((from, to, buf));
+        let n = self.sent.entry(id).or_default();
+        *n += 1;
+        let n = *n;
+        let key = self.hash((id, n));
+        let (lo, hi) = self.latency;
+        let delay = lo + (hi - lo).mul_f64((key >> 11) as f64 / (1u64 << 53) as f64);
+        let at = self.now + after + delay;
+        self.push(at, key, Event::Message { from, to, buf: buf.to_vec() });
+    }
+
+    fn timer(&mut self, node: SocketAddr, t: Box<dyn Any + Send>, after: Duration) -> u64 {
+        let n = self.timer_count.entry(node).or_default();
+        *n += 1;
+        let n = *n;
+        let key = self.hash((node, n));
+        let at = self.now + after;
+        let key = self.push(at, key, Event::Timer { node, t });
+        self.timers.insert(key, at);
+        key
+    }
+
+    fn cancel(&mut self, key: u64) {
+        if let Some(at) = self.timers.remove(&key) {
+            self.events.remove(&(at, key));
+        }
+    }
+}
+
+/// A node's way into the simulation. Stands in for its `NodeHandler`.
+pub struct SimIo {
+    addr: SocketAddr,
+    world: Arc<Mutex<World>>,
+}
+
+impl Network for SimIo {
+    fn send(&self, to: Endpoint, buf: &[u8]) {
+        self.world.lock().unwrap().send(self.addr, to.addr(), buf, Duration::ZERO);
+    }
+
+    fn send_later(&self, to: Endpoint, buf: &[u8], after: Duration) {
+        self.world.lock().unwrap().send(self.addr, to.addr(), buf, after);
+    }
+
+    /// There's nothing to connect. Endpoints are just addresses here.
+    fn connect(&self, addr: SocketAddr) -> Endpoint {
+        endpoint(addr)
+    }
+}
+
+impl<T: Send + 'static> Clock<T> for SimIo {
+    fn now(&self) -> Instant {
+        let w = self.world.lock().unwrap();
+        w.start + w.now
+    }
+
+    fn timer(&self, t: T, after: Duration) -> TimerId {
+        TimerId::Sim(self.world.lock().unwrap().timer(self.addr, Box::new(t), after))
+    }
+
+    fn cancel(&self, id: TimerId) {
+        if let TimerId::Sim(key) = id {
+            self.world.lock().unwrap().cancel(key);
+        }
+    }
+
+    fn signal(&self, t: T) {
+        Clock::timer(self, t, Duration::ZERO);
+    }
+
+    fn random(&self) -> f64 {
+        let mut w = self.world.lock().unwrap();
+        let seed = w.hash(self.addr);
+        w.rngs.entry(self.addr).or_insert_with(|| StdRng::seed_from_u64(seed)).gen()
+    }
+}
+
+/// Endpoints only need an address here. The resource is a made-up local UDP one, which is all `from_listener` checks.
+fn endpoint(addr: SocketAddr) -> Endpoint {
+    // Low 7 bits are the adapter, bit 7 is message-io's "local" flag.
+    Endpoint::from_listener(ResourceId::from(1 << 7 | Transport::Udp.id() as usize), addr)
+}
+
+/// What a message was sent as: the name of its enum variant, going by how serde_json writes them
+/// (`{"Name":...}`, or just `"Name"` if there's nothing in it).
+fn kind(buf: &[u8]) -> &str {
+    let rest = buf.strip_prefix(b"{").unwrap_or(buf);
+    let rest = rest.strip_prefix(b"\"").unwrap_or_default();
+    let end = rest.iter().position(|b| *b == b'"').unwrap_or(0);
+    std::str::from_utf8(&rest[..end]).unwrap_or_default()
+}
+
+/// The simulation. Add nodes, then run it.
+pub struct Sim {
+    world: Arc<Mutex<World>>,
+    nodes: BTreeMap<SocketAddr, Box<dyn Node>>,
+    faults: Option<Faults>,
+    invariants: Option<Invariants>,
+}
+
+impl Sim {
+    pub fn new(seed: u64) -> Self {
+        let world = World {
+            seed,
+            start: Instant::now(),
+            now: Duration::ZERO,
+            events: BTreeMap::new(),
+            timers: HashMap::new(),
+            timer_count: HashMap::new(),
+            sent: HashMap::new(),
+            rngs: HashMap::new(),
+            latency: (MIN_LATENCY, MAX_LATENCY),
+            trace: 0,
+            delivered: 0,
+            dropped: 0,
+            kinds: HashMap::new(),
+        };
+        Self {
+            world: Arc::new(Mutex::new(world)),
+            nodes: BTreeMap::new(),
+            faults: None,
+            invariants: None,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.world.lock().unwrap().seed
+    }
+
+    /// Every message takes between `min` and `max` to arrive.
+    pub fn set_latency(&mut self, min: Duration, max: Duration) {
+        self.world.lock().unwrap().latency = (min, max);
+    }
+
+    /// Everything nodes send from here on goes past `faults`. Clients' requests don't.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = Some(faults);
+    }
+
+    /// Check `invariants` after every event. See `Sim::invariants` for what it found.
+    pub fn set_invariants(&mut self, invariants: Invariants) {
+        self.invariants = Some(invariants);
+    }
+
+    pub fn invariants(&self) -> Option<&Invariants> {
+        self.invariants.as_ref()
+    }
+
+    /// What the node at `addr` should use for its `Io`. Make the node with this, then `add` it.
+    pub fn io<T: Send + 'static>(&self, addr: SocketAddr) -> Box<dyn Io<T>> {
+        faults::wrap(self.faults.as_ref(), addr, Box::new(self.sim_io(addr)))
+    }
+
+    fn sim_io(&self, addr: SocketAddr) -> SimIo {
+        SimIo {
+            addr,
+            world: self.world.clone(),
+        }
+    }
+
+    pub fn add(&mut self, addr: SocketAddr, node: impl Node) {
+        self.nodes.insert(addr, Box::new(node));
+    }
+
+    /// Take a node off the network, as if it crashed. Whatever's still on its way to it is dropped.
+    pub fn remove(&mut self, addr: SocketAddr) -> Option<Box<dyn Node>> {
+        self.nodes.remove(&addr)
+    }
+
+    /// The node at `addr`, if it's there and is an `N`.
+    pub fn node<N: Node>(&self, addr: SocketAddr) -> Option<&N> {
+        let n: &dyn Any = self.nodes.get(&addr)?.as_ref();
+        n.downcast_ref()
+    }
+
+    pub fn node_mut<N: Node>(&mut self, addr: SocketAddr) -> Option<&mut N> {
+        let n: &mut dyn Any = self.nodes.get_mut(&addr)?.as_mut();
+        n.downcast_mut()
+    }
+
+    /// Virtual time since the start.
+    pub fn now(&self) -> Duration {
+        self.world.lock().unwrap().now
+    }
+
+    /// Hash of everything that's happened so far. Same seed, same cluster, same digest.
+    pub fn digest(&self) -> u64 {
+        self.world.lock().unwrap().trace
+    }
+
+    /// Messages delivered, and messages that found nobody at the other end.
+    pub fn stats(&self) -> (usize, usize) {
+        let w = self.world.lock().unwrap();
+        (w.delivered, w.dropped)
+    }
+
+    /// How many messages of `kind` got delivered to the node at `to`.
+    pub fn delivered(&self, to: SocketAddr, kind: &str) -> usize {
+        let w = self.world.lock().unwrap();
+        w.kinds.get(&to).and_then(|k| k.get(kind)).copied().unwrap_or(0)
+    }
+
+    /// Hand out the next event, if there is one before `until`.
+    pub fn step(&mut self, until: Duration) -> bool {
+        let e = {
+            let mut w = self.world.lock().unwrap();
+            match w.events.first_key_value() {
+                Some(((at, _), _)) if *at <= until => {}
+                _ => return false,
+            }
+            let ((at, key), e) = w.events.pop_first().unwrap();
+            w.now = at;
+            w.timers.remove(&key);
+            w.trace = w.hash((w.trace, at, key));
+            match &e {
+                Event::Message { to, buf, .. } if self.nodes.contains_key(to) => {
+                    w.delivered += 1;
+                    let kinds = w.kinds.entry(*to).or_default();
+                    match kinds.get_mut(kind(buf)) {
+                        Some(n) => *n += 1,
+                        None => _ = kinds.insert(kind(buf).to_string(), 1),
+                    }
+                }
+                Event::Message { .. } => w.dropped += 1,
+                Event::Timer { .. } => {}
+            }
+            e
+        };
+        // Not holding the lock, the node is going to want it.
+        let at = match e {
+            Event::Message { from, to, buf } => {
+                if let Some(n) = self.nodes.get_mut(&to) {
+                    n.on_message(endpoint(from), &buf);
+                }
+                to
+            }
+            Event::Timer { node, t } => {
+                if let Some(n) = self.nodes.get_mut(&node) {
+                    n.on_timer(t);
+                }
+                node
+            }
+        };
+        if let (Some(inv), Some(n)) = (&self.invariants, self.nodes.get(&at)) {
+            n.observe(inv);
+        }
+        true
+    }
+
+    /// Run `d` of virtual time.
+    pub fn run_for(&mut self, d: Duration) {
+        let until = self.now() + d;
+        while self.step(until) {}
+        self.world.lock().unwrap().now = until;
+    }
+
+    /// Run until `done` says so, or `limit` of virtual time has gone by. Whether `done` said so.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut(&Sim) -> bool) -> bool {
+        let until = self.now() + limit;
+        while !done(self) {
+            if !self.step(until) {
+                return done(self);
+            }
+        }
+        true
+    }
+}
+
+impl<S> Node for Server<S>
+where
+    S: StateMachine<Op = KvOp, Output = KvResult>,
+{
+    fn on_message(&mut self, from: Endpoint, buf: &[u8]) {
+        Server::on_message(self, from, buf);
+    }
+
+    fn on_timer(&mut self, t: Box<dyn Any + Send>) {
+        Server::on_timer(self, *t.downcast().unwrap());
+    }
+
+    fn observe(&self, inv: &Invariants) {
+        Server::observe(self, inv);
+    }
+}
+
+impl Node for Acceptor {
+    fn on_message(&mut self, from: Endpoint, buf: &[u8]) {
+        Acceptor::on_message(self, from, buf);
+    }
+
+    fn on_timer(&mut self, _t: Box<dyn Any + Send>) {}
+}
+
+impl Node for Leader {
+    fn on_message(&mut self, from: Endpoint, buf: &[u8]) {
+        Leader::on_message(self, from, buf);
+    }
+
+    fn on_timer(&mut self, t: Box<dyn Any + Send>) {
+        self.on_signal(*t.downcast().unwrap());
+    }
+}
+
+impl<S> Node for Replica<S>
+where
+    S: StateMachine<Op = KvOp, Output = KvResult>,
+{
+    fn on_message(&mut self, from: Endpoint, buf: &[u8]) {
+        Replica::on_message(self, from, buf);
+    }
+
+    fn on_timer(&mut self, t: Box<dyn Any + Send>) {
+        Replica::on_timer(self, *t.downcast().unwrap());
+    }
+
+    fn observe(&self, inv: &Invariants) {
+        Replica::observe(self, inv);
+    }
+}
+
+/// Closed loop: one op at a time, the next one as soon as the last is answered (or given up on).
+/// Random ops from its own seeded RNG, with the same timeouts and retries as `client::Client`.
+pub struct SimClient<P: Protocol<Op = KvOp, Output = KvResult>> {
+    id: usize,
+    proto: P,
+    io: SimIo,
+    opts: Options,
+    rng: StdRng,
+    /// Ops still to start.
+    left: usize,
+    /// Current op, and the try it's on.
+    attempt: usize,
+    /// Times are virtual, since the start of the run.
+    pub history: Vec<Call>,
+}
+
+impl<P: Protocol<Op = KvOp, Output = KvResult>> SimClient<P> {
+    fn new(id: usize, proto: P, io: SimIo, ops: usize, opts: Options) -> Self {
+        let seed = io.world.lock().unwrap().hash((io.addr, "client"));
+        let mut out = Self {
+            id,
+            proto,
+            io,
+            opts,
+            rng: StdRng::seed_from_u64(seed),
+            left: ops,
+            attempt: 0,
+            history: vec![],
+        };
+        out.next();
+        out
+    }
+
+    fn now(&self) -> Duration {
+        self.io.world.lock().unwrap().now
+    }
+
+    /// Whether it's done all its ops.
+    pub fn done(&self) -> bool {
+        self.left == 0 && self.history.last().is_none_or(|c| c.returned.is_some())
+    }
+
+    fn next(&mut self) {
+        if self.left == 0 {
+            return;
+        }
+        self.left -= 1;
+        let op = KvOp::random(&mut self.rng);
+        self.history.push(Call {
+            client: self.id,
+            op,
+            invoked: self.now(),
+            returned: None,
+        });
+        self.attempt = 0;
+        self.send();
+    }
+
+    /// Op ids are just positions in the history.
+    fn send(&mut self) {
+        let op_id = self.history.len() - 1;
+        let buf = self.proto.request(op_id, &self.history[op_id].op);
+        for addr in self.proto.targets(self.attempt) {
+            self.io.send(endpoint(addr), &buf);
+        }
+        Clock::timer(&self.io, (op_id, self.attempt), self.opts.timeout);
+    }
+
+    fn finish(&mut self, res: Outcome) {
+        let now = self.now();
+        self.history.last_mut().unwrap().returned = Some((now, res));
+        self.next();
+    }
+}
+
+impl<P: Protocol<Op = KvOp, Output = KvResult>> Node for SimClient<P> {
+    fn on_message(&mut self, from: Endpoint, buf: &[u8]) {
+        let Some((op_id, res)) = self.proto.response(buf) else {
+            return;
+        };
+        if op_id + 1 == self.history.len() && self.history[op_id].returned.is_none() {
+            self.proto.heard_from(from.addr());
+            self.finish(res);
+        }
+    }
+
+    fn on_timer(&mut self, t: Box<dyn Any + Send>) {
+        let (op_id, attempt) = *t.downcast::<(usize, usize)>().unwrap();
+        if op_id + 1 != self.history.len() || attempt != self.attempt || self.history[op_id].returned.is_some() {
+            return;
+        }
+        if attempt < self.opts.retries {
+            self.attempt += 1;
+            self.send();
+        } else {
+            self.finish(Err(ClientError::Timeout));
+        }
+    }
+}
+
+/// Every Raft server in `cfg`, in memory.
+pub fn raft_cluster<S>(sim: &mut Sim, cfg: &ClusterConfig, opts: raft::Options)
+where
+    S: StateMachine<Op = KvOp, Output = KvResult>,
+{
+    for n in cfg.nodes(Role::Raft) {
+        let io = sim.io(n.addr);
+        let storage = Box::new(raft::storage::MemStorage::new());
+        sim.add(n.addr, Server::<S>::start(n.id, cfg, io, storage, opts));
+    }
+}
+
+/// Every Paxos acceptor, replica and leader in `cfg`, in memory. Leaders not on standby start scouting straight away.
+pub fn paxos_cluster<S>(sim: &mut Sim, cfg: &ClusterConfig)
+where
+    S: StateMachine<Op = KvOp, Output = KvResult>,
+{
+    for n in cfg.nodes(Role::Acceptor) {
+        let io = sim.io(n.addr);
+        let storage = Box::new(paxos::storage::MemStorage::new());
+        sim.add(n.addr, Acceptor::new(n.id, io, storage, cfg.lease.duration()));
+    }
+    for n in cfg.nodes(Role::Replica) {
+        sim.add(n.addr, Replica::<S>::start(n.id, cfg, sim.io(n.addr)));
+    }
+    for n in cfg.nodes(Role::Leader) {
+        let mut leader = Leader::new(n.id, cfg, sim.io(n.addr));
+        leader.start();
+        sim.add(n.addr, leader);
+    }
+}
+
+pub fn raft_client(sim: &mut Sim, cfg: &ClusterConfig, client_id: usize, ops: usize) -> SocketAddr {
+    let leader = sim.world.lock().unwrap().hash(client_id) as usize;
+    client(sim, cfg, client_id, raft::client::Raft::new(cfg, client_id, leader), ops)
+}
+
+pub fn paxos_client(sim: &mut Sim, cfg: &ClusterConfig, client_id: usize, ops: usize) -> SocketAddr {
+    let opts = Options::default();
+    client(sim, cfg, client_id, paxos::client::Paxos::new(cfg, client_id, opts.fanout), ops)
+}
+
+/// Client `client_id` at its address from `cfg`, doing `ops` ops through `proto`. Returns where to find it.
+pub fn client<P: Protocol<Op = KvOp, Output = KvResult>>(sim: &mut Sim, cfg: &ClusterConfig, client_id: usize, proto: P, ops: usize) -> SocketAddr {
+    let addr = cfg.client_addr(client_id);
+    let c = SimClient::new(client_id, proto, sim.sim_io(addr), ops, Options::default());
+    sim.add(addr, c);
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        faults::{Counts, Rates},
+        kv::KvStore,
+        linearizability::{self, Kv},
+    };
+
+    const OPS: usize = 30;
+
+    fn setup(seed: u64, raft: bool, faults: Option<&Faults>) -> (Sim, SocketAddr) {
+        let cfg = ClusterConfig::load("cluster.json");
+        let mut sim = Sim::new(seed);
+        if let Some(f) = faults {
+            sim.set_faults(f.clone());
+        }
+        sim.set_invariants(Invariants::new());
+        let c = if raft {
+            raft_cluster::<KvStore>(&mut sim, &cfg, raft::Options::default());
+            raft_client(&mut sim, &cfg, 0, OPS)
+        } else {
+            paxos_cluster::<KvStore>(&mut sim, &cfg);
+            paxos_client(&mut sim, &cfg, 0, OPS)
+        };
+        (sim, c)
+    }
+
+    fn history(sim: &Sim, c: SocketAddr) -> Vec<Call> {
+        match sim.node::<SimClient<raft::client::Raft>>(c) {
+            Some(c) => c.history.clone(),
+            None => sim.node::<SimClient<paxos::client::Paxos>>(c).unwrap().history.clone(),
+        }
+    }
+
+    /// Run till the client's done, and check every op got an answer.
+    fn finish(sim: &mut Sim, c: SocketAddr) -> (u64, Vec<Call>) {
+        let done = |h: &[Call]| h.len() == OPS && h.iter().all(|c| c.returned.is_some());
+        assert!(sim.run_until(Duration::from_secs(60), |sim| done(&history(sim, c))));
+        let h = history(sim, c);
+        assert!(h.iter().all(|c| matches!(c.returned, Some((_, Ok(_))))));
+        if let Err(v) = linearizability::check::<Kv>(&h) {
+            panic!("{v}");
+        }
+        assert!(sim.invariants().unwrap().report());
+        (sim.digest(), h)
+    }
+
+    fn run(seed: u64, raft: bool) -> (u64, Vec<Call>) {
+        let (mut sim, c) = setup(seed, raft, None);
+        finish(&mut sim, c)
+    }
+
+    #[test]
+    fn raft_replays_from_seed() {
+        let (digest, history) = run(3, true);
+        assert_eq!(run(3, true), (digest, history));
+        assert_ne!(run(4, true).0, digest);
+    }
+
+    #[test]
+    fn paxos_replays_from_seed() {
+        let (digest, history) = run(3, false);
+        assert_eq!(run(3, false), (digest, history));
+        assert_ne!(run(4, false).0, digest);
+    }
+
+    /// Lossy network, and the leader cut off for a while once there is one.
+    fn raft_with_faults(seed: u64) -> (u64, Vec<Call>, Counts) {
+        let faults = Faults::new(seed);
+        faults.set(Rates {
+            drop: 0.05,
+            duplicate: 0.05,
+            delay: (Duration::ZERO, Duration::from_millis(20)),
+            reorder: 0.1,
+        });
+        let (mut sim, c) = setup(seed, true, Some(&faults));
+        let cfg = ClusterConfig::load("cluster.json");
+        let leader = |sim: &Sim| {
+            cfg.nodes(Role::Raft)
+                .map(|n| n.addr)
+                .find(|a| sim.node::<Server<KvStore>>(*a).unwrap().is_leader())
+        };
+        assert!(sim.run_until(Duration::from_secs(10), |sim| leader(sim).is_some()));
+        let old = leader(&sim).unwrap();
+        faults.isolate(old);
+        sim.run_for(Duration::from_secs(2));
+        assert_ne!(leader(&sim), Some(old), "someone else should have taken over");
+        faults.heal();
+        let (digest, history) = finish(&mut sim, c);
+        (digest, history, faults.counts())
+    }
+
+    #[test]
+    fn raft_rides_out_faults() {
+        let (digest, history, counts) = raft_with_faults(5);
+        assert!(counts.dropped > 0 && counts.cut > 0 && counts.duplicated > 0 && counts.reordered > 0);
+        assert_eq!(raft_with_faults(5), (digest, history, counts));
+    }
+
+    /// Same rates as Raft gets, and the first leader cut off for a while.
+    #[test]
+    fn paxos_rides_out_scripted_faults() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let script = "drop 0.05; duplicate 0.05; reorder 0.1; delay 0-20ms; 500ms isolate leader 0; 2s heal";
+        let run = || {
+            let faults = Faults::parse(5, &cfg, script).unwrap();
+            let (mut sim, c) = setup(5, false, Some(&faults));
+            let (digest, history) = finish(&mut sim, c);
+            (digest, history, faults.counts())
+        };
+        let (digest, history, counts) = run();
+        assert!(counts.dropped > 0 && counts.cut > 0 && counts.duplicated > 0 && counts.reordered > 0);
+        assert_eq!(run(), (digest, history, counts));
+    }
+
+    /// Several clients at once, all checked together. Seeds that used to catch Raft electing a leader that was
+    /// missing committed entries (leaked election timers, stale votes counted), and Paxos leaders taking two
+    /// proposals for one slot.
+    #[test]
+    fn concurrent_clients_stay_linearizable() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let script = "duplicate 0.05; reorder 0.2; delay 0-30ms; 1s isolate raft 0; 2s isolate raft 1; 3s heal; \
+                      4s partition raft 0,1 / raft 2,3,4; 6s heal";
+        let paxos_script = "drop 0.05; duplicate 0.05; reorder 0.2; delay 0-30ms; 1s isolate leader 0; 3s heal";
+        for (seed, raft) in [(2, true), (1, false)] {
+            let mut sim = Sim::new(seed);
+            sim.set_invariants(Invariants::new());
+            let clients = if raft {
+                sim.set_faults(Faults::parse(seed, &cfg, script).unwrap());
+                raft_cluster::<KvStore>(&mut sim, &cfg, raft::Options::default());
+                (0..4).map(|i| raft_client(&mut sim, &cfg, i, 60)).collect::<Vec<_>>()
+            } else {
+                sim.set_faults(Faults::parse(seed, &cfg, paxos_script).unwrap());
+                paxos_cluster::<KvStore>(&mut sim, &cfg);
+                (0..4).map(|i| paxos_client(&mut sim, &cfg, i, 60)).collect()
+            };
+            check_clients(&mut sim, &clients, 60);
+        }
+    }
+
+    /// Run till every client's got an answer for all `ops`, then check them all together, and the invariants.
+    fn check_clients(sim: &mut Sim, clients: &[SocketAddr], ops: usize) {
+        let done = |sim: &Sim| {
+            clients.iter().all(|c| {
+                let h = history(sim, *c);
+                h.len() == ops && h.iter().all(|c| c.returned.is_some())
+            })
+        };
+        assert!(sim.run_until(Duration::from_secs(60), done), "seed {}", sim.seed());
+        let calls = clients.iter().flat_map(|c| history(sim, *c)).collect::<Vec<_>>();
+        if let Err(v) = linearizability::check::<Kv>(&calls) {
+            panic!("seed {}: {v}", sim.seed());
+        }
+        assert!(sim.invariants().unwrap().report());
+    }
+
+    /// Raft with `opts` on a lossy network, running `script` on top. Two clients doing `ops` each, for
+    /// `check_clients`.
+    fn raft_scenario(seed: u64, opts: raft::Options, script: &str, ops: usize) -> (Sim, Vec<SocketAddr>) {
+        let cfg = ClusterConfig::load("cluster.json");
+        let script = format!("drop 0.05; duplicate 0.05; reorder 0.1; delay 0-20ms; {script}");
+        let mut sim = Sim::new(seed);
+        sim.set_faults(Faults::parse(seed, &cfg, &script).unwrap());
+        sim.set_invariants(Invariants::new());
+        raft_cluster::<KvStore>(&mut sim, &cfg, opts);
+        let clients = (0..2).map(|i| raft_client(&mut sim, &cfg, i, ops)).collect::<Vec<_>>();
+        (sim, clients)
+    }
+
+    /// `msg` to every Raft server, as if from an admin tool. Only the leader does anything with it.
+    fn raft_admin(sim: &mut Sim, cfg: &ClusterConfig, msg: raft::Message) {
+        let buf = serde_json::to_vec(&msg).unwrap();
+        for n in cfg.nodes(Role::Raft) {
+            let from = endpoint(cfg.client_addr(99));
+            sim.node_mut::<Server<KvStore>>(n.addr).unwrap().on_message(from, &buf);
+        }
+    }
+
+    fn raft_server(sim: &Sim, addr: SocketAddr) -> &Server<KvStore> {
+        sim.node::<Server<KvStore>>(addr).unwrap()
+    }
+
+    /// Snapshots every 10 entries, so a server cut off for a while only catches up with InstallSnapshot.
+    #[test]
+    fn raft_snapshot_scenario() {
+        let opts = raft::Options {
+            snapshot_threshold: 10,
+            ..raft::Options::default()
+        };
+        let script = "300ms isolate raft 3; 1s heal; 1500ms isolate raft 4; 2500ms heal";
+        let (mut sim, clients) = raft_scenario(6, opts, script, 100);
+        check_clients(&mut sim, &clients, 100);
+        // Both came back to find what they'd missed compacted away.
+        let cfg = ClusterConfig::load("cluster.json");
+        for id in [3, 4] {
+            assert!(sim.delivered(cfg.addr(Role::Raft, id), "InstallSnapshot") > 0, "raft {id} never got a snapshot");
+        }
+    }
+
+    /// The standby joins as a learner and gets a vote, then somebody else leaves, all while the clients carry on.
+    #[test]
+    fn raft_membership_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let (mut sim, clients) = raft_scenario(7, raft::Options::default(), "1s isolate raft 2; 2s heal", 100);
+        sim.run_for(Duration::from_millis(500));
+        raft_admin(&mut sim, &cfg, raft::Message::AddServer(5, cfg.addr(Role::Raft, 5)));
+        sim.run_for(Duration::from_secs(1));
+        raft_admin(&mut sim, &cfg, raft::Message::RemoveServer(1));
+
+        // Both changes went through and committed, so a quorum of the new voters has the config.
+        let leader = |sim: &Sim| {
+            cfg.nodes(Role::Raft)
+                .map(|n| n.addr)
+                .find(|a| sim.node::<Server<KvStore>>(*a).is_some_and(|s| s.is_leader()))
+        };
+        let settled = |sim: &Sim| {
+            leader(sim).map(|a| raft_server(sim, a)).is_some_and(|l| {
+                l.config_committed() && l.voters().contains_key(&5) && !l.voters().contains_key(&1)
+            })
+        };
+        assert!(sim.run_until(Duration::from_secs(10), settled));
+        assert!(raft_server(&sim, cfg.addr(Role::Raft, 5)).voters().contains_key(&5));
+        let committed = raft_server(&sim, leader(&sim).unwrap()).commit_index();
+
+        // And it keeps committing without server 1.
+        check_clients(&mut sim, &clients, 100);
+        let leader = raft_server(&sim, leader(&sim).unwrap());
+        assert!(!leader.voters().contains_key(&1));
+        assert!(leader.commit_index() > committed);
+    }
+
+    /// The leader dies for good partway through. The clients have to time out on it and find the new one.
+    #[test]
+    fn raft_client_failover_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let (mut sim, clients) = raft_scenario(10, raft::Options::default(), "", 100);
+        let leader = |sim: &Sim| {
+            cfg.nodes(Role::Raft)
+                .map(|n| n.addr)
+                .find(|a| sim.node::<Server<KvStore>>(*a).is_some_and(|s| s.is_leader()))
+        };
+        sim.run_for(Duration::from_secs(1));
+        assert!(sim.run_until(Duration::from_secs(10), |sim| leader(sim).is_some()));
+        sim.remove(leader(&sim).unwrap());
+        check_clients(&mut sim, &clients, 100);
+    }
+
+    /// Reads off leases, with the leader cut off while it holds one. Whoever takes over has to wait it out, or
+    /// the old one would be answering reads the new one has already made stale.
+    #[test]
+    fn raft_lease_read_scenario() {
+        let opts = raft::Options {
+            read_mode: raft::ReadMode::Lease,
+            ..raft::Options::default()
+        };
+        let script = "1s isolate raft 0; 1500ms isolate raft 1; 2500ms heal; 3s partition raft 0,1 / raft 2,3,4; 4s heal";
+        let (mut sim, clients) = raft_scenario(11, opts, script, 100);
+        check_clients(&mut sim, &clients, 100);
+        // Some of them went neither through the log nor a heartbeat round.
+        let cfg = ClusterConfig::load("cluster.json");
+        assert!(cfg.nodes(Role::Raft).map(|n| raft_server(&sim, n.addr).leased()).sum::<usize>() > 0);
+    }
+
+    /// The same for Paxos: each leader cut off in turn while its acceptors' grants are still running.
+    #[test]
+    fn paxos_lease_read_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let script = "1s isolate leader 0; 1500ms isolate leader 1; 2500ms heal";
+        let (mut sim, clients) = paxos_scenario(11, &cfg, script, 100);
+        check_clients(&mut sim, &clients, 100);
+        // Some of them never took up a slot.
+        let leased = cfg.nodes(Role::Replica).map(|n| sim.node::<Replica<KvStore>>(n.addr).unwrap().leased());
+        assert!(leased.sum::<usize>() > 0);
+    }
+
+    /// Paxos with `cfg` on a lossy network, running `script` on top. Three clients doing `ops` each, checked with
+    /// `check_clients`.
+    fn paxos_scenario(seed: u64, cfg: &ClusterConfig, script: &str, ops: usize) -> (Sim, Vec<SocketAddr>) {
+        let script = format!("drop 0.05; duplicate 0.05; reorder 0.1; delay 0-20ms; {script}");
+        let mut sim = Sim::new(seed);
+        sim.set_faults(Faults::parse(seed, cfg, &script).unwrap());
+        sim.set_invariants(Invariants::new());
+        paxos_cluster::<KvStore>(&mut sim, cfg);
+        let clients = (0..3).map(|i| paxos_client(&mut sim, cfg, i, ops)).collect::<Vec<_>>();
+        (sim, clients)
+    }
+
+    /// Enough clients at once that requests pile up behind the slots in flight and go out in batches.
+    #[test]
+    fn paxos_batching_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let (mut sim, mut clients) = paxos_scenario(12, &cfg, "1s isolate leader 0; 2s heal", 60);
+        clients.extend((3..10).map(|i| paxos_client(&mut sim, &cfg, i, 60)));
+        // Decisions get forgotten at checkpoints, so look while they're still there.
+        let batched = |sim: &Sim| {
+            cfg.nodes(Role::Replica).any(|n| {
+                let rep = sim.node::<Replica<KvStore>>(n.addr).unwrap();
+                rep.decisions().values().any(|v| matches!(v, paxos::Value::Batch(ops) if ops.len() > 1))
+            })
+        };
+        assert!(sim.run_until(Duration::from_secs(10), batched));
+        check_clients(&mut sim, &clients, 60);
+    }
+
+    /// Long enough for a few checkpoints. A replica cut off for most of it misses more than the leaders keep,
+    /// and has to get a checkpoint off the others.
+    #[test]
+    fn paxos_checkpoint_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let (mut sim, clients) = paxos_scenario(6, &cfg, "300ms isolate replica 2; 10s heal", 200);
+        check_clients(&mut sim, &clients, 200);
+        // Caught up off somebody's checkpoint, and level with the others.
+        assert!(sim.delivered(cfg.addr(Role::Replica, 2), "StateTransfer") > 0);
+        let slot_out = |sim: &Sim, id| sim.node::<Replica<KvStore>>(cfg.addr(Role::Replica, id)).unwrap().slot_out();
+        let level = |sim: &Sim| slot_out(sim, 2) >= slot_out(sim, 0).max(slot_out(sim, 1));
+        assert!(sim.run_until(Duration::from_secs(1), level));
+    }
+
+    /// The active leader dies for good. The other one has to notice it's stopped answering pings and take over.
+    #[test]
+    fn paxos_leader_failover_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let (mut sim, clients) = paxos_scenario(9, &cfg, "", 200);
+        let leader = |sim: &Sim| {
+            cfg.nodes(Role::Leader)
+                .map(|n| n.addr)
+                .find(|a| sim.node::<Leader>(*a).is_some_and(|l| l.is_active()))
+        };
+        sim.run_for(Duration::from_secs(1));
+        assert!(sim.run_until(Duration::from_secs(10), |sim| leader(sim).is_some()));
+        let dead = leader(&sim).unwrap();
+        sim.remove(dead);
+        check_clients(&mut sim, &clients, 200);
+        assert!(leader(&sim).is_some_and(|a| a != dead));
+    }
+
+    /// Onto the standby leader and acceptors mid-run. Once the old ones can't have anything left to do, they're gone.
+    #[test]
+    fn paxos_reconfig_scenario() {
+        let cfg = ClusterConfig::load("cluster.json");
+        let (mut sim, clients) = paxos_scenario(8, &cfg, "", 200);
+        sim.run_for(Duration::from_secs(1));
+        let config = paxos::Config {
+            leaders: vec![cfg.addr(Role::Leader, 2)],
+            acceptors: cfg.nodes(Role::Acceptor).filter(|n| n.standby).map(|n| n.addr).collect(),
+        };
+        let buf = serde_json::to_vec(&<paxos::Message>::Reconfigure(config)).unwrap();
+        for n in cfg.nodes(Role::Replica) {
+            let from = endpoint(cfg.client_addr(99));
+            sim.node_mut::<Replica<KvStore>>(n.addr).unwrap().on_message(from, &buf);
+        }
+        sim.run_for(Duration::from_secs(4));
+        for n in cfg.nodes(Role::Leader).chain(cfg.nodes(Role::Acceptor)).filter(|n| !n.standby) {
+            sim.remove(n.addr);
+        }
+        check_clients(&mut sim, &clients, 200);
+    }
+}